@@ -0,0 +1,204 @@
+use std::fmt;
+
+// x86-64 instructions produced by the backend. Displaying an instruction gives its NASM syntax
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Register {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+// A qword in memory at base + index * scale + displacement. The scale is 1, 2, 4 or 8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Memory {
+    pub base: Register,
+    pub index: Option<(Register, u8)>,
+    pub displacement: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Register(Register),
+    Immediate(i64),
+    Memory(Memory),
+}
+
+// Signed condition codes used by cmp followed by jcc or setcc
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Condition {
+    E,
+    Ne,
+    L,
+    G,
+    Le,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Label(String),
+    Mov(Operand, Operand),
+    // movzx reg, al
+    Movzx(Register),
+    Lea(Register, Memory),
+    Add(Operand, Operand),
+    Sub(Operand, Operand),
+    Xor(Operand, Operand),
+    And(Operand, Operand),
+    Imul(Register, Operand),
+    // rdx:rax = rax * operand
+    WideImul(Operand),
+    Shl(Operand, u8),
+    // Arithmetic shift right, which keeps the sign
+    Sar(Operand, u8),
+    // Logical shift right, which fills with zeroes
+    Shr(Operand, u8),
+    // Sign extend rax into rdx:rax ready for idiv
+    Cqo,
+    Idiv(Operand),
+    Neg(Operand),
+    Cmp(Operand, Operand),
+    // setcc al
+    Set(Condition),
+    Jmp(String),
+    Jcc(Condition, String),
+    Push(Operand),
+    Pop(Operand),
+    Syscall,
+    Ret,
+}
+
+impl Condition {
+    // The condition that is true exactly when this one is false
+    pub fn inverse(&self) -> Condition {
+        match self {
+            Condition::E => Condition::Ne,
+            Condition::Ne => Condition::E,
+            Condition::L => Condition::Ge,
+            Condition::G => Condition::Le,
+            Condition::Le => Condition::G,
+            Condition::Ge => Condition::L,
+        }
+    }
+}
+
+impl Operand {
+    pub fn is_memory(&self) -> bool {
+        matches!(self, Operand::Memory(_))
+    }
+
+    // Most instructions can only take an immediate that fits in a sign extended 32 bits
+    pub fn is_wide_immediate(&self) -> bool {
+        match self {
+            Operand::Immediate(value) => i32::try_from(*value).is_err(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Register::Rax => "rax",
+            Register::Rcx => "rcx",
+            Register::Rdx => "rdx",
+            Register::Rbx => "rbx",
+            Register::Rsp => "rsp",
+            Register::Rbp => "rbp",
+            Register::Rsi => "rsi",
+            Register::Rdi => "rdi",
+            Register::R8 => "r8",
+            Register::R9 => "r9",
+            Register::R10 => "r10",
+            Register::R11 => "r11",
+            Register::R12 => "r12",
+            Register::R13 => "r13",
+            Register::R14 => "r14",
+            Register::R15 => "r15",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}", self.base)?;
+        if let Some((index, scale)) = self.index {
+            write!(f, " + {}*{}", index, scale)?;
+        }
+        match self.displacement {
+            0 => write!(f, "]"),
+            displacement if displacement < 0 => write!(f, " - {}]", -(displacement as i64)),
+            displacement => write!(f, " + {}]", displacement),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "{}", register),
+            Operand::Immediate(value) => write!(f, "{}", value),
+            Operand::Memory(memory) => write!(f, "qword {}", memory),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let suffix = match self {
+            Condition::E => "e",
+            Condition::Ne => "ne",
+            Condition::L => "l",
+            Condition::G => "g",
+            Condition::Le => "le",
+            Condition::Ge => "ge",
+        };
+        write!(f, "{}", suffix)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Label(label) => write!(f, "{}:", label),
+            Instruction::Mov(dest, src) => write!(f, "mov {}, {}", dest, src),
+            Instruction::Movzx(dest) => write!(f, "movzx {}, al", dest),
+            Instruction::Lea(dest, memory) => write!(f, "lea {}, {}", dest, memory),
+            Instruction::Add(dest, src) => write!(f, "add {}, {}", dest, src),
+            Instruction::Sub(dest, src) => write!(f, "sub {}, {}", dest, src),
+            Instruction::Xor(dest, src) => write!(f, "xor {}, {}", dest, src),
+            Instruction::And(dest, src) => write!(f, "and {}, {}", dest, src),
+            Instruction::Imul(dest, src) => write!(f, "imul {}, {}", dest, src),
+            Instruction::WideImul(src) => write!(f, "imul {}", src),
+            Instruction::Shl(dest, count) => write!(f, "shl {}, {}", dest, count),
+            Instruction::Sar(dest, count) => write!(f, "sar {}, {}", dest, count),
+            Instruction::Shr(dest, count) => write!(f, "shr {}, {}", dest, count),
+            Instruction::Cqo => write!(f, "cqo"),
+            Instruction::Idiv(divisor) => write!(f, "idiv {}", divisor),
+            Instruction::Neg(operand) => write!(f, "neg {}", operand),
+            Instruction::Cmp(left, right) => write!(f, "cmp {}, {}", left, right),
+            Instruction::Set(condition) => write!(f, "set{} al", condition),
+            Instruction::Jmp(label) => write!(f, "jmp {}", label),
+            Instruction::Jcc(condition, label) => write!(f, "j{} {}", condition, label),
+            Instruction::Push(operand) => write!(f, "push {}", operand),
+            Instruction::Pop(operand) => write!(f, "pop {}", operand),
+            Instruction::Syscall => write!(f, "syscall"),
+            Instruction::Ret => write!(f, "ret"),
+        }
+    }
+}
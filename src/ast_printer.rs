@@ -1,121 +1,314 @@
-use crate::representations::{Assignment, Block, Expression, List, Literal, Statement, Type};
+use std::collections::HashMap;
+use std::fmt::Write;
 
-fn type_printer(ttp: &Type) {
+use crate::representations::{
+    Assignment, Block, Expression, List, Literal, Statement, Symbol, Type,
+};
+
+// The trees are built up as strings so they can be traced as well as printed. Writing to a
+// string can't fail, so the results of write! are ignored
+
+fn type_printer(out: &mut String, ttp: &Type) {
     match ttp {
-        Type::Bool | Type::Int | Type::None => print!("{:?}", ttp),
+        Type::Bool | Type::Int | Type::None => {
+            let _ = write!(out, "{:?}", ttp);
+        }
         Type::Pointer(inner_ttp) => {
-            print!("*");
-            type_printer(inner_ttp)
+            out.push('*');
+            type_printer(out, inner_ttp)
         }
         Type::Array(inner_ttp, size) => {
-            type_printer(inner_ttp);
-            print!("[{}]", size)
+            type_printer(out, inner_ttp);
+            let _ = write!(out, "[{}]", size);
         }
     }
 }
 
 pub fn statement_pretty_printer(stmt: &Statement) {
+    print!("{}", statement_tree(stmt));
+}
+
+// The statement's tree, with every binary expression in brackets to show how it was grouped
+pub fn statement_tree(stmt: &Statement) -> String {
+    let mut out = String::new();
+    write_statement_tree(&mut out, stmt);
+    out
+}
+
+fn write_statement_tree(out: &mut String, stmt: &Statement) {
     match stmt {
         Statement::Assignment(assign, expr) => {
             match assign {
                 Assignment::Value(_type, symbol) | Assignment::Pointer(_type, symbol) => {
-                    type_printer(_type);
-                    print!(" {} = ", symbol);
+                    type_printer(out, _type);
+                    let _ = write!(out, " {} = ", symbol);
+                }
+                Assignment::Mutation(symbol) => {
+                    let _ = write!(out, "{} = ", symbol);
                 }
-                Assignment::Mutation(symbol) => print!("{} = ", symbol),
             }
-            ast_pretty_printer(&expr);
+            write_expression_tree(out, expr);
         }
         Statement::If(expr, block) => {
-            print!("if ");
-            ast_pretty_printer(&expr);
-            statement_pretty_printer(&block)
+            out.push_str("if ");
+            write_expression_tree(out, expr);
+            write_statement_tree(out, block)
         }
         Statement::IfElse(expr, if_block, else_block) => {
-            print!("if ");
-            ast_pretty_printer(&expr);
-            print!(" ");
-            statement_pretty_printer(&if_block);
-            print!("\nelse ");
-            statement_pretty_printer(&else_block);
+            out.push_str("if ");
+            write_expression_tree(out, expr);
+            out.push(' ');
+            write_statement_tree(out, if_block);
+            out.push_str("\nelse ");
+            write_statement_tree(out, else_block);
         }
         Statement::Block(block) => match block.as_ref() {
-            Block::Statement(stmt) => statement_pretty_printer(&stmt),
+            Block::Statement(stmt) => write_statement_tree(out, stmt),
             Block::Block(stmt, block) => {
-                statement_pretty_printer(&stmt);
-                block_pretty_printer(&block);
+                write_statement_tree(out, stmt);
+                write_block_tree(out, block);
             }
         },
         Statement::While(expr, block) => {
-            print!("while ");
-            ast_pretty_printer(&expr);
-            print!(" ");
-            statement_pretty_printer(&block)
+            out.push_str("while ");
+            write_expression_tree(out, expr);
+            out.push(' ');
+            write_statement_tree(out, block)
         }
-        Statement::Break => print!("break"),
+        Statement::Break => out.push_str("break"),
     }
 }
 
 pub fn block_pretty_printer(block: &Block) {
+    let mut out = String::new();
+    write_block_tree(&mut out, block);
+    print!("{}", out);
+}
+
+fn write_block_tree(out: &mut String, block: &Block) {
     match block {
         Block::Statement(stmt) => {
-            print!(" {{\n    ");
-            statement_pretty_printer(&stmt);
-            print!("\n}}\n");
+            out.push_str(" {\n    ");
+            write_statement_tree(out, stmt);
+            out.push_str("\n}\n");
         }
         Block::Block(stmt, block) => {
-            print!("{{\n    ");
-            statement_pretty_printer(&stmt);
-            block_pretty_printer(&block);
-            print!("\n}}\n");
+            out.push_str("{\n    ");
+            write_statement_tree(out, stmt);
+            write_block_tree(out, block);
+            out.push_str("\n}\n");
         }
     }
 }
 
-fn list_pretty_printer(list: &List) {
+fn list_pretty_printer(out: &mut String, list: &List) {
     match list {
-        List::Literal(literal) => literal_pretty_printer(literal),
+        List::Literal(literal) => literal_pretty_printer(out, literal),
         List::List(literal, list) => {
-            literal_pretty_printer(literal);
-            print!(", ");
-            list_pretty_printer(list);
+            literal_pretty_printer(out, literal);
+            out.push_str(", ");
+            list_pretty_printer(out, list);
         }
     }
 }
 
-fn literal_pretty_printer(literal: &Literal) {
+fn literal_pretty_printer(out: &mut String, literal: &Literal) {
     match literal {
         Literal::Bool(token) | Literal::Int(token) | Literal::Symbol(token) => {
-            print!("{}", token.lexeme())
+            out.push_str(token.lexeme())
         }
         Literal::List(list) => {
-            print!("[");
-            list_pretty_printer(list);
-            print!("]");
+            out.push('[');
+            list_pretty_printer(out, list);
+            out.push(']');
         }
     }
 }
 
 pub fn ast_pretty_printer(expr: &Expression) {
+    print!("{}", expression_tree(expr));
+}
+
+pub fn expression_tree(expr: &Expression) -> String {
+    let mut out = String::new();
+    write_expression_tree(&mut out, expr);
+    out
+}
+
+fn write_expression_tree(out: &mut String, expr: &Expression) {
     match expr {
         Expression::Binary(left, op, right) => {
-            print!("(");
-            //print!("{} ", op.lexeme());
-            ast_pretty_printer(left);
-            print!(" {} ", op.lexeme());
-            ast_pretty_printer(right);
-            //print!(" {}", op.lexeme());
-            print!(")");
+            out.push('(');
+            write_expression_tree(out, left);
+            let _ = write!(out, " {} ", op.lexeme());
+            write_expression_tree(out, right);
+            out.push(')');
         }
         Expression::Unary(op, right) => {
-            print!("{}", op.lexeme());
-            ast_pretty_printer(right);
+            out.push_str(op.lexeme());
+            write_expression_tree(out, right);
         }
-        Expression::Literal(literal) => literal_pretty_printer(literal),
+        Expression::Literal(literal) => literal_pretty_printer(out, literal),
         Expression::Group(_, inner_expr, _) => {
-            print!("group[");
-            ast_pretty_printer(inner_expr);
-            print!("]");
+            out.push_str("group[");
+            write_expression_tree(out, inner_expr);
+            out.push(']');
+        }
+    }
+}
+
+// Write a program back out as source that parses to the same statements, one statement to a
+// line. Declarations don't record whether they were const or mut, so that comes from the symbol
+// table
+pub fn write_source(statements: &[Statement], symbol_table: &HashMap<String, Symbol>) -> String {
+    let mut source = String::new();
+    for statement in statements {
+        write_statement(&mut source, statement, 0, symbol_table);
+    }
+    source
+}
+
+// How a type is written in a declaration
+pub fn type_name(_type: &Type) -> String {
+    match _type {
+        Type::Int => "int".to_string(),
+        Type::Bool => "bool".to_string(),
+        Type::Pointer(inner) => match inner.as_ref() {
+            Type::Array(element_type, length) => format!("{}[{}]", type_name(element_type), length),
+            _ => format!("{}*", type_name(inner)),
+        },
+        Type::Array(_, _) | Type::None => panic!("{:?} can't be written in a declaration", _type),
+    }
+}
+
+fn write_statement(
+    source: &mut String,
+    statement: &Statement,
+    depth: usize,
+    symbol_table: &HashMap<String, Symbol>,
+) {
+    source.push_str(&"    ".repeat(depth));
+    match statement {
+        Statement::Assignment(assign, expr) => {
+            match assign {
+                Assignment::Value(_type, symbol) | Assignment::Pointer(_type, symbol) => {
+                    let keyword = match symbol_table.get(symbol) {
+                        Some(symbol_info) if symbol_info.mutable => "mut",
+                        _ => "const",
+                    };
+                    source.push_str(&format!("{} {} {} = ", keyword, type_name(_type), symbol));
+                }
+                Assignment::Mutation(symbol) => source.push_str(&format!("{} = ", symbol)),
+            }
+            source.push_str(&expression_source(expr));
+            source.push_str(";\n");
+        }
+        Statement::If(expr, block) => {
+            source.push_str(&format!("if {}", expression_source(expr)));
+            write_body(source, block, depth, symbol_table);
+        }
+        Statement::IfElse(expr, if_block, else_block) => {
+            source.push_str(&format!("if {}", expression_source(expr)));
+            write_body(source, if_block, depth, symbol_table);
+            // The else goes after the closing brace, or on its own line after a lone statement
+            if let Statement::Block(_) = if_block.as_ref() {
+                source.pop();
+                source.push(' ');
+            } else {
+                source.push_str(&"    ".repeat(depth));
+            }
+            source.push_str("else");
+            write_body(source, else_block, depth, symbol_table);
+        }
+        Statement::Block(block) => write_braces(source, block, depth, symbol_table),
+        Statement::While(expr, block) => {
+            source.push_str(&format!("while {}", expression_source(expr)));
+            write_body(source, block, depth, symbol_table);
+        }
+        Statement::Break => source.push_str("break;\n"),
+    }
+}
+
+// The statement an if or while runs, which follows its condition
+fn write_body(
+    source: &mut String,
+    body: &Statement,
+    depth: usize,
+    symbol_table: &HashMap<String, Symbol>,
+) {
+    if let Statement::Block(block) = body {
+        source.push(' ');
+        write_braces(source, block, depth, symbol_table);
+    } else {
+        source.push('\n');
+        write_statement(source, body, depth + 1, symbol_table);
+    }
+}
+
+// A block's statements in braces, with the closing brace lined up with the statement the block
+// belongs to
+fn write_braces(
+    source: &mut String,
+    block: &Block,
+    depth: usize,
+    symbol_table: &HashMap<String, Symbol>,
+) {
+    source.push_str("{\n");
+    write_block(source, block, depth + 1, symbol_table);
+    source.push_str(&"    ".repeat(depth));
+    source.push_str("}\n");
+}
+
+fn write_block(
+    source: &mut String,
+    block: &Block,
+    depth: usize,
+    symbol_table: &HashMap<String, Symbol>,
+) {
+    match block {
+        Block::Statement(stmt) => write_statement(source, stmt, depth, symbol_table),
+        Block::Block(stmt, block) => {
+            write_statement(source, stmt, depth, symbol_table);
+            write_block(source, block, depth, symbol_table);
+        }
+    }
+}
+
+fn expression_source(expr: &Expression) -> String {
+    match expr {
+        Expression::Binary(left, op, right) => format!(
+            "{} {} {}",
+            expression_source(left),
+            op.lexeme(),
+            expression_source(right)
+        ),
+        Expression::Unary(op, right) => format!("{}{}", op.lexeme(), expression_source(right)),
+        Expression::Literal(literal) => literal_source(literal),
+        Expression::Group(_, inner_expr, _) => format!("({})", expression_source(inner_expr)),
+    }
+}
+
+fn literal_source(literal: &Literal) -> String {
+    match literal {
+        Literal::Bool(token) | Literal::Int(token) | Literal::Symbol(token) => {
+            token.lexeme().to_string()
+        }
+        Literal::List(list) => {
+            let mut elements = Vec::new();
+            let mut list = list.as_ref();
+            loop {
+                match list {
+                    List::Literal(literal) => {
+                        elements.push(literal_source(literal));
+                        break;
+                    }
+                    List::List(literal, next) => {
+                        elements.push(literal_source(literal));
+                        list = next;
+                    }
+                }
+            }
+            format!("[{}]", elements.join(", "))
         }
     }
 }
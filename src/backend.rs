@@ -1,532 +1,1051 @@
 use core::panic;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet};
 
-use crate::representations::{
-    Assignment, Block, Expression, InnerAddrType, Literal, Statement, Symbol, Type,
-};
+use crate::asm::{self, Condition, Memory, Register};
+use crate::ir::{BinaryOp, BlockId, Function, Instruction, Operand, Temp, Terminator, UnaryOp};
+use crate::liveness::analyse_liveness;
+use crate::representations::{LiveRange, Symbol, Type};
 
-// Build a list of statements into their instructions: module entry point
+// Temps are kept in these registers where possible. rax, rcx and rdx are left free as scratch
+// registers for moving values around and for idiv
+const ALLOCATABLE_REGISTERS: [Register; 6] = [
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+    Register::Rsi,
+    Register::Rdi,
+];
+
+// How the program hands back its exit value: through the exit system call when it runs as its
+// own process, or by returning it in rax when it is called as a function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramExit {
+    Syscall,
+    Return,
+}
+
+// Where every temp lives and how big the stack frame is, once allocation is done
+pub struct Allocation {
+    pub stack_offset: u64,
+    pub temp_locations: HashMap<Temp, asm::Operand>,
+}
+
+// Give every variable a stack slot and every temp a register or a spill slot, recording each
+// variable's slot in the symbol table
+pub fn allocate(function: &Function, symbol_table: &mut HashMap<String, Symbol>) -> Allocation {
+    // Work out which variables and temps are live at the same time so that values whose
+    // lifetimes don't overlap can share stack slots and registers
+    let temp_ranges = analyse_liveness(function, symbol_table);
+    let mut stack_offset = allocate_stack_memory(symbol_table);
+    let temp_locations = allocate_registers(&temp_ranges, &mut stack_offset);
+    Allocation {
+        stack_offset,
+        temp_locations,
+    }
+}
+
+// Build the IR for a program into its instructions once it has been allocated: module entry
+// point
 pub fn build(
-    statements: &mut VecDeque<Statement>,
-    symbol_table: &mut HashMap<String, Symbol>,
-) -> Vec<String> {
-    // The reg list is shared throughout the current function
-    // It allows simple operations in expressions to be done
-    // quickly without using the stack
-    let mut reg_list = VecDeque::<String>::from(["r8", "r9", "r10", "r11"].map(String::from));
+    function: &Function,
+    symbol_table: &HashMap<String, Symbol>,
+    allocation: Allocation,
+    exit: ProgramExit,
+) -> Vec<asm::Instruction> {
+    let Allocation {
+        stack_offset,
+        temp_locations,
+    } = allocation;
+
     // This is the final instruction list that the module
     // returns to the main function to be saved to the file
-    let mut program_instruction_list = Vec::<String>::new();
+    let mut program_instruction_list = Vec::<asm::Instruction>::new();
+    // A caller expects its base pointer back
+    if exit == ProgramExit::Return {
+        program_instruction_list.push(asm::Instruction::Push(asm::Operand::Register(
+            Register::Rbp,
+        )));
+    }
     // Move the stack pointer into the base pointer so that we have a base point relative to each
     // variable that is saved in the function
-    program_instruction_list.push(format!("mov rbp, rsp"));
-    // This stack offset starts at 8 (for u64, currently the only data type supported)
-    let mut label_counter = 1;
-    let stack_offset = allocate_stack_memory(symbol_table);
-    program_instruction_list.push(format!("lea rsp, [rsp - {}]", stack_offset));
-
-    while let Some(stmt_to_build) = statements.pop_front() {
-        let mut instruction_list = build_statement(
-            &stmt_to_build,
-            &mut reg_list,
-            symbol_table,
-            &mut label_counter,
-            None,
+    program_instruction_list.push(asm::Instruction::Mov(
+        asm::Operand::Register(Register::Rbp),
+        asm::Operand::Register(Register::Rsp),
+    ));
+    program_instruction_list.push(asm::Instruction::Lea(
+        Register::Rsp,
+        Memory {
+            base: Register::Rsp,
+            index: None,
+            displacement: -(stack_offset as i32),
+        },
+    ));
+
+    let context = BuildContext {
+        symbol_table,
+        temp_locations,
+        use_counts: count_temp_uses(function),
+        exit,
+    };
+    for (index, block) in function.blocks.iter().enumerate() {
+        let next_block = function.blocks.get(index + 1).map(|next| next.id);
+        program_instruction_list.push(asm::Instruction::Label(block_label(block.id)));
+        // A comparison that only feeds the branch at the end of the block is folded into the
+        // jump instead of being saved as a bool
+        let fused_comparison = get_fused_comparison(block, &context);
+        let instruction_count = if fused_comparison.is_some() {
+            block.instructions.len() - 1
+        } else {
+            block.instructions.len()
+        };
+        for instruction in &block.instructions[..instruction_count] {
+            build_instruction(instruction, &context, &mut program_instruction_list);
+        }
+        build_terminator(
+            &block.terminator,
+            fused_comparison,
+            next_block,
+            &context,
+            &mut program_instruction_list,
         );
-        program_instruction_list.append(&mut instruction_list);
     }
-    // FOR DEBUG ONLY: pop the last variable into rdi to act as the exit code for the generated asm
-    get_last_var_debug(&mut program_instruction_list, symbol_table);
+    // Only keep the labels of blocks that are actually jumped to
+    let jump_targets = get_jump_targets(&program_instruction_list);
+    program_instruction_list.retain(|instruction| match instruction {
+        asm::Instruction::Label(label) => jump_targets.contains(label),
+        _ => true,
+    });
     program_instruction_list
 }
 
-fn get_last_var_debug(instruction_list: &mut Vec<String>, symbol_table: &HashMap<String, Symbol>) {
-    let last_symbol = symbol_table
-        .iter()
-        .max_by_key(|(_, v)| v.last_ref)
-        .expect("Should always be at least 1 var in program")
-        .1;
-    println!("{:?}", last_symbol.stack_offset.expect("Itll be there"));
-    instruction_list.push(format!(
-        "mov rdi, qword [rbp - {}]",
-        last_symbol.stack_offset.expect("Should be assigned now")
-    ))
+// Everything the backend needs to know about where values live
+struct BuildContext<'a> {
+    symbol_table: &'a HashMap<String, Symbol>,
+    temp_locations: HashMap<Temp, asm::Operand>,
+    use_counts: HashMap<Temp, usize>,
+    exit: ProgramExit,
+}
+
+fn block_label(id: BlockId) -> String {
+    format!("block_{}", id.0)
 }
 
+// Assign each symbol a stack slot, reusing the slot of any symbol whose live range has already
+// ended. Symbols that are never used don't get a slot. Returns the size of the stack frame
 fn allocate_stack_memory(symbol_table: &mut HashMap<String, Symbol>) -> u64 {
     let mut stack_offset_counter = 0;
-    let mut current_type_size = 0;
-    for (_, symbol_info) in symbol_table.iter_mut() {
-        symbol_info.stack_offset = Some(stack_offset_counter);
-        current_type_size = get_type_size(&symbol_info._type);
-        stack_offset_counter += current_type_size;
+    // Symbols are allocated in the order they become live. Sorting by name as well keeps the
+    // layout the same between runs
+    let mut symbols: Vec<(&String, &mut Symbol)> = symbol_table
+        .iter_mut()
+        .filter(|(_, symbol_info)| symbol_info.live_range.is_some())
+        .collect();
+    symbols.sort_by_key(|(id, symbol_info)| {
+        (
+            symbol_info.live_range.expect("Filtered above").start,
+            id.to_string(),
+        )
+    });
+    // Slots in use as (live range end, offset, size), and slots free to be reused as
+    // (offset, size)
+    let mut active_slots = Vec::<(usize, u64, u64)>::new();
+    let mut free_slots = Vec::<(u64, u64)>::new();
+    for (_, symbol_info) in symbols {
+        let live_range = symbol_info.live_range.expect("Filtered above");
+        expire_slots(&mut active_slots, &mut free_slots, live_range);
+        let type_size = get_type_size(&symbol_info._type);
+        let offset = match free_slots.iter().position(|&(_, size)| size == type_size) {
+            Some(index) => free_slots.swap_remove(index).0,
+            None => {
+                // Each slot starts at rbp - offset and extends upwards
+                stack_offset_counter += type_size;
+                stack_offset_counter
+            }
+        };
+        symbol_info.stack_offset = Some(offset);
+        active_slots.push((live_range.end, offset, type_size));
+    }
+    // Clear the offsets of symbols that no longer appear in the program
+    for symbol_info in symbol_table.values_mut() {
+        if symbol_info.live_range.is_none() {
+            symbol_info.stack_offset = None;
+        }
     }
-    stack_offset_counter - current_type_size
+    stack_offset_counter
 }
 
-fn get_type_size(type_to_size: &Type) -> u64 {
+// Move any slot whose live range ended before the new range starts into the free list
+fn expire_slots(
+    active_slots: &mut Vec<(usize, u64, u64)>,
+    free_slots: &mut Vec<(u64, u64)>,
+    live_range: LiveRange,
+) {
+    active_slots.retain(|&(end, offset, size)| {
+        if end < live_range.start {
+            free_slots.push((offset, size));
+            false
+        } else {
+            true
+        }
+    });
+}
+
+// Linear scan over the temps in the order they become live. Each temp gets a free register if
+// there is one, otherwise it is spilled to a stack slot below the variables
+fn allocate_registers(
+    temp_ranges: &HashMap<Temp, LiveRange>,
+    stack_offset: &mut u64,
+) -> HashMap<Temp, asm::Operand> {
+    let mut temps: Vec<(&Temp, &LiveRange)> = temp_ranges.iter().collect();
+    temps.sort_by_key(|(temp, range)| (range.start, **temp));
+
+    let mut locations = HashMap::new();
+    let mut active_registers = Vec::<(usize, Register)>::new();
+    // Registers are handed out in the order they are listed above
+    let mut free_registers: Vec<Register> = ALLOCATABLE_REGISTERS.into_iter().rev().collect();
+    // Spill slots are handed out the same way as variable slots, as (end, offset, size)
+    let mut active_slots = Vec::<(usize, u64, u64)>::new();
+    let mut free_slots = Vec::<(u64, u64)>::new();
+    for (temp, range) in temps {
+        active_registers.retain(|&(end, register)| {
+            if end < range.start {
+                free_registers.push(register);
+                false
+            } else {
+                true
+            }
+        });
+        expire_slots(&mut active_slots, &mut free_slots, *range);
+
+        let location = if let Some(register) = free_registers.pop() {
+            active_registers.push((range.end, register));
+            asm::Operand::Register(register)
+        } else {
+            let offset = match free_slots.pop() {
+                Some((offset, _)) => offset,
+                None => {
+                    *stack_offset += 8;
+                    *stack_offset
+                }
+            };
+            active_slots.push((range.end, offset, 8));
+            asm::Operand::Memory(stack_slot(offset))
+        };
+        locations.insert(*temp, location);
+    }
+    locations
+}
+
+fn stack_slot(offset: u64) -> Memory {
+    Memory {
+        base: Register::Rbp,
+        index: None,
+        displacement: -(offset as i32),
+    }
+}
+
+pub fn get_type_size(type_to_size: &Type) -> u64 {
     match type_to_size {
-        Type::Bool | Type::Int | Type::Pointer(_) => {
-            return 8;
+        Type::Bool | Type::Int | Type::Pointer(_) => 8,
+        Type::Array(inner_type, length) => get_type_size(inner_type) * length,
+        Type::None => panic!("Should never be a symbol with type None!"),
+    }
+}
+
+fn count_temp_uses(function: &Function) -> HashMap<Temp, usize> {
+    let mut use_counts = HashMap::new();
+    for block in &function.blocks {
+        let operands = block
+            .instructions
+            .iter()
+            .flat_map(|instruction| instruction.operands())
+            .chain(block.terminator.operands());
+        for temp in operands.filter_map(|operand| operand.as_temp()) {
+            *use_counts.entry(temp).or_insert(0) += 1;
         }
-        Type::Array(inner_type, length) => {
-            let inner_size = get_type_size(&inner_type);
-            return inner_size * length;
+    }
+    use_counts
+}
+
+// If the block ends by branching on a comparison made by its last instruction, and nothing
+// else reads the result, return the comparison so the branch can use the flags directly
+fn get_fused_comparison<'a>(
+    block: &'a crate::ir::BasicBlock,
+    context: &BuildContext,
+) -> Option<(BinaryOp, &'a Operand, &'a Operand)> {
+    let Terminator::Branch(Operand::Temp(condition), _, _) = &block.terminator else {
+        return None;
+    };
+    match block.instructions.last() {
+        Some(Instruction::Binary(dest, op, left, right))
+            if dest == condition && op.is_comparison() && context.use_counts[dest] == 1 =>
+        {
+            Some((*op, left, right))
         }
-        Type::None => panic!("Should never be a symbol with type None!"),
+        _ => None,
     }
 }
 
-// Build one statement into asm instructions
-fn build_statement(
-    statement: &Statement,
-    reg_list: &mut VecDeque<String>,
-    symbol_table: &mut HashMap<String, Symbol>,
-    label_counter: &mut u32,
-    block_end_label: Option<&str>,
-) -> Vec<String> {
-    // The instruction list for each statement. Appended above into the overall program instruction
-    // list
-    let mut instruction_list = Vec::<String>::new();
-    match statement {
-        // Type checking has already occured but we need the type info to save into our symbol
-        // table once the expr is built
-        Statement::Assignment(assign_type, expr) => match assign_type {
-            Assignment::Mutation(id) | Assignment::Value(_, id) => {
-                let final_loc = build_expr(&expr, reg_list, &mut instruction_list, symbol_table);
-                let symbol_info = symbol_table
-                    .get(id)
-                    .expect("id should already be in symbol table");
-                if matches!(assign_type, Assignment::Mutation(_)) && !symbol_info.mutable {
-                    panic!("Double check for mutable reassignment: shouldn't happen!")
-                }
-                let symbol_offset = match symbol_info.stack_offset {
-                    Some(offset) => offset,
-                    None => panic!("Symbol should always have memory assigned by now"),
-                };
-                match final_loc {
-                    InnerAddrType::Stack => {
-                        instruction_list.push(format!("pop rax"));
-                        instruction_list.push(format!("mov qword [rbp - {}], rax", symbol_offset));
-                        instruction_list
-                    }
-                    InnerAddrType::Reg(reg) => {
-                        instruction_list
-                            .push(format!("mov qword [rbp - {}], {}", symbol_offset, reg));
-                        instruction_list
-                    }
-                    InnerAddrType::StackOffset(offset) => {
-                        instruction_list.push(format!("mov rax, qword [rbp - {}]", offset));
-                        instruction_list.push(format!("mov qword [rbp - {}], rax", symbol_offset));
-                        instruction_list
-                    }
+impl BuildContext<'_> {
+    // Where the value of an operand can be found
+    fn get_location(&self, operand: &Operand) -> asm::Operand {
+        match operand {
+            Operand::Temp(temp) => match self.temp_locations.get(temp) {
+                Some(location) => *location,
+                None => panic!("{:?} is used but never assigned a location", temp),
+            },
+            Operand::Const(value) => asm::Operand::Immediate(*value),
+        }
+    }
+
+    fn get_dest_location(&self, temp: &Temp) -> asm::Operand {
+        // A temp that is written but never read has no live range past its own instruction, so
+        // it may not have been given a location
+        match self.temp_locations.get(temp) {
+            Some(location) => *location,
+            None => asm::Operand::Register(Register::Rax),
+        }
+    }
+
+    fn get_variable_memory(&self, id: &str) -> Memory {
+        let symbol_info = self
+            .symbol_table
+            .get(id)
+            .expect("id should already be in symbol table");
+        match symbol_info.stack_offset {
+            Some(offset) => stack_slot(offset),
+            None => panic!("Symbol should always have memory assigned by now"),
+        }
+    }
+}
+
+// Make sure a value is somewhere that an instruction can read it as its source operand.
+// Immediates too wide for the instruction are moved into the default register first
+fn get_inner_register(
+    location: asm::Operand,
+    default: Register,
+    instruction_list: &mut Vec<asm::Instruction>,
+) -> asm::Operand {
+    if location.is_wide_immediate() {
+        instruction_list.push(asm::Instruction::Mov(
+            asm::Operand::Register(default),
+            location,
+        ));
+        asm::Operand::Register(default)
+    } else {
+        location
+    }
+}
+
+// Make sure a value is in a register, moving it into the default register if it isn't
+fn load_register(
+    location: asm::Operand,
+    default: Register,
+    instruction_list: &mut Vec<asm::Instruction>,
+) -> Register {
+    match location {
+        asm::Operand::Register(register) => register,
+        _ => {
+            instruction_list.push(asm::Instruction::Mov(
+                asm::Operand::Register(default),
+                location,
+            ));
+            default
+        }
+    }
+}
+
+// Move a value between any two locations, going through rax if both are in memory
+fn build_move(dest: asm::Operand, src: asm::Operand, instruction_list: &mut Vec<asm::Instruction>) {
+    if dest == src {
+        return;
+    }
+    if dest.is_memory() && (src.is_memory() || src.is_wide_immediate()) {
+        instruction_list.push(asm::Instruction::Mov(
+            asm::Operand::Register(Register::Rax),
+            src,
+        ));
+        instruction_list.push(asm::Instruction::Mov(
+            dest,
+            asm::Operand::Register(Register::Rax),
+        ));
+    } else {
+        instruction_list.push(asm::Instruction::Mov(dest, src));
+    }
+}
+
+// The register that an operation should be done in before the result is moved to its
+// destination. This is the destination itself if it is a register that doesn't hold the right
+// hand side, otherwise rax
+fn get_work_register(dest: asm::Operand, right: asm::Operand) -> Register {
+    match dest {
+        asm::Operand::Register(register) if right != dest => register,
+        _ => Register::Rax,
+    }
+}
+
+// Build one IR instruction into asm instructions
+fn build_instruction(
+    instruction: &Instruction,
+    context: &BuildContext,
+    instruction_list: &mut Vec<asm::Instruction>,
+) {
+    match instruction {
+        Instruction::Copy(dest, operand) => build_move(
+            context.get_dest_location(dest),
+            context.get_location(operand),
+            instruction_list,
+        ),
+        Instruction::Binary(dest, op, left, right) => {
+            let dest = context.get_dest_location(dest);
+            let left = context.get_location(left);
+            let right = context.get_location(right);
+            match op {
+                BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul => {
+                    build_arithmetic_op(dest, left, right, *op, instruction_list)
                 }
-            }
-            Assignment::Pointer(_, id) => {
-                let final_loc = build_expr(&expr, reg_list, &mut instruction_list, symbol_table);
-                let symbol_info = symbol_table
-                    .get_mut(id)
-                    .expect("Id should already be in symbol table");
-                let symbol_offset = match symbol_info.stack_offset {
-                    Some(offset) => offset,
-                    None => panic!("Symbol should always have memory assigned by now"),
-                };
-                println!("were assigning a pointer here!");
-                match final_loc {
-                    InnerAddrType::Stack => {
-                        instruction_list.push(format!("mov qword [rbp - {}], rsp", symbol_offset));
-                        instruction_list
-                    }
-                    InnerAddrType::Reg(_reg) => {
-                        panic!("Cannot have a pointer to a temporary expression var!")
-                    }
-                    InnerAddrType::StackOffset(offset) => {
-                        println!("We're assigning a pointer!");
-                        instruction_list.push(format!("lea rax, [rbp - {}]", offset));
-                        instruction_list.push(format!("mov qword [rbp - {}], rax", symbol_offset));
-                        instruction_list
-                    }
+                BinaryOp::Div | BinaryOp::Mod => {
+                    instruction_list.append(&mut build_factor_op(dest, left, right, *op))
                 }
+                _ => instruction_list.append(&mut build_comparison_op(dest, left, right, *op)),
             }
-        },
-        Statement::If(expr, if_block) => {
-            let if_label = format!("if_{}", label_counter);
-            build_cmp_instructions(
-                expr,
-                &if_label,
-                &mut instruction_list,
-                symbol_table,
-                reg_list,
+        }
+        Instruction::Unary(dest, UnaryOp::Neg, operand) => {
+            let dest = context.get_dest_location(dest);
+            let work = get_work_register(dest, asm::Operand::Immediate(0));
+            build_move(
+                asm::Operand::Register(work),
+                context.get_location(operand),
+                instruction_list,
             );
-            instruction_list.push(format!("jmp end_{}", if_label));
-            instruction_list.push(format!("{}:", if_label));
-            instruction_list.append(&mut build_statement(
-                &if_block,
-                reg_list,
-                symbol_table,
-                label_counter,
-                block_end_label,
-            ));
-            instruction_list.push(format!("end_{}:", if_label));
-            *label_counter += 1;
-            instruction_list
-        }
-        Statement::IfElse(expr, if_block, else_block) => {
-            let if_else_label = format!("if_else_{}", label_counter);
-            build_cmp_instructions(
-                expr,
-                &if_else_label,
-                &mut instruction_list,
-                symbol_table,
-                reg_list,
+            instruction_list.push(asm::Instruction::Neg(asm::Operand::Register(work)));
+            build_move(dest, asm::Operand::Register(work), instruction_list);
+        }
+        Instruction::Load(dest, id) => build_move(
+            context.get_dest_location(dest),
+            asm::Operand::Memory(context.get_variable_memory(id)),
+            instruction_list,
+        ),
+        Instruction::Store(id, operand) => build_move(
+            asm::Operand::Memory(context.get_variable_memory(id)),
+            context.get_location(operand),
+            instruction_list,
+        ),
+        Instruction::AddressOf(dest, id) => {
+            let dest = context.get_dest_location(dest);
+            let work = get_work_register(dest, asm::Operand::Immediate(0));
+            instruction_list.push(asm::Instruction::Lea(work, context.get_variable_memory(id)));
+            build_move(dest, asm::Operand::Register(work), instruction_list);
+        }
+        Instruction::LoadPointer(dest, address) => {
+            let dest = context.get_dest_location(dest);
+            let address = load_register(
+                context.get_location(address),
+                Register::Rax,
+                instruction_list,
             );
-            instruction_list.append(&mut build_statement(
-                &else_block,
-                reg_list,
-                symbol_table,
-                label_counter,
-                block_end_label,
+            let work = get_work_register(dest, asm::Operand::Immediate(0));
+            instruction_list.push(asm::Instruction::Mov(
+                asm::Operand::Register(work),
+                asm::Operand::Memory(Memory {
+                    base: address,
+                    index: None,
+                    displacement: 0,
+                }),
             ));
-            instruction_list.push(format!("jmp end_{}", if_else_label));
-            instruction_list.push(format!("{}:", if_else_label));
-            instruction_list.append(&mut build_statement(
-                &if_block,
-                reg_list,
-                symbol_table,
-                label_counter,
-                block_end_label,
-            ));
-            instruction_list.push(format!("end_{}:", if_else_label));
-            *label_counter += 1;
-            instruction_list
-        }
-        Statement::Block(block) => {
-            instruction_list.append(&mut build_block(
-                block,
-                reg_list,
-                symbol_table,
-                label_counter,
-                block_end_label,
-            ));
-            instruction_list
-        }
-        Statement::While(expr, while_block) => {
-            let while_label = format!("while_{}", label_counter);
-            instruction_list.push(format!("start_{}:", while_label));
-            let end_while_label = format!("end_while_{}", label_counter);
-            build_cmp_instructions(
-                expr,
-                &while_label,
-                &mut instruction_list,
-                symbol_table,
-                reg_list,
+            build_move(dest, asm::Operand::Register(work), instruction_list);
+        }
+        Instruction::Phi(_, _) => panic!("Phi nodes should be removed before emission"),
+        Instruction::StorePointer(address, value) => {
+            let address = load_register(
+                context.get_location(address),
+                Register::Rax,
+                instruction_list,
             );
-            instruction_list.push(format!("jmp {}", end_while_label));
-            instruction_list.push(format!("{}:", while_label));
-            instruction_list.append(&mut build_statement(
-                &while_block,
-                reg_list,
-                symbol_table,
-                label_counter,
-                Some(&end_while_label),
+            let value = match context.get_location(value) {
+                value @ asm::Operand::Memory(_) => {
+                    asm::Operand::Register(load_register(value, Register::Rcx, instruction_list))
+                }
+                value => get_inner_register(value, Register::Rcx, instruction_list),
+            };
+            instruction_list.push(asm::Instruction::Mov(
+                asm::Operand::Memory(Memory {
+                    base: address,
+                    index: None,
+                    displacement: 0,
+                }),
+                value,
             ));
-            instruction_list.push(format!("jmp start_{}", while_label));
-            instruction_list.push(format!("{}:", end_while_label));
-            *label_counter += 1;
-            instruction_list
-        }
-        Statement::Break => {
-            if let Some(end_label) = block_end_label {
-                instruction_list.push(format!("jmp {}", end_label));
-                instruction_list
-            } else {
-                panic!("Somehow a break without a while block to end??")
-            }
         }
     }
 }
 
-fn build_cmp_instructions(
-    expr: &Expression,
-    if_label: &str,
-    instruction_list: &mut Vec<String>,
-    symbol_table: &mut HashMap<String, Symbol>,
-    reg_list: &mut VecDeque<String>,
+// Add, sub and mul are all done in place on the left hand side
+fn build_arithmetic_op(
+    dest: asm::Operand,
+    left: asm::Operand,
+    right: asm::Operand,
+    op: BinaryOp,
+    instruction_list: &mut Vec<asm::Instruction>,
 ) {
-    match expr {
-        Expression::Binary(left_expr, op, right_expr) => {
-            // compute the value of each expr and move it into _addr
-            // Then, get the string representation of left_addr
-            let left_addr = get_inner_register(
-                &build_expr(&left_expr, reg_list, instruction_list, symbol_table),
-                "rax",
-                instruction_list,
-            );
-            let right_addr = get_inner_register(
-                &build_expr(&right_expr, reg_list, instruction_list, symbol_table),
-                "rcx",
+    // Multiplication is commutative, so a constant on the left can be moved to the right where
+    // it can be strength reduced
+    let (left, right) = match (op, left) {
+        (BinaryOp::Mul, asm::Operand::Immediate(_)) => (right, left),
+        _ => (left, right),
+    };
+    let work = get_work_register(dest, right);
+    build_move(asm::Operand::Register(work), left, instruction_list);
+    let work_operand = asm::Operand::Register(work);
+    match op {
+        BinaryOp::Add => {
+            let right = get_inner_register(right, Register::Rcx, instruction_list);
+            instruction_list.push(asm::Instruction::Add(work_operand, right));
+        }
+        BinaryOp::Sub => {
+            let right = get_inner_register(right, Register::Rcx, instruction_list);
+            instruction_list.push(asm::Instruction::Sub(work_operand, right));
+        }
+        BinaryOp::Mul => match right {
+            asm::Operand::Immediate(multiplier) => {
+                build_multiply_by_constant(work, multiplier, instruction_list)
+            }
+            _ => instruction_list.push(asm::Instruction::Imul(work, right)),
+        },
+        _ => panic!("Unrecognised arithmetic op {:?}", op),
+    }
+    build_move(dest, work_operand, instruction_list);
+}
+
+// Multiply the work register by a constant in place. Powers of two are shifts and multiples of
+// 3, 5 or 9 by a power of two use lea, while anything else falls back to imul
+fn build_multiply_by_constant(
+    work: Register,
+    multiplier: i64,
+    instruction_list: &mut Vec<asm::Instruction>,
+) {
+    let work_operand = asm::Operand::Register(work);
+    // Wrapping multiplication by 2^63 is also a shift, so the magnitude is taken as unsigned
+    let magnitude = multiplier.unsigned_abs();
+    let shift = magnitude.trailing_zeros().min(63) as u8;
+    let odd_factor = magnitude >> shift;
+    match multiplier {
+        0 => instruction_list.push(asm::Instruction::Mov(
+            work_operand,
+            asm::Operand::Immediate(0),
+        )),
+        _ if odd_factor == 1 || (multiplier > 0 && matches!(odd_factor, 3 | 5 | 9)) => {
+            if odd_factor != 1 {
+                instruction_list.push(asm::Instruction::Lea(
+                    work,
+                    Memory {
+                        base: work,
+                        index: Some((work, (odd_factor - 1) as u8)),
+                        displacement: 0,
+                    },
+                ));
+            }
+            if shift > 0 {
+                instruction_list.push(asm::Instruction::Shl(work_operand, shift));
+            }
+            if multiplier < 0 && multiplier != i64::MIN {
+                instruction_list.push(asm::Instruction::Neg(work_operand));
+            }
+        }
+        // The two operand form of imul can't take an immediate
+        _ => {
+            let right = load_register(
+                asm::Operand::Immediate(multiplier),
+                Register::Rcx,
                 instruction_list,
             );
-            // Add the cmp instruction using the addresses computed above
-            instruction_list.push(format!("cmp {}, {}", left_addr, right_addr));
-            // Match the operation and get the instruction
-            let jump_instr = match op.lexeme() {
-                "==" => "je",
-                "!=" => "jne",
-                "<" => "jl",
-                ">" => "jg",
-                "<=" => "jle",
-                ">=" => "jge",
-                _ => panic!("Should never be a non bool op in if statement expr"),
-            };
-            // Add the jump instruction for the end of the if block, along with some nice debug
-            // comments
-            instruction_list.push(format!("{} {}\n    ; else block", jump_instr, if_label,));
+            instruction_list.push(asm::Instruction::Imul(work, asm::Operand::Register(right)));
         }
-        _ => panic!("can;t do this yet"),
     }
 }
 
-fn build_block(
-    block: &Block,
-    reg_list: &mut VecDeque<String>,
-    symbol_table: &mut HashMap<String, Symbol>,
-    label_counter: &mut u32,
-    block_end_label: Option<&str>,
-) -> Vec<String> {
-    match block {
-        Block::Statement(stmt) => {
-            return build_statement(
-                &stmt,
-                reg_list,
-                symbol_table,
-                label_counter,
-                block_end_label,
-            )
-        }
-        Block::Block(stmt, block) => {
-            let mut stmt_instructions = build_statement(
-                &stmt,
-                reg_list,
-                symbol_table,
-                label_counter,
-                block_end_label,
-            );
-            let mut block_instructions = build_block(
-                block,
-                reg_list,
-                symbol_table,
-                label_counter,
-                block_end_label,
-            );
-            stmt_instructions.append(&mut block_instructions);
-            stmt_instructions
+// Division leaves the quotient in rax and the remainder in rdx
+fn build_factor_op(
+    dest: asm::Operand,
+    left: asm::Operand,
+    right: asm::Operand,
+    operation: BinaryOp,
+) -> Vec<asm::Instruction> {
+    let mut factor_op = Vec::<asm::Instruction>::new();
+    let result = match right {
+        // Dividing by 0 or -1 can trap, which has to be left for idiv to do
+        asm::Operand::Immediate(divisor) if !matches!(divisor, 0 | -1 | i64::MIN) => {
+            build_division_by_constant(left, divisor, operation, &mut factor_op)
+        }
+        _ => {
+            build_move(asm::Operand::Register(Register::Rax), left, &mut factor_op);
+            let divisor = match right {
+                asm::Operand::Immediate(_) => {
+                    asm::Operand::Register(load_register(right, Register::Rcx, &mut factor_op))
+                }
+                _ => right,
+            };
+            factor_op.push(asm::Instruction::Cqo);
+            factor_op.push(asm::Instruction::Idiv(divisor));
+            match operation {
+                BinaryOp::Div => Register::Rax,
+                BinaryOp::Mod => Register::Rdx,
+                _ => panic!("Unrecognised factor op {:?}", operation),
+            }
         }
+    };
+    build_move(dest, asm::Operand::Register(result), &mut factor_op);
+    factor_op
+}
+
+// Divide by a constant without idiv, returning the register holding the result. Division
+// rounds towards zero like idiv, and the remainder takes the sign of the dividend
+fn build_division_by_constant(
+    left: asm::Operand,
+    divisor: i64,
+    operation: BinaryOp,
+    instruction_list: &mut Vec<asm::Instruction>,
+) -> Register {
+    let rax = asm::Operand::Register(Register::Rax);
+    let rcx = asm::Operand::Register(Register::Rcx);
+    let rdx = asm::Operand::Register(Register::Rdx);
+    let magnitude = divisor.unsigned_abs();
+
+    if magnitude == 1 {
+        match operation {
+            BinaryOp::Div => build_move(rax, left, instruction_list),
+            BinaryOp::Mod => build_move(rax, asm::Operand::Immediate(0), instruction_list),
+            _ => panic!("Unrecognised factor op {:?}", operation),
+        }
+        return Register::Rax;
+    }
+
+    if magnitude.is_power_of_two() {
+        // An arithmetic shift rounds down, so negative dividends have 2^shift - 1 added first
+        // to round towards zero instead. The bias is kept in rcx
+        let shift = magnitude.trailing_zeros() as u8;
+        build_move(rax, left, instruction_list);
+        instruction_list.push(asm::Instruction::Mov(rcx, rax));
+        instruction_list.push(asm::Instruction::Sar(rcx, 63));
+        instruction_list.push(asm::Instruction::Shr(rcx, 64 - shift));
+        instruction_list.push(asm::Instruction::Add(rax, rcx));
+        match operation {
+            BinaryOp::Div => {
+                instruction_list.push(asm::Instruction::Sar(rax, shift));
+                if divisor < 0 {
+                    instruction_list.push(asm::Instruction::Neg(rax));
+                }
+            }
+            BinaryOp::Mod => {
+                let mask = get_inner_register(
+                    asm::Operand::Immediate((magnitude - 1) as i64),
+                    Register::Rdx,
+                    instruction_list,
+                );
+                instruction_list.push(asm::Instruction::And(rax, mask));
+                instruction_list.push(asm::Instruction::Sub(rax, rcx));
+            }
+            _ => panic!("Unrecognised factor op {:?}", operation),
+        }
+        return Register::Rax;
+    }
+
+    // Multiply by a fixed point reciprocal of the divisor and keep the high half, as in Hacker's
+    // Delight. The quotient is built up in rdx
+    let (magic, shift) = get_division_magic(magnitude);
+    let dividend = match left {
+        asm::Operand::Immediate(_) => {
+            asm::Operand::Register(load_register(left, Register::Rcx, instruction_list))
+        }
+        _ => left,
+    };
+    instruction_list.push(asm::Instruction::Mov(rax, asm::Operand::Immediate(magic)));
+    instruction_list.push(asm::Instruction::WideImul(dividend));
+    if magic < 0 {
+        instruction_list.push(asm::Instruction::Add(rdx, dividend));
+    }
+    if shift > 0 {
+        instruction_list.push(asm::Instruction::Sar(rdx, shift));
+    }
+    // Add one to negative quotients so they round towards zero
+    instruction_list.push(asm::Instruction::Mov(rax, rdx));
+    instruction_list.push(asm::Instruction::Shr(rax, 63));
+    instruction_list.push(asm::Instruction::Add(rdx, rax));
+    if divisor < 0 {
+        instruction_list.push(asm::Instruction::Neg(rdx));
+    }
+    match operation {
+        BinaryOp::Div => Register::Rdx,
+        // remainder = dividend - quotient * divisor
+        BinaryOp::Mod => {
+            instruction_list.push(asm::Instruction::Mov(rax, asm::Operand::Immediate(divisor)));
+            instruction_list.push(asm::Instruction::Imul(Register::Rdx, rax));
+            instruction_list.push(asm::Instruction::Mov(rax, dividend));
+            instruction_list.push(asm::Instruction::Sub(rax, rdx));
+            Register::Rax
+        }
+        _ => panic!("Unrecognised factor op {:?}", operation),
     }
 }
 
-fn get_inner_register(
-    addr: &InnerAddrType,
-    default: &str,
-    instruction_list: &mut Vec<String>,
-) -> String {
-    // Match the return addresses from the recursive build_expr calls
-    // If the return address is on the stack then pop it and set the
-    // side reg to rax/rcx.
-    // If the return address is a stack offset, move that qword into the respective
-    // register.
-    // Otherwise, set the side reg to the reg that was returned
-    match addr {
-        InnerAddrType::Stack => {
-            instruction_list.push(format!("pop {}", default));
-            default.to_string()
-        }
-        InnerAddrType::Reg(ref reg) => reg.to_string(),
-        InnerAddrType::StackOffset(offset) => {
-            instruction_list.push(format!("mov {}, qword [rbp - {}]", default, offset));
-            default.to_string()
+// The multiplier and shift for signed division by a divisor that is at least 3 and not a power
+// of two. This is the magic algorithm from Hacker's Delight 10-1 for 64 bit words
+fn get_division_magic(divisor: u64) -> (i64, u8) {
+    let two_63 = 1u64 << 63;
+    // The largest dividend that leaves a remainder of divisor - 1
+    let abs_nc = two_63 - 1 - two_63 % divisor;
+    let mut p = 63;
+    let mut q1 = two_63 / abs_nc;
+    let mut r1 = two_63 - q1 * abs_nc;
+    let mut q2 = two_63 / divisor;
+    let mut r2 = two_63 - q2 * divisor;
+    loop {
+        p += 1;
+        q1 = q1.wrapping_mul(2);
+        r1 = r1.wrapping_mul(2);
+        if r1 >= abs_nc {
+            q1 = q1.wrapping_add(1);
+            r1 = r1.wrapping_sub(abs_nc);
+        }
+        q2 = q2.wrapping_mul(2);
+        r2 = r2.wrapping_mul(2);
+        if r2 >= divisor {
+            q2 = q2.wrapping_add(1);
+            r2 = r2.wrapping_sub(divisor);
+        }
+        let delta = divisor - r2;
+        if !(q1 < delta || (q1 == delta && r1 == 0)) {
+            break;
         }
     }
+    (q2.wrapping_add(1) as i64, (p - 64) as u8)
 }
 
-// Builds an expression into asm instructions
-fn build_expr(
-    expr: &Expression,
-    reg_list: &mut VecDeque<String>,
-    instruction_list: &mut Vec<String>,
-    symbol_table: &mut HashMap<String, Symbol>,
-) -> InnerAddrType {
-    // Recursive match on the expression AST
-    match expr {
-        Expression::Binary(left, op, right) => {
-            // Recurse into the tree
-            let left_addr = build_expr(left, reg_list, instruction_list, symbol_table);
-            let right_addr = build_expr(right, reg_list, instruction_list, symbol_table);
-
-            let right_reg = get_inner_register(&right_addr, "rcx", instruction_list);
-            let left_reg = get_inner_register(&left_addr, "rax", instruction_list);
-            // Add the actual operation instructions
-            match op.lexeme() {
-                "+" => {
-                    instruction_list.push(format!("add {}, {}", left_reg, right_reg));
-                }
-                "-" => {
-                    instruction_list.push(format!("sub {}, {}", left_reg, right_reg));
-                }
-                operation @ ("*" | "/" | "%") => {
-                    instruction_list.append(&mut build_factor_op(&left_reg, &right_reg, operation))
-                }
-                operation @ ("==" | "!=" | "<" | ">" | "<=" | ">=") => instruction_list
-                    .append(&mut build_comparison_op(&left_reg, &right_reg, operation)),
-                // Other types of op that aren't implemented yet like ^ etc
-                _ => panic!("Can't handle {} yet", op.lexeme()),
-            };
+// Compare two values and leave the flags set, making sure the left hand side is somewhere cmp
+// can take it
+fn build_cmp(
+    left: asm::Operand,
+    right: asm::Operand,
+    instruction_list: &mut Vec<asm::Instruction>,
+) {
+    let left = match left {
+        asm::Operand::Immediate(_) => {
+            asm::Operand::Register(load_register(left, Register::Rax, instruction_list))
+        }
+        asm::Operand::Memory(_) if right.is_memory() => {
+            asm::Operand::Register(load_register(left, Register::Rax, instruction_list))
+        }
+        _ => left,
+    };
+    let right = get_inner_register(right, Register::Rcx, instruction_list);
+    instruction_list.push(asm::Instruction::Cmp(left, right));
+}
 
-            // If the right expr result was in a reg it can always be released.
-            // If it was on the stack as a value it has been popped, if it was a relative address
-            // to a variable value then it just stays there
-            match right_addr {
-                InnerAddrType::Reg(reg) => reg_list.push_back(reg),
-                _ => (),
-            }
+fn get_condition(op: BinaryOp) -> Condition {
+    match op {
+        BinaryOp::Eq => Condition::E,
+        BinaryOp::Ne => Condition::Ne,
+        BinaryOp::Lt => Condition::L,
+        BinaryOp::Gt => Condition::G,
+        BinaryOp::Le => Condition::Le,
+        BinaryOp::Ge => Condition::Ge,
+        _ => panic!("Should never be a non bool op in a comparison: {:?}", op),
+    }
+}
 
-            // If the left value was a reg, we can store the result in there
-            // If not, we can check for free regs and store the result in there
-            // If not, we can push it onto the stack
-            // This logic is the same if it was originally a stack offset
-            match left_addr {
-                InnerAddrType::Reg(_) => {
-                    return InnerAddrType::Reg(left_reg.to_string());
-                }
-                InnerAddrType::Stack | InnerAddrType::StackOffset(_) => {
-                    match reg_list.pop_front() {
-                        Some(reg) => {
-                            instruction_list.push(format!("mov {}, rax", reg));
-                            return InnerAddrType::Reg(reg);
-                        }
-                        None => {
-                            instruction_list.push(format!("push rax"));
-                            return InnerAddrType::Stack;
-                        }
-                    }
-                }
+fn build_comparison_op(
+    dest: asm::Operand,
+    left: asm::Operand,
+    right: asm::Operand,
+    operation: BinaryOp,
+) -> Vec<asm::Instruction> {
+    let mut comparison_op = Vec::new();
+    build_cmp(left, right, &mut comparison_op);
+    comparison_op.push(asm::Instruction::Set(get_condition(operation)));
+    let work = get_work_register(dest, asm::Operand::Immediate(0));
+    comparison_op.push(asm::Instruction::Movzx(work));
+    build_move(dest, asm::Operand::Register(work), &mut comparison_op);
+    comparison_op
+}
+
+fn build_terminator(
+    terminator: &Terminator,
+    fused_comparison: Option<(BinaryOp, &Operand, &Operand)>,
+    next_block: Option<BlockId>,
+    context: &BuildContext,
+    instruction_list: &mut Vec<asm::Instruction>,
+) {
+    match terminator {
+        Terminator::Jump(target) => {
+            // Jumping to the next block is just falling through
+            if Some(*target) != next_block {
+                instruction_list.push(asm::Instruction::Jmp(block_label(*target)));
             }
         }
-        Expression::Unary(op, expr) => {
-            let inner_addr = build_expr(&expr, reg_list, instruction_list, symbol_table);
-            match op.lexeme() {
-                "&" => {
-                    if inner_addr.is_memory() {
-                        inner_addr
-                    } else {
-                        panic!("Attempted to reference a non-memory location: this should never happen!")
-                    }
+        Terminator::Branch(condition, if_true, if_false) => {
+            let jump_condition = match (fused_comparison, condition) {
+                (Some((op, left, right)), _) => {
+                    build_cmp(
+                        context.get_location(left),
+                        context.get_location(right),
+                        instruction_list,
+                    );
+                    get_condition(op)
                 }
-                "*" => {
-                    if inner_addr.is_memory() {
-                        get_inner_register(&inner_addr, "rax", instruction_list);
-                        if let Some(reg) = reg_list.pop_front() {
-                            instruction_list.push(format!("mov {}, qword [rax]", reg));
-                            return InnerAddrType::Reg(reg);
-                        } else {
-                            instruction_list.push(format!("mov rax, qword [rax]"));
-                            instruction_list.push(format!("push rax"));
-                            return InnerAddrType::Stack;
-                        }
-                    } else {
-                        panic!(
-                            "Attempted to deref a non-memory location: this should never happen!"
-                        )
+                (None, Operand::Const(value)) => {
+                    let target = if *value != 0 { if_true } else { if_false };
+                    if Some(*target) != next_block {
+                        instruction_list.push(asm::Instruction::Jmp(block_label(*target)));
                     }
+                    return;
                 }
-                _ => panic!("can't handle unary ops other than references yet"),
-            }
-        }
-        // When we get to a literal, the value is just pushed into a
-        // reg or onto the stack
-        // If the primary token is an id, we get it's address
-        Expression::Literal(literal) => {
-            match literal {
-                Literal::Int(token) | Literal::Bool(token) => {
-                    let value_to_load = if token._type() == &Type::Bool {
-                        bool_to_int(token.lexeme())
-                    } else {
-                        token.lexeme()
-                    };
-                    match reg_list.pop_front() {
-                        Some(reg) => {
-                            instruction_list.push(format!("mov {}, {}", reg, value_to_load));
-                            return InnerAddrType::Reg(reg);
-                        }
-                        None => {
-                            instruction_list.push(format!("mov rax, {}", value_to_load));
-                            instruction_list.push(format!("push rax"));
-                            return InnerAddrType::Stack;
-                        }
-                    }
+                (None, Operand::Temp(_)) => {
+                    build_cmp(
+                        context.get_location(condition),
+                        asm::Operand::Immediate(0),
+                        instruction_list,
+                    );
+                    Condition::Ne
                 }
-                Literal::Symbol(token) => {
-                    match symbol_table.get(token.lexeme()) {
-                        // Match to see if the symbol is initialised. If not, it's an error
-                        Some(symbol_info) => match symbol_info.stack_offset {
-                            Some(offset) => {
-                                return InnerAddrType::StackOffset(offset);
-                            }
-                            None => {
-                                panic!("{} is referenced before initialisation", token.lexeme())
-                            }
-                        },
-                        None => panic!("{} is referenced before declaration", token.lexeme()),
-                    }
+            };
+            // Lay out the jumps so that whichever block comes next is fallen through to
+            if Some(*if_true) == next_block {
+                instruction_list.push(asm::Instruction::Jcc(
+                    jump_condition.inverse(),
+                    block_label(*if_false),
+                ));
+            } else {
+                instruction_list.push(asm::Instruction::Jcc(jump_condition, block_label(*if_true)));
+                if Some(*if_false) != next_block {
+                    instruction_list.push(asm::Instruction::Jmp(block_label(*if_false)));
                 }
-                Literal::List(_list) => panic!("Lists are not implemented yet"),
             }
         }
-        // A group just recurses straight away
-        Expression::Group(_, expr, _) => build_expr(expr, reg_list, instruction_list, symbol_table),
+        Terminator::Exit(operand) => match context.exit {
+            ProgramExit::Syscall => {
+                build_move(
+                    asm::Operand::Register(Register::Rdi),
+                    context.get_location(operand),
+                    instruction_list,
+                );
+                build_move(
+                    asm::Operand::Register(Register::Rax),
+                    asm::Operand::Immediate(60),
+                    instruction_list,
+                );
+                instruction_list.push(asm::Instruction::Syscall);
+            }
+            ProgramExit::Return => {
+                build_move(
+                    asm::Operand::Register(Register::Rax),
+                    context.get_location(operand),
+                    instruction_list,
+                );
+                instruction_list.push(asm::Instruction::Mov(
+                    asm::Operand::Register(Register::Rsp),
+                    asm::Operand::Register(Register::Rbp),
+                ));
+                instruction_list.push(asm::Instruction::Pop(asm::Operand::Register(Register::Rbp)));
+                instruction_list.push(asm::Instruction::Ret);
+            }
+        },
     }
 }
 
-fn bool_to_int(bool: &str) -> &str {
-    match bool {
-        "true" => "1",
-        "false" => "0",
-        _ => panic!("{} is not a bool value!", bool),
-    }
+fn get_jump_targets(instruction_list: &[asm::Instruction]) -> HashSet<String> {
+    instruction_list
+        .iter()
+        .filter_map(|instruction| match instruction {
+            asm::Instruction::Jmp(label) | asm::Instruction::Jcc(_, label) => Some(label.clone()),
+            _ => None,
+        })
+        .collect()
 }
 
-fn build_comparison_op(left_reg: &str, right_reg: &str, operation: &str) -> Vec<String> {
-    let compare = format!("cmp {}, {}", left_reg, right_reg);
-    let set8 = match operation {
-        "==" => format!("sete al"),
-        "<" => format!("setb al"),
-        ">" => format!("seta al"),
-        "!=" => format!("setne al"),
-        "<=" => format!("setbe al"),
-        ">=" => format!("setae al"),
-        _ => panic!("Unrecognised op {}!", operation),
-    };
-    let assign = format!("movzx {}, al", left_reg);
-    vec![compare, set8, assign]
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir_text::parse_ir;
 
-fn build_factor_op(left_reg: &str, right_reg: &str, operation: &str) -> Vec<String> {
-    let mut factor_op = Vec::<String>::new();
-    factor_op.push(format!("mov rax, {}", left_reg));
-    let mut op = match operation {
-        "*" => vec![format!("mul {}", right_reg)],
-        "/" => vec![format!("xor rdx, rdx"), format!("div {}", right_reg)],
-        "%" => vec![
-            format!("xor rdx, rdx"),
-            format!("div {}", right_reg),
-            format!("mov rax, rdx"),
-        ],
-        _ => panic!("Unrecognised factor op {}", operation),
-    };
-    if left_reg == "rax" {
-        return op;
-    };
-    factor_op.append(&mut op);
-    factor_op.push(format!("mov {}, rax", left_reg));
-    factor_op
+    // The stack slot each variable was given, and the size of the frame
+    fn allocate_slots(text: &str) -> (HashMap<String, Option<u64>>, u64) {
+        let (function, mut symbol_table) = parse_ir(text);
+        let allocation = allocate(&function, &mut symbol_table);
+        let slots = symbol_table
+            .iter()
+            .map(|(id, symbol)| (id.clone(), symbol.stack_offset))
+            .collect();
+        (slots, allocation.stack_offset)
+    }
+
+    #[test]
+    fn variables_with_disjoint_live_ranges_share_a_slot() {
+        let (slots, frame_size) = allocate_slots(
+            "mut a: int
+            mut b: int
+            mut c: int
+
+            block_0:
+                store a, 1
+                t0 = load a
+                store b, t0
+                t1 = load b
+                store c, t1
+                t2 = load c
+                exit t2",
+        );
+        assert_eq!(slots["a"], Some(8));
+        assert_eq!(slots["b"], Some(8));
+        assert_eq!(slots["c"], Some(8));
+        assert_eq!(frame_size, 8);
+    }
+
+    #[test]
+    fn overlapping_and_escaped_variables_get_their_own_slots() {
+        let (slots, _) = allocate_slots(
+            "mut a: int
+            mut b: int
+            mut unused: int
+
+            block_0:
+                store a, 1
+                store b, 2
+                t0 = load a
+                t1 = load b
+                t2 = add t0, t1
+                exit t2",
+        );
+        assert_ne!(slots["a"], slots["b"]);
+        assert_eq!(slots["unused"], None);
+
+        // t0 points at a, so a has to keep its slot after its last load
+        let (slots, _) = allocate_slots(
+            "mut a: int
+            mut b: int
+
+            block_0:
+                store a, 1
+                t0 = addr a
+                t1 = load a
+                store b, t1
+                t2 = loadptr t0
+                t3 = load b
+                t4 = add t2, t3
+                exit t4",
+        );
+        assert_ne!(slots["a"], slots["b"]);
+    }
+
+    // Run the instructions build_division_by_constant produces, with the dividend in rbx
+    fn run_division(divisor: i64, operation: BinaryOp, dividend: i64) -> i64 {
+        let mut instructions = Vec::new();
+        let result = build_division_by_constant(
+            asm::Operand::Register(Register::Rbx),
+            divisor,
+            operation,
+            &mut instructions,
+        );
+        let mut registers = HashMap::from([(Register::Rbx, dividend)]);
+        let value = |registers: &HashMap<Register, i64>, operand: asm::Operand| match operand {
+            asm::Operand::Register(register) => registers[&register],
+            asm::Operand::Immediate(value) => value,
+            asm::Operand::Memory(_) => unreachable!("Division by a constant doesn't touch memory"),
+        };
+        for instruction in instructions {
+            let (dest, result) = match instruction {
+                asm::Instruction::Mov(dest, src) => (dest, value(&registers, src)),
+                asm::Instruction::Add(dest, src) => (
+                    dest,
+                    value(&registers, dest).wrapping_add(value(&registers, src)),
+                ),
+                asm::Instruction::Sub(dest, src) => (
+                    dest,
+                    value(&registers, dest).wrapping_sub(value(&registers, src)),
+                ),
+                asm::Instruction::And(dest, src) => {
+                    (dest, value(&registers, dest) & value(&registers, src))
+                }
+                asm::Instruction::Sar(dest, shift) => (dest, value(&registers, dest) >> shift),
+                asm::Instruction::Shr(dest, shift) => {
+                    (dest, ((value(&registers, dest) as u64) >> shift) as i64)
+                }
+                asm::Instruction::Neg(dest) => (dest, value(&registers, dest).wrapping_neg()),
+                asm::Instruction::Imul(dest, src) => (
+                    asm::Operand::Register(dest),
+                    registers[&dest].wrapping_mul(value(&registers, src)),
+                ),
+                asm::Instruction::WideImul(src) => {
+                    let product =
+                        registers[&Register::Rax] as i128 * value(&registers, src) as i128;
+                    registers.insert(Register::Rax, product as i64);
+                    (
+                        asm::Operand::Register(Register::Rdx),
+                        (product >> 64) as i64,
+                    )
+                }
+                _ => unreachable!("Unexpected {:?} in division by a constant", instruction),
+            };
+            let asm::Operand::Register(dest) = dest else {
+                unreachable!("Division by a constant only writes to registers")
+            };
+            registers.insert(dest, result);
+        }
+        registers[&result]
+    }
+
+    #[test]
+    fn division_by_constants_matches_idiv() {
+        let divisors = [
+            2,
+            3,
+            7,
+            10,
+            641,
+            1 << 40,
+            (1 << 62) + 1,
+            i64::MAX,
+            -2,
+            -3,
+            -7,
+            -8,
+            -(1 << 62),
+            -i64::MAX,
+        ];
+        let mut dividends = vec![i64::MIN, i64::MIN + 1, i64::MAX, i64::MAX - 1];
+        dividends.extend(-300..300);
+        // Spread more dividends over the whole range with a simple generator
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0..2000 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            dividends.push(state as i64);
+        }
+        for divisor in divisors {
+            for &dividend in &dividends {
+                assert_eq!(
+                    run_division(divisor, BinaryOp::Div, dividend),
+                    dividend.wrapping_div(divisor),
+                    "{} / {}",
+                    dividend,
+                    divisor
+                );
+                assert_eq!(
+                    run_division(divisor, BinaryOp::Mod, dividend),
+                    dividend.wrapping_rem(divisor),
+                    "{} % {}",
+                    dividend,
+                    divisor
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn division_magic_matches_known_values() {
+        // From the table in Hacker's Delight 10-1
+        assert_eq!(get_division_magic(3), (0x5555555555555556, 0));
+        assert_eq!(get_division_magic(7), (0x4924924924924925, 1));
+    }
 }
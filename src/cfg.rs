@@ -0,0 +1,304 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::{BlockId, Function, Instruction};
+
+// The edges between the blocks of a function. Only blocks reachable from the entry are included
+#[derive(Debug)]
+pub struct ControlFlowGraph {
+    pub entry: BlockId,
+    pub successors: HashMap<BlockId, Vec<BlockId>>,
+    pub predecessors: HashMap<BlockId, Vec<BlockId>>,
+    // Reachable blocks ordered so that every block comes before its successors, apart from
+    // along back edges
+    pub reverse_postorder: Vec<BlockId>,
+}
+
+#[derive(Debug)]
+pub struct DominatorTree {
+    // The closest block that every path from the entry to a block must pass through. The entry
+    // is its own immediate dominator
+    pub immediate_dominators: HashMap<BlockId, BlockId>,
+    pub children: HashMap<BlockId, Vec<BlockId>>,
+}
+
+impl ControlFlowGraph {
+    pub fn new(function: &Function) -> Self {
+        let entry = function
+            .blocks
+            .first()
+            .expect("A function should always have an entry block")
+            .id;
+        let mut successors = HashMap::new();
+        for block in &function.blocks {
+            let mut block_successors = block.terminator.successors();
+            block_successors.dedup();
+            successors.insert(block.id, block_successors);
+        }
+
+        // Depth first search from the entry, recording blocks once all their successors have
+        // been visited
+        let mut postorder = Vec::new();
+        let mut visited = HashSet::from([entry]);
+        let mut stack = vec![(entry, 0)];
+        while let Some((block, next_successor)) = stack.pop() {
+            match successors[&block].get(next_successor) {
+                Some(successor) => {
+                    stack.push((block, next_successor + 1));
+                    if visited.insert(*successor) {
+                        stack.push((*successor, 0));
+                    }
+                }
+                None => postorder.push(block),
+            }
+        }
+        let reverse_postorder: Vec<BlockId> = postorder.into_iter().rev().collect();
+
+        successors.retain(|block, _| visited.contains(block));
+        let mut predecessors: HashMap<BlockId, Vec<BlockId>> = reverse_postorder
+            .iter()
+            .map(|block| (*block, Vec::new()))
+            .collect();
+        for block in &reverse_postorder {
+            for successor in &successors[block] {
+                predecessors
+                    .get_mut(successor)
+                    .expect("Successors of reachable blocks are reachable")
+                    .push(*block);
+            }
+        }
+
+        Self {
+            entry,
+            successors,
+            predecessors,
+            reverse_postorder,
+        }
+    }
+
+    pub fn is_reachable(&self, block: BlockId) -> bool {
+        self.successors.contains_key(&block)
+    }
+}
+
+impl DominatorTree {
+    // The iterative algorithm from Cooper, Harvey and Kennedy's "A Simple, Fast Dominance
+    // Algorithm": keep intersecting the dominators of each block's predecessors until nothing
+    // changes
+    pub fn new(cfg: &ControlFlowGraph) -> Self {
+        let order: HashMap<BlockId, usize> = cfg
+            .reverse_postorder
+            .iter()
+            .enumerate()
+            .map(|(index, block)| (*block, index))
+            .collect();
+        let mut immediate_dominators = HashMap::from([(cfg.entry, cfg.entry)]);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in cfg.reverse_postorder.iter().skip(1) {
+                let mut processed = cfg.predecessors[block]
+                    .iter()
+                    .filter(|pred| immediate_dominators.contains_key(*pred));
+                let mut new_dominator = *processed
+                    .next()
+                    .expect("Every block after the entry has a processed predecessor");
+                for pred in processed {
+                    new_dominator = intersect(&immediate_dominators, &order, *pred, new_dominator);
+                }
+                if immediate_dominators.get(block) != Some(&new_dominator) {
+                    immediate_dominators.insert(*block, new_dominator);
+                    changed = true;
+                }
+            }
+        }
+
+        let mut children: HashMap<BlockId, Vec<BlockId>> = cfg
+            .reverse_postorder
+            .iter()
+            .map(|block| (*block, Vec::new()))
+            .collect();
+        for block in cfg.reverse_postorder.iter().skip(1) {
+            children
+                .get_mut(&immediate_dominators[block])
+                .expect("Dominators are reachable")
+                .push(*block);
+        }
+
+        Self {
+            immediate_dominators,
+            children,
+        }
+    }
+
+    // Whether every path from the entry to b passes through a
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        let mut current = b;
+        loop {
+            if current == a {
+                return true;
+            }
+            let dominator = self.immediate_dominators[&current];
+            if dominator == current {
+                return false;
+            }
+            current = dominator;
+        }
+    }
+
+    // The blocks where a block's dominance ends: each frontier block has a predecessor that the
+    // block dominates, but isn't strictly dominated by the block itself
+    pub fn dominance_frontiers(
+        &self,
+        cfg: &ControlFlowGraph,
+    ) -> HashMap<BlockId, HashSet<BlockId>> {
+        let mut frontiers: HashMap<BlockId, HashSet<BlockId>> = cfg
+            .reverse_postorder
+            .iter()
+            .map(|block| (*block, HashSet::new()))
+            .collect();
+        for block in &cfg.reverse_postorder {
+            let predecessors = &cfg.predecessors[block];
+            if predecessors.len() < 2 {
+                continue;
+            }
+            for pred in predecessors {
+                let mut runner = *pred;
+                while runner != self.immediate_dominators[block] {
+                    frontiers
+                        .get_mut(&runner)
+                        .expect("Predecessors are reachable")
+                        .insert(*block);
+                    runner = self.immediate_dominators[&runner];
+                }
+            }
+        }
+        frontiers
+    }
+
+    // Blocks in an order where every block comes after its dominator
+    pub fn preorder(&self, entry: BlockId) -> Vec<BlockId> {
+        let mut preorder = Vec::new();
+        let mut stack = vec![entry];
+        while let Some(block) = stack.pop() {
+            preorder.push(block);
+            stack.extend(self.children[&block].iter().rev());
+        }
+        preorder
+    }
+}
+
+// Walk up the tree from both blocks until they meet at their closest common dominator
+fn intersect(
+    immediate_dominators: &HashMap<BlockId, BlockId>,
+    order: &HashMap<BlockId, usize>,
+    mut a: BlockId,
+    mut b: BlockId,
+) -> BlockId {
+    while a != b {
+        while order[&a] > order[&b] {
+            a = immediate_dominators[&a];
+        }
+        while order[&b] > order[&a] {
+            b = immediate_dominators[&b];
+        }
+    }
+    a
+}
+
+// Remove every block that can't be reached from the entry, along with their entries in any
+// phis. Returns the removed blocks
+pub fn remove_unreachable_blocks(function: &mut Function) -> Vec<BlockId> {
+    let cfg = ControlFlowGraph::new(function);
+    let mut removed = Vec::new();
+    function.blocks.retain(|block| {
+        if cfg.is_reachable(block.id) {
+            true
+        } else {
+            removed.push(block.id);
+            false
+        }
+    });
+    for block in &mut function.blocks {
+        for instruction in &mut block.instructions {
+            if let Instruction::Phi(_, incoming) = instruction {
+                incoming.retain(|(pred, _)| cfg.is_reachable(*pred));
+            }
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{Operand, Temp};
+    use crate::ir_text::parse_ir;
+
+    // A diamond from block_0 through block_1 or block_2 to block_3, which loops back to itself
+    // through block_4. block_5 can't be reached
+    const DIAMOND: &str = "block_0:
+            branch 1, block_1, block_2
+        block_1:
+            jump block_3
+        block_2:
+            jump block_3
+        block_3:
+            branch 1, block_4, block_6
+        block_4:
+            jump block_3
+        block_5:
+            jump block_3
+        block_6:
+            exit 0";
+
+    #[test]
+    fn finds_immediate_dominators_and_frontiers() {
+        let (function, _) = parse_ir(DIAMOND);
+        let cfg = ControlFlowGraph::new(&function);
+        let tree = DominatorTree::new(&cfg);
+        assert!(!cfg.is_reachable(BlockId(5)));
+        assert!(!tree.immediate_dominators.contains_key(&BlockId(5)));
+
+        let idom = |block| tree.immediate_dominators[&BlockId(block)];
+        assert_eq!(idom(0), BlockId(0));
+        assert_eq!(idom(1), BlockId(0));
+        assert_eq!(idom(2), BlockId(0));
+        // Neither side of the diamond dominates where it meets
+        assert_eq!(idom(3), BlockId(0));
+        assert_eq!(idom(4), BlockId(3));
+        assert_eq!(idom(6), BlockId(3));
+        assert!(tree.dominates(BlockId(3), BlockId(4)));
+        assert!(!tree.dominates(BlockId(1), BlockId(3)));
+
+        let frontiers = tree.dominance_frontiers(&cfg);
+        let frontier = |block| {
+            let mut frontier: Vec<BlockId> = frontiers[&BlockId(block)].iter().copied().collect();
+            frontier.sort();
+            frontier
+        };
+        assert_eq!(frontier(1), vec![BlockId(3)]);
+        assert_eq!(frontier(2), vec![BlockId(3)]);
+        // The back edge puts the loop header in its own frontier
+        assert_eq!(frontier(3), vec![BlockId(3)]);
+        assert_eq!(frontier(4), vec![BlockId(3)]);
+        assert_eq!(frontier(0), Vec::new());
+    }
+
+    #[test]
+    fn removes_unreachable_blocks_from_phis() {
+        let (mut function, _) = parse_ir(
+            "block_0:
+                jump block_2
+            block_1:
+                jump block_2
+            block_2:
+                t0 = phi [block_0: 1], [block_1: 2]
+                exit t0",
+        );
+        assert_eq!(remove_unreachable_blocks(&mut function), vec![BlockId(1)]);
+        assert_eq!(
+            function.block(BlockId(2)).instructions[0],
+            Instruction::Phi(Temp(0), vec![(BlockId(0), Operand::Const(1))])
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+
+use crate::cfg::{remove_unreachable_blocks, ControlFlowGraph, DominatorTree};
+use crate::ir::{BinaryOp, BlockId, Function, Instruction, Operand, Temp, Terminator, UnaryOp};
+use crate::representations::Symbol;
+
+// Evaluate everything that can be worked out at compile time. Expressions with constant
+// operands are replaced by their value, const symbols are replaced by the value they were
+// declared with, and branches on a known condition become jumps, with the block that can no
+// longer be reached removed. Runs on SSA form, repeating until nothing else can be folded
+pub fn fold_constants(function: &mut Function, symbol_table: &HashMap<String, Symbol>) {
+    loop {
+        let mut changed = propagate_const_symbols(function, symbol_table);
+        changed |= propagate_constants(function);
+        changed |= !fold_branches(function).is_empty();
+        changed |= !remove_unreachable_blocks(function).is_empty();
+        if !changed {
+            break;
+        }
+    }
+}
+
+// Replace reads of a const symbol with its value, as long as it was declared with a constant
+// and the declaration is always run before the read. Reads through a pointer made with & count
+// as reads of the symbol. Variables promoted by SSA construction are handled by
+// propagate_constants instead, as their loads are already gone
+fn propagate_const_symbols(
+    function: &mut Function,
+    symbol_table: &HashMap<String, Symbol>,
+) -> bool {
+    // Find the one store to each const symbol, as (block, position in block, value)
+    let mut stores = HashMap::<&str, Vec<(BlockId, usize, Operand)>>::new();
+    let mut addresses = HashMap::<Temp, &str>::new();
+    for block in &function.blocks {
+        for (position, instruction) in block.instructions.iter().enumerate() {
+            match instruction {
+                Instruction::Store(id, value) => stores
+                    .entry(id.as_str())
+                    .or_default()
+                    .push((block.id, position, *value)),
+                Instruction::AddressOf(dest, id) => {
+                    addresses.insert(*dest, id.as_str());
+                }
+                _ => (),
+            }
+        }
+    }
+    let const_values: HashMap<String, (BlockId, usize, i64)> = stores
+        .into_iter()
+        .filter_map(
+            |(id, stores)| match (symbol_table.get(id), stores.as_slice()) {
+                (Some(symbol_info), [(block, position, Operand::Const(value))])
+                    if !symbol_info.mutable =>
+                {
+                    Some((id.to_string(), (*block, *position, *value)))
+                }
+                _ => None,
+            },
+        )
+        .collect();
+    let addresses: HashMap<Temp, String> = addresses
+        .into_iter()
+        .map(|(temp, id)| (temp, id.to_string()))
+        .collect();
+    if const_values.is_empty() {
+        return false;
+    }
+
+    let cfg = ControlFlowGraph::new(function);
+    let dominator_tree = DominatorTree::new(&cfg);
+    let mut changed = false;
+    for block in &mut function.blocks {
+        for (position, instruction) in block.instructions.iter_mut().enumerate() {
+            let (dest, id) = match instruction {
+                Instruction::Load(dest, id) => (*dest, id.clone()),
+                Instruction::LoadPointer(dest, Operand::Temp(address)) => {
+                    match addresses.get(address) {
+                        Some(id) => (*dest, id.clone()),
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+            let Some((store_block, store_position, value)) = const_values.get(&id) else {
+                continue;
+            };
+            let store_runs_first = if *store_block == block.id {
+                *store_position < position
+            } else {
+                dominator_tree.dominates(*store_block, block.id)
+            };
+            if store_runs_first {
+                *instruction = Instruction::Copy(dest, Operand::Const(*value));
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// Work out the value of every temp whose operands are all constant, then replace every use of
+// those temps with the value and remove the instructions that computed them. A temp defined
+// more than once, which only happens when SSA construction was disabled, is left alone
+fn propagate_constants(function: &mut Function) -> bool {
+    let mut definition_counts = HashMap::<Temp, usize>::new();
+    for block in &function.blocks {
+        for dest in block.instructions.iter().filter_map(Instruction::dest) {
+            *definition_counts.entry(dest).or_default() += 1;
+        }
+    }
+    let mut constants = HashMap::<Temp, i64>::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &mut function.blocks {
+            for instruction in &mut block.instructions {
+                replace_constant_operands(instruction.operands_mut(), &constants);
+                let Some(dest) = instruction.dest() else {
+                    continue;
+                };
+                if constants.contains_key(&dest) || definition_counts[&dest] > 1 {
+                    continue;
+                }
+                if let Some(value) = evaluate_instruction(instruction) {
+                    constants.insert(dest, value);
+                    changed = true;
+                }
+            }
+        }
+    }
+    if constants.is_empty() {
+        return false;
+    }
+
+    for block in &mut function.blocks {
+        block.instructions.retain(|instruction| {
+            !instruction
+                .dest()
+                .is_some_and(|dest| constants.contains_key(&dest))
+        });
+        replace_constant_operands(block.terminator.operands_mut(), &constants);
+    }
+    true
+}
+
+fn replace_constant_operands(operands: Vec<&mut Operand>, constants: &HashMap<Temp, i64>) {
+    for operand in operands {
+        if let Operand::Temp(temp) = operand {
+            if let Some(value) = constants.get(temp) {
+                *operand = Operand::Const(*value);
+            }
+        }
+    }
+}
+
+// The value an instruction produces, if it can be known at compile time
+fn evaluate_instruction(instruction: &Instruction) -> Option<i64> {
+    match instruction {
+        Instruction::Copy(_, Operand::Const(value)) => Some(*value),
+        Instruction::Binary(_, op, Operand::Const(left), Operand::Const(right)) => {
+            evaluate_binary(*op, *left, *right)
+        }
+        Instruction::Unary(_, UnaryOp::Neg, Operand::Const(value)) => Some(value.wrapping_neg()),
+        // A phi where every incoming value is the same constant
+        Instruction::Phi(_, incoming) => {
+            let mut values = incoming.iter().map(|(_, operand)| match operand {
+                Operand::Const(value) => Some(*value),
+                Operand::Temp(_) => None,
+            });
+            let first = values.next()??;
+            values.all(|value| value == Some(first)).then_some(first)
+        }
+        _ => None,
+    }
+}
+
+// Arithmetic wraps on overflow to match the generated code. Division that would trap at
+// runtime, by zero or overflowing, is left for the program to do
+fn evaluate_binary(op: BinaryOp, left: i64, right: i64) -> Option<i64> {
+    match op {
+        BinaryOp::Add => Some(left.wrapping_add(right)),
+        BinaryOp::Sub => Some(left.wrapping_sub(right)),
+        BinaryOp::Mul => Some(left.wrapping_mul(right)),
+        BinaryOp::Div => left.checked_div(right),
+        BinaryOp::Mod => left.checked_rem(right),
+        BinaryOp::Eq => Some((left == right) as i64),
+        BinaryOp::Ne => Some((left != right) as i64),
+        BinaryOp::Lt => Some((left < right) as i64),
+        BinaryOp::Gt => Some((left > right) as i64),
+        BinaryOp::Le => Some((left <= right) as i64),
+        BinaryOp::Ge => Some((left >= right) as i64),
+    }
+}
+
+// Turn branches on a constant condition into jumps. The block that is no longer jumped to loses
+// its phi entries for this block. Returns the blocks whose branch was folded
+pub fn fold_branches(function: &mut Function) -> Vec<BlockId> {
+    let mut folded = Vec::new();
+    let mut removed_edges = Vec::<(BlockId, BlockId)>::new();
+    for block in &mut function.blocks {
+        if let Terminator::Branch(Operand::Const(value), if_true, if_false) = block.terminator {
+            folded.push(block.id);
+            let (taken, not_taken) = if value != 0 {
+                (if_true, if_false)
+            } else {
+                (if_false, if_true)
+            };
+            block.terminator = Terminator::Jump(taken);
+            if taken != not_taken {
+                removed_edges.push((block.id, not_taken));
+            }
+        }
+    }
+    for (from, to) in &removed_edges {
+        for instruction in &mut function.block_mut(*to).instructions {
+            if let Instruction::Phi(_, incoming) = instruction {
+                incoming.retain(|(pred, _)| pred != from);
+            }
+        }
+    }
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir_text::{parse_ir, write_ir};
+
+    fn fold(text: &str) -> String {
+        let (mut function, symbol_table) = parse_ir(text);
+        fold_constants(&mut function, &symbol_table);
+        write_ir(&function, &symbol_table)
+    }
+
+    #[test]
+    fn folds_arithmetic_and_the_branches_it_decides() {
+        let folded = fold(
+            "block_0:
+                t0 = add 2, 3
+                t1 = mul t0, 4
+                t2 = neg t1
+                t3 = lt t2, 0
+                branch t3, block_1, block_2
+            block_1:
+                jump block_3
+            block_2:
+                jump block_3
+            block_3:
+                t4 = phi [block_1: t2], [block_2: 7]
+                exit t4",
+        );
+        assert_eq!(
+            folded,
+            "block_0:
+    jump block_1
+block_1:
+    jump block_3
+block_3:
+    exit -20
+"
+        );
+    }
+
+    #[test]
+    fn leaves_division_that_would_trap_and_unknown_values() {
+        let text = "mut x: int
+
+block_0:
+    t0 = div 1, 0
+    t1 = load x
+    t2 = add t1, 1
+    exit t2
+";
+        assert_eq!(fold(text), text);
+    }
+
+    #[test]
+    fn replaces_reads_of_const_symbols_the_declaration_dominates() {
+        let folded = fold(
+            "const c: int
+
+            block_0:
+                store c, 6
+                t0 = addr c
+                t1 = loadptr t0
+                t2 = load c
+                t3 = add t1, t2
+                exit t3",
+        );
+        assert!(folded.contains("exit 12"), "{}", folded);
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::cfg::remove_unreachable_blocks;
+use crate::constant_folding::fold_branches;
+use crate::ir::{BinaryOp, BlockId, Function, Instruction, Operand, Temp};
+
+// Everything removed by dead code elimination, so it can be reported in verbose mode
+#[derive(Debug, Default)]
+pub struct DeadCodeReport {
+    pub folded_branches: Vec<BlockId>,
+    // Each removed block with the number of instructions it held
+    pub unreachable_blocks: Vec<(BlockId, usize)>,
+    pub dead_stores: Vec<String>,
+    pub dead_instructions: usize,
+}
+
+// Remove code that can never run or whose result is never used: branches on constant conditions
+// become jumps, unreachable blocks are removed, stores to variables that are never read again
+// are dropped, and instructions whose results are unused are deleted. Repeats until nothing else
+// can be removed, as each kind of removal can expose more of the others
+pub fn eliminate_dead_code(function: &mut Function) -> DeadCodeReport {
+    let mut report = DeadCodeReport::default();
+    loop {
+        let folded = fold_branches(function);
+        let instruction_counts: HashMap<BlockId, usize> = function
+            .blocks
+            .iter()
+            .map(|block| (block.id, block.instructions.len()))
+            .collect();
+        let unreachable = remove_unreachable_blocks(function);
+        let dead_stores = remove_dead_stores(function);
+        let dead_instructions = remove_dead_instructions(function);
+
+        let changed = !folded.is_empty()
+            || !unreachable.is_empty()
+            || !dead_stores.is_empty()
+            || dead_instructions != 0;
+        report.folded_branches.extend(folded);
+        report.unreachable_blocks.extend(
+            unreachable
+                .into_iter()
+                .map(|block| (block, instruction_counts[&block])),
+        );
+        report.dead_stores.extend(dead_stores);
+        report.dead_instructions += dead_instructions;
+        if !changed {
+            return report;
+        }
+    }
+}
+
+impl fmt::Display for DeadCodeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for block in &self.folded_branches {
+            writeln!(
+                f,
+                "dce: folded constant branch at the end of block_{}",
+                block.0
+            )?;
+        }
+        for (block, instruction_count) in &self.unreachable_blocks {
+            writeln!(
+                f,
+                "dce: removed unreachable block_{} ({} instructions)",
+                block.0, instruction_count
+            )?;
+        }
+        for id in &self.dead_stores {
+            writeln!(f, "dce: removed dead store to {}", id)?;
+        }
+        writeln!(
+            f,
+            "dce: removed {} instructions with unused results",
+            self.dead_instructions
+        )
+    }
+}
+
+// Remove stores to variables that are overwritten or never read before the program ends.
+// Variables that have their address taken are left alone, as they can be read through a pointer.
+// Returns the variable of each removed store
+fn remove_dead_stores(function: &mut Function) -> Vec<String> {
+    let escaped: HashSet<String> = function
+        .blocks
+        .iter()
+        .flat_map(|block| &block.instructions)
+        .filter_map(|instruction| match instruction {
+            Instruction::AddressOf(_, id) => Some(id.clone()),
+            _ => None,
+        })
+        .collect();
+
+    // Backwards dataflow to find which variables are live out of each block
+    let block_indices: HashMap<BlockId, usize> = function
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(index, block)| (block.id, index))
+        .collect();
+    let mut live_in = vec![HashSet::<String>::new(); function.blocks.len()];
+    let mut live_out = vec![HashSet::<String>::new(); function.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (index, block) in function.blocks.iter().enumerate().rev() {
+            let mut live = HashSet::new();
+            for successor in block.terminator.successors() {
+                live.extend(live_in[block_indices[&successor]].iter().cloned());
+            }
+            live_out[index] = live.clone();
+            for instruction in block.instructions.iter().rev() {
+                match instruction {
+                    Instruction::Store(id, _) => {
+                        live.remove(id);
+                    }
+                    Instruction::Load(_, id) => {
+                        live.insert(id.clone());
+                    }
+                    _ => (),
+                }
+            }
+            if live != live_in[index] {
+                live_in[index] = live;
+                changed = true;
+            }
+        }
+    }
+
+    let mut removed = Vec::new();
+    for (block, mut live) in function.blocks.iter_mut().zip(live_out) {
+        let mut keep = vec![true; block.instructions.len()];
+        for (position, instruction) in block.instructions.iter().enumerate().rev() {
+            match instruction {
+                Instruction::Store(id, _) => {
+                    keep[position] = live.remove(id) || escaped.contains(id);
+                    if !keep[position] {
+                        removed.push(id.clone());
+                    }
+                }
+                Instruction::Load(_, id) => {
+                    live.insert(id.clone());
+                }
+                _ => (),
+            }
+        }
+        let mut keep = keep.into_iter();
+        block
+            .instructions
+            .retain(|_| keep.next().expect("One flag per instruction"));
+    }
+    removed
+}
+
+// Mark every temp that something with a side effect depends on, starting from stores,
+// terminators and instructions that can trap, then remove every instruction whose result
+// wasn't marked. Returns the number of instructions removed
+fn remove_dead_instructions(function: &mut Function) -> usize {
+    let mut definitions = HashMap::<Temp, Vec<Operand>>::new();
+    let mut worklist = Vec::<Temp>::new();
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            match instruction.dest() {
+                Some(dest) if !has_side_effect(instruction) => {
+                    definitions
+                        .entry(dest)
+                        .or_default()
+                        .extend(instruction.operands());
+                }
+                _ => worklist.extend(instruction.operands().iter().filter_map(Operand::as_temp)),
+            }
+        }
+        worklist.extend(
+            block
+                .terminator
+                .operands()
+                .iter()
+                .filter_map(Operand::as_temp),
+        );
+    }
+
+    let mut used = HashSet::new();
+    while let Some(temp) = worklist.pop() {
+        if used.insert(temp) {
+            if let Some(operands) = definitions.get(&temp) {
+                worklist.extend(operands.iter().filter_map(Operand::as_temp));
+            }
+        }
+    }
+
+    let mut removed = 0;
+    for block in &mut function.blocks {
+        let before = block.instructions.len();
+        block
+            .instructions
+            .retain(|instruction| match instruction.dest() {
+                Some(dest) => has_side_effect(instruction) || used.contains(&dest),
+                None => true,
+            });
+        removed += before - block.instructions.len();
+    }
+    removed
+}
+
+// Instructions that do something other than produce their result. Division traps when the
+// divisor is zero, or when it is -1 and the result overflows, so it is only free of side
+// effects when the divisor is a constant that can't do either
+pub fn has_side_effect(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::Store(_, _) | Instruction::StorePointer(_, _) => true,
+        Instruction::Binary(_, BinaryOp::Div | BinaryOp::Mod, _, divisor) => {
+            !matches!(divisor, Operand::Const(value) if *value != 0 && *value != -1)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir_text::{parse_ir, write_ir};
+
+    #[test]
+    fn removes_dead_stores_instructions_and_blocks() {
+        let (mut function, symbol_table) = parse_ir(
+            "mut x: int
+            mut y: int
+
+            block_0:
+                store x, 1
+                store x, 2
+                store y, 3
+                t0 = mul 4, 5
+                t1 = load x
+                branch 0, block_1, block_2
+            block_1:
+                t2 = load y
+                exit t2
+            block_2:
+                exit t1",
+        );
+        let report = eliminate_dead_code(&mut function);
+        // The first store to x is overwritten, and y is only read in the block that is removed
+        assert_eq!(report.folded_branches, vec![BlockId(0)]);
+        assert_eq!(report.unreachable_blocks, vec![(BlockId(1), 1)]);
+        let mut dead_stores = report.dead_stores.clone();
+        dead_stores.sort();
+        assert_eq!(dead_stores, vec!["x".to_string(), "y".to_string()]);
+        assert_eq!(report.dead_instructions, 1);
+        assert_eq!(
+            write_ir(&function, &symbol_table),
+            "mut x: int
+mut y: int
+
+block_0:
+    store x, 2
+    t1 = load x
+    jump block_2
+block_2:
+    exit t1
+"
+        );
+    }
+
+    #[test]
+    fn keeps_instructions_with_side_effects() {
+        let text = "mut a: int
+
+block_0:
+    store a, 1
+    t0 = addr a
+    t1 = div 7, 0
+    t2 = div 7, 2
+    t3 = loadptr t0
+    exit t3
+";
+        let (mut function, symbol_table) = parse_ir(text);
+        let report = eliminate_dead_code(&mut function);
+        // a is only read through a pointer, so its store has to stay, as does the division by
+        // zero so that it traps. The other division is unused
+        assert!(report.dead_stores.is_empty());
+        assert_eq!(report.dead_instructions, 1);
+        assert_eq!(
+            write_ir(&function, &symbol_table),
+            "mut a: int
+
+block_0:
+    store a, 1
+    t0 = addr a
+    t1 = div 7, 0
+    t3 = loadptr t0
+    exit t3
+"
+        );
+    }
+}
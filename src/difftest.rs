@@ -0,0 +1,229 @@
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use crate::interp::{interpret_with_limit, Trap};
+use crate::reduce::reduce;
+use crate::sandbox::{run_sandboxed, Limits};
+use crate::toolchain::TempDir;
+use crate::{compile, lex, parse, Diagnostic, Options};
+
+// Checks the compiler against the interpreter: every program in a corpus is interpreted and
+// compiled to a native executable at each optimisation level, and any executable that doesn't
+// do what the interpreter did is reported along with the smallest version of the program that
+// still goes wrong. The language has no output, so the exit status is all there is to compare
+
+// How a program finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    // Only the low byte of the exit value survives the exit system call
+    Exit(u8),
+    Signal(i32),
+    // The compiled program was killed for running too long
+    TimedOut,
+    // The compiler gave up on a program the front end accepted
+    CompilerFailed(Diagnostic),
+}
+
+// Programs that take longer than this in the interpreter aren't checked. Compiled programs get
+// far longer than they should need to do the same work before they are killed
+const MAX_STEPS: u64 = 1_000_000;
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// A compiled program that didn't do what the interpreter did
+pub struct Divergence {
+    pub path: PathBuf,
+    pub opt_level: u8,
+    pub expected: Outcome,
+    pub actual: Outcome,
+    pub minimal_program: String,
+}
+
+// What one program did in the interpreter, or why it couldn't be checked
+enum Reference {
+    Expected(Outcome),
+    // The program is rejected by the front end or its behaviour isn't defined
+    Unchecked(String),
+}
+
+// Run every .ttc file in the given files and directories at each optimisation level, returning
+// every divergence found. Executables are written to a temporary directory that is removed
+// afterwards. A program the compiler fails on counts as a divergence
+pub fn run_difftest(paths: &[PathBuf], opt_levels: &[u8]) -> Vec<Divergence> {
+    let mut programs = Vec::new();
+    for path in paths {
+        collect_programs(path, &mut programs);
+    }
+    let work_dir = TempDir::new("difftest");
+    let executable = work_dir.path.join("program");
+
+    let mut divergences = Vec::new();
+    let mut checked = 0;
+    let mut report = Vec::new();
+    for path in programs {
+        let source = std::fs::read_to_string(&path)
+            .unwrap_or_else(|error| panic!("Couldn't read {}: {}", path.display(), error));
+        let expected = match run_reference(&source) {
+            Reference::Expected(expected) => expected,
+            Reference::Unchecked(reason) => {
+                report.push(format!("skipped {}: {}", path.display(), reason));
+                continue;
+            }
+        };
+        checked += 1;
+        for &opt_level in opt_levels {
+            let actual = run_native(&source, opt_level, &executable);
+            if actual == expected {
+                continue;
+            }
+            // Keep shrinking the program for as long as the same optimisation level still diverges
+            // the same way. A compiler failure only has to happen in the same phase, as the
+            // message usually names things that change as the program shrinks
+            let minimal_program = reduce(&source, |candidate| match run_reference(candidate) {
+                Reference::Expected(expected) => {
+                    match (&actual, run_native(candidate, opt_level, &executable)) {
+                        (
+                            Outcome::CompilerFailed(failure),
+                            Outcome::CompilerFailed(candidate_failure),
+                        ) => candidate_failure.phase == failure.phase,
+                        (Outcome::CompilerFailed(_), _) => false,
+                        (_, candidate_actual) => candidate_actual != expected,
+                    }
+                }
+                Reference::Unchecked(_) => false,
+            });
+            divergences.push(Divergence {
+                path: path.clone(),
+                opt_level,
+                expected: expected.clone(),
+                actual,
+                minimal_program,
+            });
+        }
+    }
+
+    for line in report {
+        println!("{}", line);
+    }
+    println!(
+        "difftest: checked {} programs at {} optimisation levels, {} divergences",
+        checked,
+        opt_levels.len(),
+        divergences.len()
+    );
+    divergences
+}
+
+fn collect_programs(path: &Path, programs: &mut Vec<PathBuf>) {
+    if path.is_dir() {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(path)
+            .unwrap_or_else(|error| panic!("Couldn't read {}: {}", path.display(), error))
+            .map(|entry| entry.expect("Directory entries should be readable").path())
+            .collect();
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir()
+                || entry
+                    .extension()
+                    .is_some_and(|extension| extension == "ttc")
+            {
+                collect_programs(&entry, programs);
+            }
+        }
+    } else {
+        programs.push(path.to_path_buf());
+    }
+}
+
+// Division traps raise SIGFPE in the compiled program. Following a bad pointer reads whatever is
+// there, so the interpreter can't say what should happen
+fn run_reference(source: &str) -> Reference {
+    let Ok(program) = lex(source).and_then(parse) else {
+        return Reference::Unchecked("rejected by the front end".to_string());
+    };
+    match interpret_with_limit(&program.statements, &program.symbol_table, MAX_STEPS) {
+        Ok(exit_value) => Reference::Expected(Outcome::Exit(exit_value as u8)),
+        Err(Trap::DivisionByZero | Trap::DivisionOverflow) => {
+            Reference::Expected(Outcome::Signal(libc::SIGFPE))
+        }
+        Err(Trap::OutOfSteps) => {
+            Reference::Unchecked(format!("didn't finish within {} steps", MAX_STEPS))
+        }
+        Err(trap @ Trap::InvalidAddress(_)) => {
+            Reference::Unchecked(format!("undefined behaviour: {}", trap))
+        }
+    }
+}
+
+// Compile the program to an executable and run it
+fn run_native(source: &str, opt_level: u8, executable: &Path) -> Outcome {
+    let options = Options {
+        opt_level,
+        ..Options::default()
+    };
+    let artifact = match compile(source, &options) {
+        Ok(artifact) => artifact,
+        Err(mut diagnostics) => return Outcome::CompilerFailed(diagnostics.remove(0)),
+    };
+    std::fs::write(executable, artifact.executable())
+        .expect("Should be able to write the executable");
+    std::fs::set_permissions(executable, std::fs::Permissions::from_mode(0o755))
+        .expect("Should be able to make the executable runnable");
+    let limits = Limits {
+        timeout: TIMEOUT,
+        ..Limits::default()
+    };
+    let result = run_sandboxed(&mut Command::new(executable), &limits)
+        .unwrap_or_else(|error| panic!("Couldn't run {}: {}", executable.display(), error));
+    if result.timed_out {
+        return Outcome::TimedOut;
+    }
+    match (result.exit_code, result.signal) {
+        (Some(code), _) => Outcome::Exit(code as u8),
+        (None, Some(signal)) => Outcome::Signal(signal),
+        (None, None) => panic!("The program neither exited nor was killed"),
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Exit(code) => write!(f, "exit {}", code),
+            Outcome::Signal(signal) => write!(f, "signal {}", signal),
+            Outcome::TimedOut => write!(f, "timed out after {:?}", TIMEOUT),
+            Outcome::CompilerFailed(diagnostic) => write!(f, "{}", diagnostic),
+        }
+    }
+}
+
+impl Divergence {
+    pub fn print(&self) {
+        println!(
+            "{} at -O{}: expected {}, got {}",
+            self.path.display(),
+            self.opt_level,
+            self.expected,
+            self.actual
+        );
+        println!("minimal program:\n{}\n", self.minimal_program);
+    }
+}
+
+// The corpus is compiled and run natively, so this only runs on hosts that can
+// execute the generated code.
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corpus_matches_the_interpreter() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+        let divergences = run_difftest(&[corpus], &[0, 1, 2]);
+        for divergence in &divergences {
+            divergence.print();
+        }
+        assert!(divergences.is_empty());
+    }
+}
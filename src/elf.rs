@@ -0,0 +1,672 @@
+use std::collections::HashMap;
+
+use crate::asm;
+use crate::encoder::encode_with_labels;
+
+// Writes ELF64 files for x86-64 Linux, either as a relocatable object that a linker can take or
+// as a statically linked executable that can be run directly. Both are built from an Object
+// holding the contents of the .text, .data and .bss sections
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Text,
+    Data,
+    Bss,
+}
+
+// A named position in one of the sections. Global symbols are visible to the linker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub section: Section,
+    pub offset: u64,
+    pub global: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    // The symbol's address, as 8 bytes
+    Absolute64,
+    // The symbol's address relative to the end of the 4 bytes being written
+    PcRelative32,
+}
+
+// A place in .text that refers to a symbol whose address isn't known until the program is linked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: u64,
+    pub symbol: String,
+    pub kind: RelocationKind,
+    pub addend: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub data: Vec<u8>,
+    pub bss_size: u64,
+    pub symbols: Vec<ObjectSymbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+// The entry point of every program the compiler writes
+pub const ENTRY_SYMBOL: &str = "_start";
+
+// Executables are loaded at the usual address for non position independent x86-64 programs
+const BASE_ADDRESS: u64 = 0x400000;
+const PAGE_SIZE: u64 = 0x1000;
+
+const HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const SECTION_HEADER_SIZE: u64 = 64;
+const SYMBOL_SIZE: u64 = 24;
+const RELOCATION_SIZE: u64 = 24;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+
+const SHF_WRITE: u64 = 1;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+
+// .text, .data and .bss always come first after the null section, in this order
+const TEXT_INDEX: u16 = 1;
+const DATA_INDEX: u16 = 2;
+const BSS_INDEX: u16 = 3;
+const SYMTAB_INDEX: u32 = 4;
+const STRTAB_INDEX: u32 = 5;
+
+impl Object {
+    // The object for a program from the backend. The code starts at _start, and every label
+    // becomes a local symbol so the output can be debugged and disassembled
+    pub fn from_program(instructions: &[asm::Instruction]) -> Object {
+        let (text, labels) = encode_with_labels(instructions);
+        let mut symbols = vec![ObjectSymbol {
+            name: ENTRY_SYMBOL.to_string(),
+            section: Section::Text,
+            offset: 0,
+            global: true,
+        }];
+        symbols.extend(labels.into_iter().map(|(name, offset)| ObjectSymbol {
+            name,
+            section: Section::Text,
+            offset: offset as u64,
+            global: false,
+        }));
+        Object {
+            text,
+            symbols,
+            ..Object::default()
+        }
+    }
+
+    fn find_symbol(&self, name: &str) -> Option<&ObjectSymbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+}
+
+// Write a relocatable object, in the same form `nasm -f elf64` produces. Symbols that
+// relocations refer to but the object doesn't define are written as undefined globals for the
+// linker to find elsewhere
+pub fn write_object(object: &Object) -> Vec<u8> {
+    let mut file = vec![0; HEADER_SIZE as usize];
+    let mut sections = vec![SectionHeader::default()];
+
+    let text_offset = append_aligned(&mut file, &object.text, 16);
+    sections.push(SectionHeader {
+        name: ".text",
+        kind: SHT_PROGBITS,
+        flags: SHF_ALLOC | SHF_EXECINSTR,
+        offset: text_offset,
+        size: object.text.len() as u64,
+        align: 16,
+        ..SectionHeader::default()
+    });
+    let data_offset = append_aligned(&mut file, &object.data, 8);
+    sections.push(SectionHeader {
+        name: ".data",
+        kind: SHT_PROGBITS,
+        flags: SHF_ALLOC | SHF_WRITE,
+        offset: data_offset,
+        size: object.data.len() as u64,
+        align: 8,
+        ..SectionHeader::default()
+    });
+    sections.push(SectionHeader {
+        name: ".bss",
+        kind: SHT_NOBITS,
+        flags: SHF_ALLOC | SHF_WRITE,
+        offset: data_offset + object.data.len() as u64,
+        size: object.bss_size,
+        align: 8,
+        ..SectionHeader::default()
+    });
+
+    let mut undefined: Vec<&str> = Vec::new();
+    for relocation in &object.relocations {
+        if object.find_symbol(&relocation.symbol).is_none()
+            && !undefined.contains(&relocation.symbol.as_str())
+        {
+            undefined.push(&relocation.symbol);
+        }
+    }
+    let symbol_table = SymbolTable::new(object, [0; 3], &undefined);
+    append_symbol_table(&mut file, &mut sections, &symbol_table);
+
+    let mut relocations = Vec::new();
+    for relocation in &object.relocations {
+        let kind = match relocation.kind {
+            RelocationKind::Absolute64 => R_X86_64_64,
+            RelocationKind::PcRelative32 => R_X86_64_PC32,
+        };
+        let info = (symbol_table.indices[relocation.symbol.as_str()] as u64) << 32 | kind as u64;
+        relocations.extend(relocation.offset.to_le_bytes());
+        relocations.extend(info.to_le_bytes());
+        relocations.extend(relocation.addend.to_le_bytes());
+    }
+    let relocations_offset = append_aligned(&mut file, &relocations, 8);
+    sections.push(SectionHeader {
+        name: ".rela.text",
+        kind: SHT_RELA,
+        flags: SHF_INFO_LINK,
+        offset: relocations_offset,
+        size: relocations.len() as u64,
+        link: SYMTAB_INDEX,
+        info: TEXT_INDEX as u32,
+        align: 8,
+        entry_size: RELOCATION_SIZE,
+        ..SectionHeader::default()
+    });
+
+    let header = FileHeader {
+        file_type: ET_REL,
+        entry: 0,
+        program_header_count: 0,
+    };
+    finish_file(file, header, sections)
+}
+
+// Write an executable that runs the object on its own, starting at _start. Every relocation is
+// applied here, so every symbol they refer to has to be defined in the object. .text is mapped
+// read and execute, and .data and .bss are mapped read and write after it
+pub fn write_executable(object: &Object) -> Vec<u8> {
+    let has_data = !object.data.is_empty() || object.bss_size != 0;
+    let program_header_count = if has_data { 2 } else { 1 };
+
+    let text_offset = PAGE_SIZE;
+    let text_address = BASE_ADDRESS + text_offset;
+    let data_offset = align_up(text_offset + object.text.len() as u64, PAGE_SIZE);
+    let data_address = BASE_ADDRESS + data_offset;
+    let bss_address = data_address + align_up(object.data.len() as u64, 8);
+    let addresses = [text_address, data_address, bss_address];
+
+    let mut text = object.text.clone();
+    for relocation in &object.relocations {
+        let symbol = object
+            .find_symbol(&relocation.symbol)
+            .unwrap_or_else(|| panic!("Relocation against undefined symbol {}", relocation.symbol));
+        let target = symbol_address(symbol, &addresses).wrapping_add_signed(relocation.addend);
+        let position = relocation.offset as usize;
+        match relocation.kind {
+            RelocationKind::Absolute64 => {
+                text[position..position + 8].copy_from_slice(&target.to_le_bytes());
+            }
+            RelocationKind::PcRelative32 => {
+                let place = text_address + relocation.offset;
+                let offset = i32::try_from(target.wrapping_sub(place) as i64)
+                    .expect("PC relative relocations should fit in 32 bits");
+                text[position..position + 4].copy_from_slice(&offset.to_le_bytes());
+            }
+        }
+    }
+
+    let mut file = vec![0; (HEADER_SIZE + PROGRAM_HEADER_SIZE * program_header_count) as usize];
+    file.resize(text_offset as usize, 0);
+    file.extend(&text);
+    let mut program_headers = vec![ProgramHeader {
+        flags: PF_R | PF_X,
+        offset: text_offset,
+        address: text_address,
+        file_size: text.len() as u64,
+        memory_size: text.len() as u64,
+    }];
+    let mut sections = vec![
+        SectionHeader::default(),
+        SectionHeader {
+            name: ".text",
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_EXECINSTR,
+            address: text_address,
+            offset: text_offset,
+            size: text.len() as u64,
+            align: 16,
+            ..SectionHeader::default()
+        },
+    ];
+
+    if has_data {
+        file.resize(data_offset as usize, 0);
+        file.extend(&object.data);
+        program_headers.push(ProgramHeader {
+            flags: PF_R | PF_W,
+            offset: data_offset,
+            address: data_address,
+            file_size: object.data.len() as u64,
+            memory_size: bss_address - data_address + object.bss_size,
+        });
+    }
+    sections.push(SectionHeader {
+        name: ".data",
+        kind: SHT_PROGBITS,
+        flags: SHF_ALLOC | SHF_WRITE,
+        address: data_address,
+        offset: data_offset.min(file.len() as u64),
+        size: object.data.len() as u64,
+        align: 8,
+        ..SectionHeader::default()
+    });
+    sections.push(SectionHeader {
+        name: ".bss",
+        kind: SHT_NOBITS,
+        flags: SHF_ALLOC | SHF_WRITE,
+        address: bss_address,
+        offset: file.len() as u64,
+        size: object.bss_size,
+        align: 8,
+        ..SectionHeader::default()
+    });
+
+    let symbol_table = SymbolTable::new(object, addresses, &[]);
+    append_symbol_table(&mut file, &mut sections, &symbol_table);
+
+    let mut offset = HEADER_SIZE as usize;
+    for program_header in &program_headers {
+        program_header.write(&mut file[offset..offset + PROGRAM_HEADER_SIZE as usize]);
+        offset += PROGRAM_HEADER_SIZE as usize;
+    }
+
+    let entry = object
+        .find_symbol(ENTRY_SYMBOL)
+        .unwrap_or_else(|| panic!("The program has no {} symbol", ENTRY_SYMBOL));
+    let header = FileHeader {
+        file_type: ET_EXEC,
+        entry: symbol_address(entry, &addresses),
+        program_header_count: program_header_count as u16,
+    };
+    finish_file(file, header, sections)
+}
+
+fn symbol_address(symbol: &ObjectSymbol, addresses: &[u64; 3]) -> u64 {
+    let base = match symbol.section {
+        Section::Text => addresses[0],
+        Section::Data => addresses[1],
+        Section::Bss => addresses[2],
+    };
+    base + symbol.offset
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+// Pad the file to the alignment and append the bytes, returning where they start
+fn append_aligned(file: &mut Vec<u8>, bytes: &[u8], alignment: u64) -> u64 {
+    let offset = align_up(file.len() as u64, alignment);
+    file.resize(offset as usize, 0);
+    file.extend(bytes);
+    offset
+}
+
+#[derive(Default)]
+struct SectionHeader {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+struct ProgramHeader {
+    flags: u32,
+    offset: u64,
+    address: u64,
+    file_size: u64,
+    memory_size: u64,
+}
+
+struct FileHeader {
+    file_type: u16,
+    entry: u64,
+    program_header_count: u16,
+}
+
+impl ProgramHeader {
+    fn write(&self, bytes: &mut [u8]) {
+        let mut fields = Vec::with_capacity(PROGRAM_HEADER_SIZE as usize);
+        fields.extend(PT_LOAD.to_le_bytes());
+        fields.extend(self.flags.to_le_bytes());
+        fields.extend(self.offset.to_le_bytes());
+        fields.extend(self.address.to_le_bytes());
+        // The physical address, which Linux ignores
+        fields.extend(self.address.to_le_bytes());
+        fields.extend(self.file_size.to_le_bytes());
+        fields.extend(self.memory_size.to_le_bytes());
+        fields.extend(PAGE_SIZE.to_le_bytes());
+        bytes.copy_from_slice(&fields);
+    }
+}
+
+// The symbol table with its string table. Local symbols have to come before global ones, and
+// index 0 is the null symbol
+struct SymbolTable<'a> {
+    symbols: Vec<u8>,
+    strings: Vec<u8>,
+    indices: HashMap<&'a str, u32>,
+    first_global: u32,
+}
+
+impl<'a> SymbolTable<'a> {
+    // Symbols are given the address of their section plus their offset, which is 0 for objects
+    fn new(object: &'a Object, addresses: [u64; 3], undefined: &[&'a str]) -> Self {
+        let mut table = SymbolTable {
+            symbols: vec![0; SYMBOL_SIZE as usize],
+            strings: vec![0],
+            indices: HashMap::new(),
+            first_global: 0,
+        };
+        for global in [false, true] {
+            if global {
+                table.first_global = table.indices.len() as u32 + 1;
+            }
+            for symbol in object
+                .symbols
+                .iter()
+                .filter(|symbol| symbol.global == global)
+            {
+                let binding = if global { STB_GLOBAL } else { STB_LOCAL };
+                let section = match symbol.section {
+                    Section::Text => TEXT_INDEX,
+                    Section::Data => DATA_INDEX,
+                    Section::Bss => BSS_INDEX,
+                };
+                table.add(
+                    &symbol.name,
+                    binding,
+                    section,
+                    symbol_address(symbol, &addresses),
+                );
+            }
+        }
+        for name in undefined {
+            table.add(name, STB_GLOBAL, 0, 0);
+        }
+        table
+    }
+
+    fn add(&mut self, name: &'a str, binding: u8, section: u16, value: u64) {
+        let index = (self.symbols.len() as u64 / SYMBOL_SIZE) as u32;
+        if self.indices.insert(name, index).is_some() {
+            panic!("Symbol {} is defined more than once", name);
+        }
+        self.symbols
+            .extend((self.strings.len() as u32).to_le_bytes());
+        self.strings.extend(name.as_bytes());
+        self.strings.push(0);
+        // The symbol's binding goes in the top 4 bits and its type, which is left unspecified,
+        // in the bottom 4
+        self.symbols.push(binding << 4);
+        self.symbols.push(0);
+        self.symbols.extend(section.to_le_bytes());
+        self.symbols.extend(value.to_le_bytes());
+        // The symbol's size isn't known
+        self.symbols.extend(0u64.to_le_bytes());
+    }
+}
+
+fn append_symbol_table(file: &mut Vec<u8>, sections: &mut Vec<SectionHeader>, table: &SymbolTable) {
+    let symbols_offset = append_aligned(file, &table.symbols, 8);
+    sections.push(SectionHeader {
+        name: ".symtab",
+        kind: SHT_SYMTAB,
+        offset: symbols_offset,
+        size: table.symbols.len() as u64,
+        link: STRTAB_INDEX,
+        info: table.first_global,
+        align: 8,
+        entry_size: SYMBOL_SIZE,
+        ..SectionHeader::default()
+    });
+    let strings_offset = append_aligned(file, &table.strings, 1);
+    sections.push(SectionHeader {
+        name: ".strtab",
+        kind: SHT_STRTAB,
+        offset: strings_offset,
+        size: table.strings.len() as u64,
+        align: 1,
+        ..SectionHeader::default()
+    });
+}
+
+// Add the section name table and the section headers to the end of the file, then fill in the
+// file header at its start
+fn finish_file(mut file: Vec<u8>, header: FileHeader, mut sections: Vec<SectionHeader>) -> Vec<u8> {
+    sections.push(SectionHeader {
+        name: ".shstrtab",
+        kind: SHT_STRTAB,
+        align: 1,
+        ..SectionHeader::default()
+    });
+    let mut names = vec![0];
+    let mut name_offsets = Vec::new();
+    for section in &sections {
+        if section.name.is_empty() {
+            name_offsets.push(0u32);
+        } else {
+            name_offsets.push(names.len() as u32);
+            names.extend(section.name.as_bytes());
+            names.push(0);
+        }
+    }
+    let names_offset = append_aligned(&mut file, &names, 1);
+    let names_section = sections.last_mut().expect("The name table was just added");
+    names_section.offset = names_offset;
+    names_section.size = names.len() as u64;
+
+    let section_headers_offset = align_up(file.len() as u64, 8);
+    file.resize(section_headers_offset as usize, 0);
+    for (section, name_offset) in sections.iter().zip(name_offsets) {
+        file.extend(name_offset.to_le_bytes());
+        file.extend(section.kind.to_le_bytes());
+        file.extend(section.flags.to_le_bytes());
+        file.extend(section.address.to_le_bytes());
+        file.extend(section.offset.to_le_bytes());
+        file.extend(section.size.to_le_bytes());
+        file.extend(section.link.to_le_bytes());
+        file.extend(section.info.to_le_bytes());
+        file.extend(section.align.to_le_bytes());
+        file.extend(section.entry_size.to_le_bytes());
+    }
+
+    let program_header_offset = if header.program_header_count == 0 {
+        0
+    } else {
+        HEADER_SIZE
+    };
+    let mut bytes = Vec::with_capacity(HEADER_SIZE as usize);
+    // Magic number, 64 bit, little endian, ELF version 1, System V ABI, then padding
+    bytes.extend([0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    bytes.extend([0; 8]);
+    bytes.extend(header.file_type.to_le_bytes());
+    bytes.extend(EM_X86_64.to_le_bytes());
+    bytes.extend(1u32.to_le_bytes());
+    bytes.extend(header.entry.to_le_bytes());
+    bytes.extend(program_header_offset.to_le_bytes());
+    bytes.extend(section_headers_offset.to_le_bytes());
+    bytes.extend(0u32.to_le_bytes());
+    bytes.extend((HEADER_SIZE as u16).to_le_bytes());
+    let program_header_size = if header.program_header_count == 0 {
+        0
+    } else {
+        PROGRAM_HEADER_SIZE
+    };
+    bytes.extend((program_header_size as u16).to_le_bytes());
+    bytes.extend(header.program_header_count.to_le_bytes());
+    bytes.extend((SECTION_HEADER_SIZE as u16).to_le_bytes());
+    bytes.extend((sections.len() as u16).to_le_bytes());
+    bytes.extend((sections.len() as u16 - 1).to_le_bytes());
+    file[..HEADER_SIZE as usize].copy_from_slice(&bytes);
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    // mov rax, <address of value>; mov rax, [rax]; jmp <end>; end:
+    fn object_with_relocations() -> Object {
+        let mut text = vec![
+            0x48, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0x48, 0x8b, 0x00, 0xe9, 0, 0, 0, 0,
+        ];
+        text.extend([0x0f, 0x05]);
+        Object {
+            text,
+            data: 42u64.to_le_bytes().to_vec(),
+            bss_size: 16,
+            symbols: vec![
+                ObjectSymbol {
+                    name: ENTRY_SYMBOL.to_string(),
+                    section: Section::Text,
+                    offset: 0,
+                    global: true,
+                },
+                ObjectSymbol {
+                    name: "end".to_string(),
+                    section: Section::Text,
+                    offset: 18,
+                    global: false,
+                },
+                ObjectSymbol {
+                    name: "value".to_string(),
+                    section: Section::Data,
+                    offset: 0,
+                    global: false,
+                },
+            ],
+            relocations: vec![
+                Relocation {
+                    offset: 2,
+                    symbol: "value".to_string(),
+                    kind: RelocationKind::Absolute64,
+                    addend: 0,
+                },
+                Relocation {
+                    offset: 14,
+                    symbol: "end".to_string(),
+                    kind: RelocationKind::PcRelative32,
+                    addend: -4,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn writes_relocatable_objects() {
+        let mut object = object_with_relocations();
+        object.relocations.push(Relocation {
+            offset: 2,
+            symbol: "elsewhere".to_string(),
+            kind: RelocationKind::Absolute64,
+            addend: 8,
+        });
+        let file = write_object(&object);
+        assert_eq!(&file[..4], b"\x7fELF");
+        assert_eq!(read_u16(&file, 16), ET_REL);
+        assert_eq!(read_u16(&file, 18), EM_X86_64);
+        assert_eq!(read_u16(&file, 56), 0);
+        assert_eq!(read_u16(&file, 60), 8);
+        // The text comes straight after the header, untouched by its relocations
+        assert_eq!(&file[64..64 + object.text.len()], object.text.as_slice());
+
+        let section_headers = read_u64(&file, 40) as usize;
+        let rela = section_headers + 6 * SECTION_HEADER_SIZE as usize;
+        let rela_offset = read_u64(&file, rela + 24) as usize;
+        assert_eq!(read_u64(&file, rela + 32), 3 * RELOCATION_SIZE);
+        // Locals come first, so end and value are symbols 1 and 2, then _start, then the
+        // undefined symbol
+        let entries: Vec<(u64, u64, i64)> = (0..3)
+            .map(|index| {
+                let entry = rela_offset + index * RELOCATION_SIZE as usize;
+                let addend = read_u64(&file, entry + 16) as i64;
+                (read_u64(&file, entry), read_u64(&file, entry + 8), addend)
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (2, 2 << 32 | R_X86_64_64 as u64, 0),
+                (14, 1 << 32 | R_X86_64_PC32 as u64, -4),
+                (2, 4 << 32 | R_X86_64_64 as u64, 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_relocations_in_executables() {
+        let object = object_with_relocations();
+        let file = write_executable(&object);
+        assert_eq!(read_u16(&file, 16), ET_EXEC);
+        assert_eq!(read_u64(&file, 24), 0x401000);
+        assert_eq!(read_u16(&file, 56), 2);
+
+        let text = &file[0x1000..0x1000 + object.text.len()];
+        assert_eq!(read_u64(text, 2), 0x402000);
+        assert_eq!(&text[14..18], &[0, 0, 0, 0]);
+        assert_eq!(&file[0x2000..0x2008], &42u64.to_le_bytes());
+
+        // The data segment covers .data and the .bss after it
+        let data_segment = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as usize;
+        assert_eq!(read_u64(&file, data_segment + 16), 0x402000);
+        assert_eq!(read_u64(&file, data_segment + 32), 8);
+        assert_eq!(read_u64(&file, data_segment + 40), 24);
+    }
+
+    #[test]
+    #[should_panic(expected = "Relocation against undefined symbol elsewhere")]
+    fn rejects_undefined_symbols_in_executables() {
+        let mut object = object_with_relocations();
+        object.relocations[0].symbol = "elsewhere".to_string();
+        write_executable(&object);
+    }
+}
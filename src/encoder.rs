@@ -0,0 +1,570 @@
+use std::collections::HashMap;
+
+use crate::asm::{Condition, Instruction, Memory, Operand, Register};
+
+// Turns the backend's instructions into x86-64 machine code, choosing the same encodings NASM
+// does so the output can be compared against it. Jumps always use a 32 bit offset, which is
+// filled in once every label's position is known
+
+// Encode a whole program. Labels take up no space, and every jump must be to a label in the
+// program
+pub fn encode(instructions: &[Instruction]) -> Vec<u8> {
+    encode_with_labels(instructions).0
+}
+
+// Encode a program, also returning the position of each label in the order they appear so they
+// can be written to a symbol table
+pub fn encode_with_labels(instructions: &[Instruction]) -> (Vec<u8>, Vec<(String, usize)>) {
+    let mut encoder = Encoder::default();
+    for instruction in instructions {
+        encoder.encode_instruction(instruction);
+    }
+    encoder.resolve_fixups();
+    let mut labels: Vec<(String, usize)> = encoder.labels.into_iter().collect();
+    labels.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+    (encoder.code, labels)
+}
+
+#[derive(Default)]
+struct Encoder {
+    code: Vec<u8>,
+    labels: HashMap<String, usize>,
+    // The position of each jump offset still to be filled in, and the label it jumps to
+    fixups: Vec<(usize, String)>,
+}
+
+// The /digit in the ModRM reg field that picks the operation for the group 1 arithmetic opcodes
+// 81 and 83. The register forms of each operation are at 8 times its digit
+const ADD: u8 = 0;
+const AND: u8 = 4;
+const SUB: u8 = 5;
+const XOR: u8 = 6;
+const CMP: u8 = 7;
+
+fn register_number(register: Register) -> u8 {
+    match register {
+        Register::Rax => 0,
+        Register::Rcx => 1,
+        Register::Rdx => 2,
+        Register::Rbx => 3,
+        Register::Rsp => 4,
+        Register::Rbp => 5,
+        Register::Rsi => 6,
+        Register::Rdi => 7,
+        Register::R8 => 8,
+        Register::R9 => 9,
+        Register::R10 => 10,
+        Register::R11 => 11,
+        Register::R12 => 12,
+        Register::R13 => 13,
+        Register::R14 => 14,
+        Register::R15 => 15,
+    }
+}
+
+fn condition_code(condition: Condition) -> u8 {
+    match condition {
+        Condition::E => 0x4,
+        Condition::Ne => 0x5,
+        Condition::L => 0xc,
+        Condition::Ge => 0xd,
+        Condition::Le => 0xe,
+        Condition::G => 0xf,
+    }
+}
+
+fn immediate_32(value: i64) -> i32 {
+    i32::try_from(value).unwrap_or_else(|_| panic!("Immediate {} doesn't fit in 32 bits", value))
+}
+
+impl Encoder {
+    fn encode_instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Label(label) => {
+                if self.labels.insert(label.clone(), self.code.len()).is_some() {
+                    panic!("Label {} is defined more than once", label);
+                }
+            }
+            Instruction::Mov(dest, src) => self.encode_mov(dest, src),
+            Instruction::Movzx(dest) => self.encode_modrm(
+                true,
+                &[0x0f, 0xb6],
+                register_number(*dest),
+                &Operand::Register(Register::Rax),
+            ),
+            Instruction::Lea(dest, memory) => self.encode_modrm(
+                true,
+                &[0x8d],
+                register_number(*dest),
+                &Operand::Memory(*memory),
+            ),
+            Instruction::Add(dest, src) => self.encode_arithmetic(ADD, dest, src),
+            Instruction::Sub(dest, src) => self.encode_arithmetic(SUB, dest, src),
+            Instruction::Xor(dest, src) => self.encode_arithmetic(XOR, dest, src),
+            Instruction::And(dest, src) => self.encode_arithmetic(AND, dest, src),
+            Instruction::Cmp(left, right) => self.encode_arithmetic(CMP, left, right),
+            Instruction::Imul(dest, Operand::Immediate(value)) => {
+                let dest_number = register_number(*dest);
+                let dest = Operand::Register(*dest);
+                match i8::try_from(*value) {
+                    Ok(value) => {
+                        self.encode_modrm(true, &[0x6b], dest_number, &dest);
+                        self.code.push(value as u8);
+                    }
+                    Err(_) => {
+                        self.encode_modrm(true, &[0x69], dest_number, &dest);
+                        self.code.extend(immediate_32(*value).to_le_bytes());
+                    }
+                }
+            }
+            Instruction::Imul(dest, src) => {
+                self.encode_modrm(true, &[0x0f, 0xaf], register_number(*dest), src)
+            }
+            Instruction::WideImul(src) => self.encode_modrm(true, &[0xf7], 5, src),
+            Instruction::Idiv(divisor) => self.encode_modrm(true, &[0xf7], 7, divisor),
+            Instruction::Neg(operand) => self.encode_modrm(true, &[0xf7], 3, operand),
+            Instruction::Shl(dest, count) => self.encode_shift(4, dest, *count),
+            Instruction::Shr(dest, count) => self.encode_shift(5, dest, *count),
+            Instruction::Sar(dest, count) => self.encode_shift(7, dest, *count),
+            Instruction::Cqo => self.code.extend([0x48, 0x99]),
+            Instruction::Set(condition) => self.encode_modrm(
+                false,
+                &[0x0f, 0x90 + condition_code(*condition)],
+                0,
+                &Operand::Register(Register::Rax),
+            ),
+            Instruction::Jmp(label) => {
+                self.code.push(0xe9);
+                self.add_fixup(label);
+            }
+            Instruction::Jcc(condition, label) => {
+                self.code.extend([0x0f, 0x80 + condition_code(*condition)]);
+                self.add_fixup(label);
+            }
+            Instruction::Push(Operand::Register(register)) => {
+                self.encode_short_register(0x50, *register)
+            }
+            Instruction::Push(Operand::Immediate(value)) => match i8::try_from(*value) {
+                Ok(value) => self.code.extend([0x6a, value as u8]),
+                Err(_) => {
+                    self.code.push(0x68);
+                    self.code.extend(immediate_32(*value).to_le_bytes());
+                }
+            },
+            Instruction::Push(memory) => self.encode_modrm(false, &[0xff], 6, memory),
+            Instruction::Pop(Operand::Register(register)) => {
+                self.encode_short_register(0x58, *register)
+            }
+            Instruction::Pop(Operand::Immediate(_)) => panic!("Can't pop into an immediate"),
+            Instruction::Pop(memory) => self.encode_modrm(false, &[0x8f], 0, memory),
+            Instruction::Syscall => self.code.extend([0x0f, 0x05]),
+            Instruction::Ret => self.code.push(0xc3),
+        }
+    }
+
+    // Like NASM, moves of immediates use the shortest form that gives the right value: writing a
+    // 32 bit register clears the top half, so values that fit in an unsigned 32 bits don't need
+    // a REX prefix, and only values that don't fit in a sign extended 32 bits need all 64
+    fn encode_mov(&mut self, dest: &Operand, src: &Operand) {
+        match (dest, src) {
+            (_, Operand::Register(src)) => {
+                self.encode_modrm(true, &[0x89], register_number(*src), dest)
+            }
+            (Operand::Register(dest), Operand::Memory(_)) => {
+                self.encode_modrm(true, &[0x8b], register_number(*dest), src)
+            }
+            (Operand::Register(dest), Operand::Immediate(value)) => {
+                if let Ok(value) = u32::try_from(*value) {
+                    self.encode_short_register(0xb8, *dest);
+                    self.code.extend(value.to_le_bytes());
+                } else if let Ok(value) = i32::try_from(*value) {
+                    self.encode_modrm(true, &[0xc7], 0, &Operand::Register(*dest));
+                    self.code.extend(value.to_le_bytes());
+                } else {
+                    self.encode_rex(true, 0, None, register_number(*dest));
+                    self.code.push(0xb8 + (register_number(*dest) & 7));
+                    self.code.extend(value.to_le_bytes());
+                }
+            }
+            (Operand::Memory(_), Operand::Immediate(value)) => {
+                self.encode_modrm(true, &[0xc7], 0, dest);
+                self.code.extend(immediate_32(*value).to_le_bytes());
+            }
+            _ => panic!("Can't encode mov {}, {}", dest, src),
+        }
+    }
+
+    // add, sub, xor, and and cmp share their encodings, differing only in the opcode or /digit.
+    // Immediates that fit in a byte are sign extended from 8 bits, and NASM uses the shorter
+    // accumulator form for larger immediates into rax
+    fn encode_arithmetic(&mut self, digit: u8, dest: &Operand, src: &Operand) {
+        match (dest, src) {
+            (_, Operand::Register(src)) => {
+                self.encode_modrm(true, &[digit * 8 + 1], register_number(*src), dest)
+            }
+            (Operand::Register(dest), Operand::Memory(_)) => {
+                self.encode_modrm(true, &[digit * 8 + 3], register_number(*dest), src)
+            }
+            (_, Operand::Immediate(value)) => match i8::try_from(*value) {
+                Ok(value) => {
+                    self.encode_modrm(true, &[0x83], digit, dest);
+                    self.code.push(value as u8);
+                }
+                Err(_) => {
+                    if *dest == Operand::Register(Register::Rax) {
+                        self.code.extend([0x48, digit * 8 + 5]);
+                    } else {
+                        self.encode_modrm(true, &[0x81], digit, dest);
+                    }
+                    self.code.extend(immediate_32(*value).to_le_bytes());
+                }
+            },
+            _ => panic!("Can't encode an operation on {} and {}", dest, src),
+        }
+    }
+
+    // Shifts by one have their own opcode without an immediate
+    fn encode_shift(&mut self, digit: u8, dest: &Operand, count: u8) {
+        if count == 1 {
+            self.encode_modrm(true, &[0xd1], digit, dest);
+        } else {
+            self.encode_modrm(true, &[0xc1], digit, dest);
+            self.code.push(count);
+        }
+    }
+
+    // Opcodes like push and pop that add the register number to the opcode itself
+    fn encode_short_register(&mut self, opcode: u8, register: Register) {
+        self.encode_rex(false, 0, None, register_number(register));
+        self.code.push(opcode + (register_number(register) & 7));
+    }
+
+    // A REX prefix carries the 64 bit operand size flag and the top bit of each register number.
+    // It is left out when none of those are needed
+    fn encode_rex(&mut self, wide: bool, reg: u8, index: Option<u8>, base: u8) {
+        let rex =
+            0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (index.unwrap_or(0) >> 3) << 1 | base >> 3;
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    // Encode an instruction that takes a register or /digit in the ModRM reg field and a
+    // register or memory operand in the rm field
+    fn encode_modrm(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: &Operand) {
+        match rm {
+            Operand::Register(register) => {
+                let rm = register_number(*register);
+                self.encode_rex(wide, reg, None, rm);
+                self.code.extend(opcode);
+                self.code.push(0xc0 | (reg & 7) << 3 | rm & 7);
+            }
+            Operand::Memory(memory) => self.encode_memory(wide, opcode, reg, memory),
+            Operand::Immediate(value) => {
+                panic!("Expected a register or memory but found {}", value)
+            }
+        }
+    }
+
+    // rsp and r12 as a base can only be encoded with a SIB byte, and rbp and r13 as a base can
+    // only be encoded with a displacement, so they get a zero byte displacement if they have none
+    fn encode_memory(&mut self, wide: bool, opcode: &[u8], reg: u8, memory: &Memory) {
+        let base = register_number(memory.base);
+        let index = memory.index.map(|(index, scale)| {
+            if index == Register::Rsp {
+                panic!("rsp can't be used as an index");
+            }
+            let scale_bits = match scale {
+                1 => 0,
+                2 => 1,
+                4 => 2,
+                8 => 3,
+                _ => panic!("Invalid scale {}", scale),
+            };
+            (register_number(index), scale_bits)
+        });
+        self.encode_rex(wide, reg, index.map(|(index, _)| index), base);
+        self.code.extend(opcode);
+
+        let (mode, displacement) = match (memory.displacement, i8::try_from(memory.displacement)) {
+            (0, _) if base & 7 != 5 => (0b00, vec![]),
+            (_, Ok(displacement)) => (0b01, vec![displacement as u8]),
+            (displacement, Err(_)) => (0b10, displacement.to_le_bytes().to_vec()),
+        };
+        match index {
+            Some((index, scale_bits)) => {
+                self.code.push(mode << 6 | (reg & 7) << 3 | 0b100);
+                self.code
+                    .push(scale_bits << 6 | (index & 7) << 3 | base & 7);
+            }
+            None if base & 7 == 4 => {
+                self.code.push(mode << 6 | (reg & 7) << 3 | 0b100);
+                self.code.push(0b00_100_100);
+            }
+            None => self.code.push(mode << 6 | (reg & 7) << 3 | base & 7),
+        }
+        self.code.extend(displacement);
+    }
+
+    fn add_fixup(&mut self, label: &str) {
+        self.fixups.push((self.code.len(), label.to_string()));
+        self.code.extend([0; 4]);
+    }
+
+    // Jump offsets are relative to the end of the jump, which is where the offset ends
+    fn resolve_fixups(&mut self) {
+        for (position, label) in &self.fixups {
+            let target = *self
+                .labels
+                .get(label)
+                .unwrap_or_else(|| panic!("Jump to undefined label {}", label));
+            let offset = target as i64 - (*position as i64 + 4);
+            let offset = i32::try_from(offset).expect("Jumps should fit in 32 bits");
+            self.code[*position..*position + 4].copy_from_slice(&offset.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(register: Register) -> Operand {
+        Operand::Register(register)
+    }
+
+    fn memory(base: Register, index: Option<(Register, u8)>, displacement: i32) -> Operand {
+        Operand::Memory(Memory {
+            base,
+            index,
+            displacement,
+        })
+    }
+
+    fn assert_encodes(instruction: Instruction, expected: &[u8]) {
+        let code = encode(std::slice::from_ref(&instruction));
+        assert_eq!(code, expected, "{}", instruction);
+    }
+
+    #[test]
+    fn encodes_moves() {
+        use Register::*;
+        assert_encodes(
+            Instruction::Mov(register(Rax), register(Rbx)),
+            &[0x48, 0x89, 0xd8],
+        );
+        assert_encodes(
+            Instruction::Mov(register(R8), register(Rdi)),
+            &[0x49, 0x89, 0xf8],
+        );
+        assert_encodes(
+            Instruction::Mov(register(Rsi), register(R11)),
+            &[0x4c, 0x89, 0xde],
+        );
+        assert_encodes(
+            Instruction::Mov(register(Rax), Operand::Immediate(60)),
+            &[0xb8, 0x3c, 0, 0, 0],
+        );
+        assert_encodes(
+            Instruction::Mov(register(R9), Operand::Immediate(1)),
+            &[0x41, 0xb9, 1, 0, 0, 0],
+        );
+        assert_encodes(
+            Instruction::Mov(register(Rcx), Operand::Immediate(-2)),
+            &[0x48, 0xc7, 0xc1, 0xfe, 0xff, 0xff, 0xff],
+        );
+        assert_encodes(
+            Instruction::Mov(register(Rdx), Operand::Immediate(0x1_0000_0000)),
+            &[0x48, 0xba, 0, 0, 0, 0, 1, 0, 0, 0],
+        );
+        assert_encodes(
+            Instruction::Mov(register(Rax), memory(Rbp, None, -8)),
+            &[0x48, 0x8b, 0x45, 0xf8],
+        );
+        assert_encodes(
+            Instruction::Mov(memory(Rbp, None, -256), register(R10)),
+            &[0x4c, 0x89, 0x95, 0x00, 0xff, 0xff, 0xff],
+        );
+        assert_encodes(
+            Instruction::Mov(memory(Rbp, None, -16), Operand::Immediate(7)),
+            &[0x48, 0xc7, 0x45, 0xf0, 7, 0, 0, 0],
+        );
+        assert_encodes(Instruction::Movzx(Rax), &[0x48, 0x0f, 0xb6, 0xc0]);
+        assert_encodes(Instruction::Movzx(R8), &[0x4c, 0x0f, 0xb6, 0xc0]);
+    }
+
+    #[test]
+    fn encodes_memory_operands() {
+        use Register::*;
+        assert_encodes(
+            Instruction::Mov(register(Rax), memory(Rax, None, 0)),
+            &[0x48, 0x8b, 0x00],
+        );
+        assert_encodes(
+            Instruction::Mov(register(Rax), memory(Rsp, None, 0)),
+            &[0x48, 0x8b, 0x04, 0x24],
+        );
+        assert_encodes(
+            Instruction::Mov(register(Rax), memory(Rbp, None, 0)),
+            &[0x48, 0x8b, 0x45, 0x00],
+        );
+        assert_encodes(
+            Instruction::Mov(register(Rcx), memory(R12, None, 8)),
+            &[0x49, 0x8b, 0x4c, 0x24, 0x08],
+        );
+        assert_encodes(
+            Instruction::Mov(register(Rcx), memory(R13, None, 0)),
+            &[0x49, 0x8b, 0x4d, 0x00],
+        );
+        assert_encodes(
+            Instruction::Lea(
+                Rax,
+                Memory {
+                    base: Rcx,
+                    index: Some((Rcx, 2)),
+                    displacement: 0,
+                },
+            ),
+            &[0x48, 0x8d, 0x04, 0x49],
+        );
+        assert_encodes(
+            Instruction::Lea(
+                R9,
+                Memory {
+                    base: R9,
+                    index: Some((R9, 8)),
+                    displacement: 0,
+                },
+            ),
+            &[0x4f, 0x8d, 0x0c, 0xc9],
+        );
+        assert_encodes(
+            Instruction::Lea(
+                Rsi,
+                Memory {
+                    base: Rbp,
+                    index: None,
+                    displacement: -24,
+                },
+            ),
+            &[0x48, 0x8d, 0x75, 0xe8],
+        );
+    }
+
+    #[test]
+    fn encodes_arithmetic() {
+        use Register::*;
+        assert_encodes(
+            Instruction::Add(register(Rax), register(Rcx)),
+            &[0x48, 0x01, 0xc8],
+        );
+        assert_encodes(
+            Instruction::Add(register(R8), Operand::Immediate(1)),
+            &[0x49, 0x83, 0xc0, 0x01],
+        );
+        assert_encodes(
+            Instruction::Add(register(Rax), Operand::Immediate(1000)),
+            &[0x48, 0x05, 0xe8, 0x03, 0, 0],
+        );
+        assert_encodes(
+            Instruction::Sub(register(Rsp), Operand::Immediate(1000)),
+            &[0x48, 0x81, 0xec, 0xe8, 0x03, 0, 0],
+        );
+        assert_encodes(
+            Instruction::Sub(register(Rax), memory(Rbp, None, -8)),
+            &[0x48, 0x2b, 0x45, 0xf8],
+        );
+        assert_encodes(
+            Instruction::Xor(register(Rax), register(Rax)),
+            &[0x48, 0x31, 0xc0],
+        );
+        assert_encodes(
+            Instruction::And(register(Rax), Operand::Immediate(7)),
+            &[0x48, 0x83, 0xe0, 0x07],
+        );
+        assert_encodes(
+            Instruction::Cmp(register(Rdi), register(Rsi)),
+            &[0x48, 0x39, 0xf7],
+        );
+        assert_encodes(
+            Instruction::Cmp(memory(Rbp, None, -8), Operand::Immediate(-1)),
+            &[0x48, 0x83, 0x7d, 0xf8, 0xff],
+        );
+        assert_encodes(
+            Instruction::Imul(Rax, register(Rcx)),
+            &[0x48, 0x0f, 0xaf, 0xc1],
+        );
+        assert_encodes(
+            Instruction::Imul(R10, register(R11)),
+            &[0x4d, 0x0f, 0xaf, 0xd3],
+        );
+        assert_encodes(
+            Instruction::Imul(Rax, Operand::Immediate(10)),
+            &[0x48, 0x6b, 0xc0, 0x0a],
+        );
+        assert_encodes(Instruction::WideImul(register(Rcx)), &[0x48, 0xf7, 0xe9]);
+        assert_encodes(Instruction::Cqo, &[0x48, 0x99]);
+        assert_encodes(Instruction::Idiv(register(Rcx)), &[0x48, 0xf7, 0xf9]);
+        assert_encodes(
+            Instruction::Idiv(memory(Rbp, None, -8)),
+            &[0x48, 0xf7, 0x7d, 0xf8],
+        );
+        assert_encodes(Instruction::Neg(register(Rdx)), &[0x48, 0xf7, 0xda]);
+        assert_encodes(
+            Instruction::Shl(register(Rax), 3),
+            &[0x48, 0xc1, 0xe0, 0x03],
+        );
+        assert_encodes(
+            Instruction::Sar(register(Rcx), 63),
+            &[0x48, 0xc1, 0xf9, 0x3f],
+        );
+        assert_encodes(Instruction::Shr(register(Rax), 1), &[0x48, 0xd1, 0xe8]);
+    }
+
+    #[test]
+    fn encodes_stack_and_system_instructions() {
+        use Register::*;
+        assert_encodes(Instruction::Push(register(Rbp)), &[0x55]);
+        assert_encodes(Instruction::Push(register(R12)), &[0x41, 0x54]);
+        assert_encodes(Instruction::Push(Operand::Immediate(5)), &[0x6a, 0x05]);
+        assert_encodes(
+            Instruction::Push(Operand::Immediate(500)),
+            &[0x68, 0xf4, 0x01, 0, 0],
+        );
+        assert_encodes(
+            Instruction::Push(memory(Rbp, None, -8)),
+            &[0xff, 0x75, 0xf8],
+        );
+        assert_encodes(Instruction::Pop(register(Rdi)), &[0x5f]);
+        assert_encodes(Instruction::Pop(register(R15)), &[0x41, 0x5f]);
+        assert_encodes(Instruction::Set(Condition::L), &[0x0f, 0x9c, 0xc0]);
+        assert_encodes(Instruction::Set(Condition::Ne), &[0x0f, 0x95, 0xc0]);
+        assert_encodes(Instruction::Syscall, &[0x0f, 0x05]);
+        assert_encodes(Instruction::Ret, &[0xc3]);
+    }
+
+    #[test]
+    fn resolves_jumps_to_labels() {
+        let code = encode(&[
+            Instruction::Label("top".to_string()),
+            Instruction::Jcc(Condition::Ge, "end".to_string()),
+            Instruction::Jmp("top".to_string()),
+            Instruction::Label("end".to_string()),
+            Instruction::Syscall,
+        ]);
+        assert_eq!(
+            code,
+            [
+                0x0f, 0x8d, 0x05, 0, 0, 0, // jge end
+                0xe9, 0xf5, 0xff, 0xff, 0xff, // jmp top
+                0x0f, 0x05, // syscall
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Jump to undefined label missing")]
+    fn rejects_jumps_to_undefined_labels() {
+        encode(&[Instruction::Jmp("missing".to_string())]);
+    }
+}
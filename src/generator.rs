@@ -0,0 +1,430 @@
+use std::fmt::Write;
+
+use crate::ast_printer::type_name;
+use crate::representations::Type;
+
+// Generates random programs to stress the front end and backend with. Programs use every
+// construct the parser accepts and are always well typed, so they are never rejected, and they
+// always finish: every loop counts up to a small bound and no divisor can be zero. The same seed
+// and size always give the same program
+
+// How deeply ifs, whiles and blocks are nested
+const MAX_DEPTH: usize = 4;
+// Loops inside loops multiply how long a program runs
+const MAX_LOOP_DEPTH: usize = 2;
+const MAX_LOOP_COUNT: u64 = 8;
+const MAX_EXPRESSION_DEPTH: usize = 3;
+const MAX_ARRAY_LENGTH: u64 = 4;
+
+// Generate a program of about size statements, ending with the declaration of the exit value
+pub fn generate(seed: u64, size: usize) -> String {
+    let mut generator = Generator {
+        rng: Rng::new(seed),
+        scopes: vec![Vec::new()],
+        statements_left: size,
+        next_id: 0,
+        loop_depth: 0,
+        output: String::new(),
+    };
+    while generator.statements_left > 0 {
+        generator.statement(0);
+    }
+    let exit = generator.int_expression(MAX_EXPRESSION_DEPTH);
+    generator.line(0, &format!("const int exit = {};", exit));
+    generator.output
+}
+
+// SplitMix64, which is small and good enough to pick program shapes with
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // A number from 0 up to but not including bound
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+
+    fn choose<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len() as u64) as usize]
+    }
+}
+
+struct Variable {
+    name: String,
+    _type: Type,
+    mutable: bool,
+    // Loop counters are never assigned by the loop body, so that every loop finishes
+    counter: bool,
+    // How many blocks the variable is declared inside
+    scope: usize,
+}
+
+struct Generator {
+    rng: Rng,
+    // The variables declared in each enclosing block, outermost first
+    scopes: Vec<Vec<Variable>>,
+    statements_left: usize,
+    next_id: usize,
+    loop_depth: usize,
+    output: String,
+}
+
+impl Generator {
+    fn line(&mut self, depth: usize, text: &str) {
+        writeln!(self.output, "{:indent$}{}", "", text, indent = depth * 4)
+            .expect("Writing to a string can't fail");
+    }
+
+    fn visible(&self) -> impl Iterator<Item = &Variable> {
+        self.scopes.iter().flatten()
+    }
+
+    fn names_of_type(&self, _type: &Type) -> Vec<String> {
+        self.visible()
+            .filter(|variable| &variable._type == _type)
+            .map(|variable| variable.name.clone())
+            .collect()
+    }
+
+    fn declare(&mut self, _type: Type, mutable: bool, counter: bool) -> String {
+        let name = format!("v{}", self.next_id);
+        self.next_id += 1;
+        let scope = self.scopes.len() - 1;
+        self.scopes[scope].push(Variable {
+            name: name.clone(),
+            _type,
+            mutable,
+            counter,
+            scope,
+        });
+        name
+    }
+
+    fn statement(&mut self, depth: usize) {
+        self.statements_left = self.statements_left.saturating_sub(1);
+        let roll = self.rng.below(100);
+        if roll < 30 {
+            self.declaration(depth);
+        } else if roll < 55 {
+            self.assignment(depth);
+        } else if roll < 65 && depth < MAX_DEPTH {
+            self.if_statement(depth);
+        } else if roll < 75 && depth < MAX_DEPTH && self.loop_depth < MAX_LOOP_DEPTH {
+            self.while_statement(depth);
+        } else if roll < 85 && self.loop_depth > 0 {
+            let condition = self.bool_expression(MAX_EXPRESSION_DEPTH);
+            self.line(depth, &format!("if {} {{", condition));
+            self.line(depth + 1, "break;");
+            self.line(depth, "}");
+        } else if roll < 90 && depth < MAX_DEPTH {
+            self.line(depth, "{");
+            self.block(depth + 1);
+            self.line(depth, "}");
+        } else {
+            self.declaration(depth);
+        }
+    }
+
+    // The statements inside a pair of braces, which always has at least one statement
+    fn block(&mut self, depth: usize) {
+        self.scopes.push(Vec::new());
+        let count = 1 + self.rng.below(4);
+        for _ in 0..count {
+            self.statement(depth);
+            if self.statements_left == 0 {
+                break;
+            }
+        }
+        self.scopes.pop();
+    }
+
+    fn declaration(&mut self, depth: usize) {
+        let element_type = self.scalar_type();
+        let _type = match self.rng.below(10) {
+            0..=4 => element_type,
+            5..=7 => Type::Pointer(Box::new(element_type)),
+            _ => {
+                let length = 1 + self.rng.below(MAX_ARRAY_LENGTH);
+                Type::Pointer(Box::new(Type::Array(Box::new(element_type), length)))
+            }
+        };
+        // The variable is declared after its value is generated, so it can't refer to itself
+        let value = self.expression(&_type, self.scopes.len() - 1);
+        let mutable = self.rng.chance(50);
+        let declared_type = type_name(&_type);
+        let name = self.declare(_type, mutable, false);
+        let keyword = if mutable { "mut" } else { "const" };
+        self.line(
+            depth,
+            &format!("{} {} {} = {};", keyword, declared_type, name, value),
+        );
+    }
+
+    fn assignment(&mut self, depth: usize) {
+        let targets: Vec<(String, Type, usize)> = self
+            .visible()
+            .filter(|variable| variable.mutable && !variable.counter)
+            .map(|variable| {
+                (
+                    variable.name.clone(),
+                    variable._type.clone(),
+                    variable.scope,
+                )
+            })
+            .collect();
+        if targets.is_empty() {
+            return self.declaration(depth);
+        }
+        let (name, _type, scope) = self.rng.choose(&targets).clone();
+        let value = self.expression(&_type, scope);
+        self.line(depth, &format!("{} = {};", name, value));
+    }
+
+    fn if_statement(&mut self, depth: usize) {
+        let condition = self.bool_expression(MAX_EXPRESSION_DEPTH);
+        self.line(depth, &format!("if {} {{", condition));
+        self.block(depth + 1);
+        if self.rng.chance(50) {
+            self.line(depth, "} else {");
+            self.block(depth + 1);
+        }
+        self.line(depth, "}");
+    }
+
+    // Every loop counts up to a bound with a counter only it changes, either in its condition or
+    // by breaking out at the top of its body
+    fn while_statement(&mut self, depth: usize) {
+        self.statements_left = self.statements_left.saturating_sub(1);
+        let counter = self.declare(Type::Int, true, true);
+        let count = 1 + self.rng.below(MAX_LOOP_COUNT);
+        self.line(depth, &format!("mut int {} = 0;", counter));
+        self.loop_depth += 1;
+        if self.rng.chance(70) {
+            self.line(depth, &format!("while {} < {} {{", counter, count));
+        } else {
+            self.line(depth, "while true {");
+            self.line(depth + 1, &format!("if {} >= {} {{", counter, count));
+            self.line(depth + 2, "break;");
+            self.line(depth + 1, "}");
+        }
+        self.block(depth + 1);
+        self.line(depth + 1, &format!("{} = {} + 1;", counter, counter));
+        self.line(depth, "}");
+        self.loop_depth -= 1;
+    }
+
+    fn scalar_type(&mut self) -> Type {
+        if self.rng.chance(70) {
+            Type::Int
+        } else {
+            Type::Bool
+        }
+    }
+
+    // An expression of the given type for a variable declared in the given scope. Pointers only
+    // ever point to variables declared in the same scope as them or outside it, so that nothing
+    // is pointed to after the block declaring it has finished
+    fn expression(&mut self, _type: &Type, scope: usize) -> String {
+        match _type {
+            Type::Int => self.int_expression(MAX_EXPRESSION_DEPTH),
+            Type::Bool => self.bool_expression(MAX_EXPRESSION_DEPTH),
+            Type::Pointer(inner) => match inner.as_ref() {
+                Type::Array(element_type, length) => {
+                    self.array_expression(_type, element_type, *length)
+                }
+                _ => self.pointer_expression(_type, inner, scope),
+            },
+            Type::Array(_, _) | Type::None => panic!("No variable can have the type {:?}", _type),
+        }
+    }
+
+    fn pointer_expression(&mut self, _type: &Type, pointee_type: &Type, scope: usize) -> String {
+        let candidates: Vec<String> = self
+            .visible()
+            .filter(|variable| variable.scope <= scope)
+            .filter_map(|variable| {
+                if &variable._type == pointee_type {
+                    Some(format!("&{}", variable.name))
+                } else if &variable._type == _type {
+                    Some(variable.name.clone())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        if candidates.is_empty() {
+            // Nothing to point to yet, so make something
+            let value = self.expression(pointee_type, scope);
+            let depth = self.scopes.len() - 1;
+            let name = self.declare(pointee_type.clone(), false, false);
+            self.line(
+                depth,
+                &format!("const {} {} = {};", type_name(pointee_type), name, value),
+            );
+            return format!("&{}", name);
+        }
+        self.rng.choose(&candidates).clone()
+    }
+
+    // List literals can only hold literals, so negative numbers can't appear in them
+    fn array_expression(&mut self, _type: &Type, element_type: &Type, length: u64) -> String {
+        let arrays = self.names_of_type(_type);
+        if !arrays.is_empty() && self.rng.chance(30) {
+            return self.rng.choose(&arrays).clone();
+        }
+        let elements: Vec<String> = (0..length)
+            .map(|_| match element_type {
+                Type::Bool => self.bool_literal(),
+                _ => self.rng.below(100).to_string(),
+            })
+            .collect();
+        format!("[{}]", elements.join(", "))
+    }
+
+    fn int_expression(&mut self, depth: usize) -> String {
+        if depth == 0 || self.rng.chance(30) {
+            return self.int_leaf();
+        }
+        let left = self.int_expression(depth - 1);
+        match self.rng.below(7) {
+            0 => format!("-{}", left),
+            1 => format!("({} + {})", left, self.int_expression(depth - 1)),
+            2 => format!("({} - {})", left, self.int_expression(depth - 1)),
+            3 => format!("({} * {})", left, self.int_expression(depth - 1)),
+            operator => {
+                let operator = if operator % 2 == 0 { "/" } else { "%" };
+                format!("({} {} {})", left, operator, self.divisor(depth - 1))
+            }
+        }
+    }
+
+    // Something that can never be zero or -1, so dividing by it never traps
+    fn divisor(&mut self, depth: usize) -> String {
+        if self.rng.chance(50) {
+            (1 + self.rng.below(9)).to_string()
+        } else {
+            format!("(({} % 7) + 8)", self.int_expression(depth))
+        }
+    }
+
+    fn int_leaf(&mut self) -> String {
+        let variables = self.names_of_type(&Type::Int);
+        let pointers = self.names_of_type(&Type::Pointer(Box::new(Type::Int)));
+        match self.rng.below(10) {
+            0..=3 if !variables.is_empty() => self.rng.choose(&variables).clone(),
+            4..=5 if !pointers.is_empty() => format!("*{}", self.rng.choose(&pointers)),
+            6 => self.rng.below(1_000_000).to_string(),
+            _ => self.rng.below(20).to_string(),
+        }
+    }
+
+    fn bool_expression(&mut self, depth: usize) -> String {
+        if depth == 0 || self.rng.chance(30) {
+            return self.bool_leaf();
+        }
+        if self.rng.chance(70) {
+            let operator = *self.rng.choose(&["<", ">", "<=", ">=", "==", "!="]);
+            let left = self.int_expression(depth - 1);
+            format!("({} {} {})", left, operator, self.int_expression(depth - 1))
+        } else {
+            let operator = *self.rng.choose(&["==", "!="]);
+            let left = self.bool_expression(depth - 1);
+            format!(
+                "({} {} {})",
+                left,
+                operator,
+                self.bool_expression(depth - 1)
+            )
+        }
+    }
+
+    fn bool_leaf(&mut self) -> String {
+        let variables = self.names_of_type(&Type::Bool);
+        let pointers = self.names_of_type(&Type::Pointer(Box::new(Type::Bool)));
+        match self.rng.below(10) {
+            0..=3 if !variables.is_empty() => self.rng.choose(&variables).clone(),
+            4..=5 if !pointers.is_empty() => format!("*{}", self.rng.choose(&pointers)),
+            _ => self.bool_literal(),
+        }
+    }
+
+    fn bool_literal(&mut self) -> String {
+        if self.rng.chance(50) {
+            "true".to_string()
+        } else {
+            "false".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::interpret_with_limit;
+    use crate::lexer::lexer;
+    use crate::parser::parse_tokens;
+    use std::collections::HashMap;
+
+    #[test]
+    fn the_same_seed_gives_the_same_program() {
+        assert_eq!(generate(7, 40), generate(7, 40));
+        assert_ne!(generate(7, 40), generate(8, 40));
+    }
+
+    #[test]
+    fn programs_are_accepted_and_finish() {
+        for seed in 0..100 {
+            let source = generate(seed, 40);
+            let mut tokens = lexer(source.clone());
+            let mut symbol_table = HashMap::new();
+            let statements = parse_tokens(&mut tokens, &mut symbol_table);
+            let result = interpret_with_limit(&statements, &symbol_table, 1_000_000);
+            assert!(
+                result.is_ok(),
+                "seed {} gave {:?}:\n{}",
+                seed,
+                result,
+                source
+            );
+        }
+    }
+
+    // Compiles and runs the programs natively, like the difftest corpus test.
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn compiled_programs_match_the_interpreter() {
+        use crate::difftest::run_difftest;
+        use crate::toolchain::TempDir;
+
+        let directory = TempDir::new("generated");
+        for seed in 0..20 {
+            std::fs::write(
+                directory.path.join(format!("{}.ttc", seed)),
+                generate(seed, 30),
+            )
+            .unwrap();
+        }
+        let divergences = run_difftest(std::slice::from_ref(&directory.path), &[0, 1, 2]);
+        for divergence in &divergences {
+            divergence.print();
+        }
+        assert!(divergences.is_empty());
+    }
+}
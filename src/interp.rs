@@ -0,0 +1,398 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use crate::backend::get_type_size;
+use crate::lowering::get_exit_symbol;
+use crate::representations::{Assignment, Block, Expression, List, Literal, Statement, Symbol};
+
+// Runs a program straight from its statements, as a reference for what the compiled program
+// should do. Every value is a 64 bit integer that wraps on overflow, bools are 0 or 1, and
+// every variable lives in a simulated stack so that pointers to it can be taken and followed
+
+// Where the simulated stack starts. Nothing is ever at address 0, so null pointers can't be
+// followed
+const STACK_BASE: i64 = 0x1000;
+const WORD_SIZE: i64 = 8;
+
+// Ways a program can stop early. The compiled program is killed by SIGFPE for both kinds of bad
+// division, and reads whatever happens to be at an invalid address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    DivisionByZero,
+    // i64::MIN / -1, whose result doesn't fit in 64 bits
+    DivisionOverflow,
+    InvalidAddress(i64),
+    // The program ran more statements and loop iterations than it was allowed to
+    OutOfSteps,
+}
+
+// Run a program, returning its exit value: the final value of the variable the compiled
+// program would exit with
+pub fn interpret(
+    statements: &VecDeque<Statement>,
+    symbol_table: &HashMap<String, Symbol>,
+) -> Result<i64, Trap> {
+    interpret_with_limit(statements, symbol_table, u64::MAX)
+}
+
+// Run a program, giving up once it has taken max_steps steps. Each statement and each check of
+// a loop's condition is a step, so a program that never finishes is always stopped
+pub fn interpret_with_limit(
+    statements: &VecDeque<Statement>,
+    symbol_table: &HashMap<String, Symbol>,
+    max_steps: u64,
+) -> Result<i64, Trap> {
+    let interpreter = run_statements(statements, symbol_table, max_steps)?;
+    let exit_symbol = get_exit_symbol(symbol_table);
+    interpreter.load(interpreter.addresses[&exit_symbol])
+}
+
+// Run a program only for whether it traps, giving up once it has taken max_steps steps. Unlike
+// interpret it doesn't need a variable to exit with
+pub fn run_with_limit(
+    statements: &VecDeque<Statement>,
+    symbol_table: &HashMap<String, Symbol>,
+    max_steps: u64,
+) -> Result<(), Trap> {
+    run_statements(statements, symbol_table, max_steps).map(|_| ())
+}
+
+// Run a program and then evaluate an expression over the variables it leaves behind, giving up
+// once they have taken max_steps steps between them
+pub fn evaluate_after(
+    statements: &VecDeque<Statement>,
+    symbol_table: &HashMap<String, Symbol>,
+    expr: &Expression,
+    max_steps: u64,
+) -> Result<i64, Trap> {
+    let mut interpreter = run_statements(statements, symbol_table, max_steps)?;
+    interpreter.evaluate(expr)
+}
+
+fn run_statements(
+    statements: &VecDeque<Statement>,
+    symbol_table: &HashMap<String, Symbol>,
+    max_steps: u64,
+) -> Result<Interpreter, Trap> {
+    let mut interpreter = Interpreter::new(symbol_table, max_steps);
+    for statement in statements {
+        // The parser doesn't allow a break outside of a loop
+        interpreter.run_statement(statement)?;
+    }
+    Ok(interpreter)
+}
+
+// What running a statement leaves the enclosing loop to do
+#[derive(PartialEq, Eq)]
+enum Flow {
+    Continue,
+    Break,
+}
+
+struct Interpreter {
+    stack: Vec<i64>,
+    // The address of the first word of each variable
+    addresses: HashMap<String, i64>,
+    // Each list literal has its own storage, which is reused every time the literal is
+    // evaluated, just like the compiled program's. They are told apart by where they are in the
+    // tree
+    list_addresses: HashMap<*const List, i64>,
+    steps_left: u64,
+}
+
+impl Interpreter {
+    // Every variable gets space up front, arrays getting a word for each element. Variables are
+    // laid out in name order so addresses are the same on every run
+    fn new(symbol_table: &HashMap<String, Symbol>, max_steps: u64) -> Self {
+        let mut ids: Vec<&String> = symbol_table.keys().collect();
+        ids.sort();
+        let mut interpreter = Interpreter {
+            stack: Vec::new(),
+            addresses: HashMap::new(),
+            list_addresses: HashMap::new(),
+            steps_left: max_steps,
+        };
+        for id in ids {
+            let words = get_type_size(&symbol_table[id]._type) / WORD_SIZE as u64;
+            let address = interpreter.allocate(words as usize);
+            interpreter.addresses.insert(id.clone(), address);
+        }
+        interpreter
+    }
+
+    fn allocate(&mut self, words: usize) -> i64 {
+        let address = STACK_BASE + self.stack.len() as i64 * WORD_SIZE;
+        self.stack.resize(self.stack.len() + words, 0);
+        address
+    }
+
+    fn word_index(&self, address: i64) -> Result<usize, Trap> {
+        let offset = address.wrapping_sub(STACK_BASE);
+        if offset < 0 || offset % WORD_SIZE != 0 || offset / WORD_SIZE >= self.stack.len() as i64 {
+            return Err(Trap::InvalidAddress(address));
+        }
+        Ok((offset / WORD_SIZE) as usize)
+    }
+
+    fn load(&self, address: i64) -> Result<i64, Trap> {
+        Ok(self.stack[self.word_index(address)?])
+    }
+
+    fn store(&mut self, address: i64, value: i64) -> Result<(), Trap> {
+        let index = self.word_index(address)?;
+        self.stack[index] = value;
+        Ok(())
+    }
+
+    fn take_step(&mut self) -> Result<(), Trap> {
+        if self.steps_left == 0 {
+            return Err(Trap::OutOfSteps);
+        }
+        self.steps_left -= 1;
+        Ok(())
+    }
+
+    fn variable_address(&self, id: &str) -> i64 {
+        *self
+            .addresses
+            .get(id)
+            .unwrap_or_else(|| panic!("{} is referenced before declaration", id))
+    }
+
+    fn run_statement(&mut self, statement: &Statement) -> Result<Flow, Trap> {
+        self.take_step()?;
+        match statement {
+            Statement::Assignment(assign_type, expr) => {
+                let value = self.evaluate(expr)?;
+                let id = match assign_type {
+                    Assignment::Value(_, id)
+                    | Assignment::Pointer(_, id)
+                    | Assignment::Mutation(id) => id,
+                };
+                self.store(self.variable_address(id), value)?;
+            }
+            Statement::If(expr, if_block) => {
+                if self.evaluate(expr)? != 0 {
+                    return self.run_statement(if_block);
+                }
+            }
+            Statement::IfElse(expr, if_block, else_block) => {
+                return if self.evaluate(expr)? != 0 {
+                    self.run_statement(if_block)
+                } else {
+                    self.run_statement(else_block)
+                };
+            }
+            Statement::Block(block) => return self.run_block(block),
+            Statement::While(expr, while_block) => loop {
+                self.take_step()?;
+                if self.evaluate(expr)? == 0 || self.run_statement(while_block)? == Flow::Break {
+                    break;
+                }
+            },
+            Statement::Break => return Ok(Flow::Break),
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn run_block(&mut self, block: &Block) -> Result<Flow, Trap> {
+        match block {
+            Block::Statement(stmt) => self.run_statement(stmt),
+            Block::Block(stmt, block) => match self.run_statement(stmt)? {
+                Flow::Break => Ok(Flow::Break),
+                Flow::Continue => self.run_block(block),
+            },
+        }
+    }
+
+    fn evaluate(&mut self, expr: &Expression) -> Result<i64, Trap> {
+        match expr {
+            Expression::Binary(left, op, right) => {
+                let left = self.evaluate(left)?;
+                let right = self.evaluate(right)?;
+                evaluate_binary(op.lexeme(), left, right)
+            }
+            Expression::Unary(op, inner) => match op.lexeme() {
+                "-" => Ok(self.evaluate(inner)?.wrapping_neg()),
+                "&" => match inner.as_ref() {
+                    Expression::Literal(Literal::Symbol(token)) => {
+                        Ok(self.variable_address(token.lexeme()))
+                    }
+                    _ => panic!(
+                        "Attempted to reference a non-memory location: this should never happen!"
+                    ),
+                },
+                "*" => {
+                    let address = self.evaluate(inner)?;
+                    self.load(address)
+                }
+                _ => panic!("Unrecognised unary op {}", op.lexeme()),
+            },
+            Expression::Literal(literal) => self.evaluate_literal(literal),
+            Expression::Group(_, inner, _) => self.evaluate(inner),
+        }
+    }
+
+    fn evaluate_literal(&mut self, literal: &Literal) -> Result<i64, Trap> {
+        match literal {
+            Literal::Int(token) => Ok(token
+                .lexeme()
+                .parse()
+                .unwrap_or_else(|_| panic!("{} is not a valid int literal", token.lexeme()))),
+            Literal::Bool(token) => match token.lexeme() {
+                "true" => Ok(1),
+                "false" => Ok(0),
+                other => panic!("{} is not a bool value!", other),
+            },
+            Literal::Symbol(token) => self.load(self.variable_address(token.lexeme())),
+            Literal::List(list) => self.evaluate_list(list),
+        }
+    }
+
+    // A list evaluates to the address of its first element, with the rest following it
+    fn evaluate_list(&mut self, list_literal: &List) -> Result<i64, Trap> {
+        let mut elements = Vec::new();
+        let mut list = list_literal;
+        loop {
+            match list {
+                List::Literal(literal) => {
+                    elements.push(self.evaluate_literal(literal)?);
+                    break;
+                }
+                List::List(literal, next) => {
+                    elements.push(self.evaluate_literal(literal)?);
+                    list = next;
+                }
+            }
+        }
+
+        let key = list_literal as *const List;
+        let base = match self.list_addresses.get(&key) {
+            Some(address) => *address,
+            None => {
+                let address = self.allocate(elements.len());
+                self.list_addresses.insert(key, address);
+                address
+            }
+        };
+        for (index, element) in elements.into_iter().enumerate() {
+            self.store(base + index as i64 * WORD_SIZE, element)?;
+        }
+        Ok(base)
+    }
+}
+
+fn evaluate_binary(op: &str, left: i64, right: i64) -> Result<i64, Trap> {
+    let check_divisor = || match right {
+        0 => Err(Trap::DivisionByZero),
+        -1 if left == i64::MIN => Err(Trap::DivisionOverflow),
+        _ => Ok(()),
+    };
+    let value = match op {
+        "+" => left.wrapping_add(right),
+        "-" => left.wrapping_sub(right),
+        "*" => left.wrapping_mul(right),
+        "/" => {
+            check_divisor()?;
+            left / right
+        }
+        "%" => {
+            check_divisor()?;
+            left % right
+        }
+        "==" => (left == right) as i64,
+        "!=" => (left != right) as i64,
+        "<" => (left < right) as i64,
+        ">" => (left > right) as i64,
+        "<=" => (left <= right) as i64,
+        ">=" => (left >= right) as i64,
+        _ => panic!("Can't handle {} yet", op),
+    };
+    Ok(value)
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::DivisionByZero => write!(f, "division by zero"),
+            Trap::DivisionOverflow => write!(f, "division overflow"),
+            Trap::InvalidAddress(address) => write!(f, "invalid address {:#x}", address),
+            Trap::OutOfSteps => write!(f, "ran out of steps"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lexer;
+    use crate::parser::parse_tokens;
+
+    fn run_source(source: &str) -> Result<i64, Trap> {
+        let mut tokens = lexer(source.to_string());
+        let mut symbol_table = HashMap::new();
+        let statements = parse_tokens(&mut tokens, &mut symbol_table);
+        interpret(&statements, &symbol_table)
+    }
+
+    #[test]
+    fn runs_loops_and_branches() {
+        let source = "mut int sum = 0;
+            mut int i = 0;
+            while true {
+                if i == 5 {
+                    break;
+                }
+                if i % 2 == 0 {
+                    sum = sum + i;
+                } else {
+                    sum = sum - 1;
+                }
+                i = i + 1;
+            }
+            const int exit = sum * 10 + i;";
+        assert_eq!(run_source(source), Ok(45));
+    }
+
+    #[test]
+    fn follows_pointers_to_variables() {
+        let source = "mut int x = 3;
+            const int* y = &x;
+            x = x + 4;
+            const int[3] l = [4, 5, 6];
+            const int exit = *y * 100;";
+        assert_eq!(run_source(source), Ok(700));
+    }
+
+    #[test]
+    fn uses_signed_division_that_truncates() {
+        let source = "const int a = -7;
+            const int b = a / 2;
+            const int c = a % 3;
+            const int exit = b * 10 + c;";
+        assert_eq!(run_source(source), Ok(-31));
+    }
+
+    #[test]
+    fn traps_on_division_by_zero() {
+        let source = "const int z = 0;
+            const int exit = 5 / z;";
+        assert_eq!(run_source(source), Err(Trap::DivisionByZero));
+    }
+
+    #[test]
+    fn stops_programs_that_never_finish() {
+        let source = "mut int i = 0;
+            while true {
+                i = i + 1;
+            }
+            const int exit = i;";
+        let mut tokens = lexer(source.to_string());
+        let mut symbol_table = HashMap::new();
+        let statements = parse_tokens(&mut tokens, &mut symbol_table);
+        assert_eq!(
+            interpret_with_limit(&statements, &symbol_table, 1000),
+            Err(Trap::OutOfSteps)
+        );
+    }
+}
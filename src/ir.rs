@@ -0,0 +1,352 @@
+// Three-address code that sits between the AST and the x86 backend. Every value is a 64 bit
+// integer: bools are 0 or 1 and pointers are addresses. Named variables live in memory and are
+// only touched through explicit loads and stores, while temporaries hold intermediate values
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Temp(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Temp(Temp),
+    Const(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Neg,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    // dest = operand
+    Copy(Temp, Operand),
+    // dest = left op right
+    Binary(Temp, BinaryOp, Operand, Operand),
+    // dest = op operand
+    Unary(Temp, UnaryOp, Operand),
+    // dest = value of variable
+    Load(Temp, String),
+    // variable = operand
+    Store(String, Operand),
+    // dest = address of variable
+    AddressOf(Temp, String),
+    // dest = value at the address in operand
+    LoadPointer(Temp, Operand),
+    // value at the address in the first operand = second operand
+    StorePointer(Operand, Operand),
+    // dest = the operand paired with whichever block control came from. Only found at the
+    // start of a block while the function is in SSA form
+    Phi(Temp, Vec<(BlockId, Operand)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    // Jump to the first block if the operand is non-zero, otherwise the second
+    Branch(Operand, BlockId, BlockId),
+    // End the program with the operand as the exit code
+    Exit(Operand),
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub id: BlockId,
+    pub instructions: Vec<Instruction>,
+    pub terminator: Terminator,
+}
+
+// The whole program as a list of basic blocks. The first block is the entry point, and the
+// blocks are laid out in this order when the program is emitted
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub blocks: Vec<BasicBlock>,
+    pub temp_count: u32,
+    pub block_count: u32,
+}
+
+impl BinaryOp {
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge
+        )
+    }
+}
+
+impl Operand {
+    pub fn as_temp(&self) -> Option<Temp> {
+        match self {
+            Operand::Temp(temp) => Some(*temp),
+            Operand::Const(_) => None,
+        }
+    }
+}
+
+impl Instruction {
+    // The temp written by this instruction, if any
+    pub fn dest(&self) -> Option<Temp> {
+        match self {
+            Instruction::Copy(dest, _)
+            | Instruction::Binary(dest, _, _, _)
+            | Instruction::Unary(dest, _, _)
+            | Instruction::Load(dest, _)
+            | Instruction::AddressOf(dest, _)
+            | Instruction::LoadPointer(dest, _)
+            | Instruction::Phi(dest, _) => Some(*dest),
+            Instruction::Store(_, _) | Instruction::StorePointer(_, _) => None,
+        }
+    }
+
+    pub fn dest_mut(&mut self) -> Option<&mut Temp> {
+        match self {
+            Instruction::Copy(dest, _)
+            | Instruction::Binary(dest, _, _, _)
+            | Instruction::Unary(dest, _, _)
+            | Instruction::Load(dest, _)
+            | Instruction::AddressOf(dest, _)
+            | Instruction::LoadPointer(dest, _)
+            | Instruction::Phi(dest, _) => Some(dest),
+            Instruction::Store(_, _) | Instruction::StorePointer(_, _) => None,
+        }
+    }
+
+    // Every operand read by this instruction
+    pub fn operands(&self) -> Vec<Operand> {
+        match self {
+            Instruction::Copy(_, operand)
+            | Instruction::Unary(_, _, operand)
+            | Instruction::Store(_, operand)
+            | Instruction::LoadPointer(_, operand) => vec![*operand],
+            Instruction::Binary(_, _, left, right) => vec![*left, *right],
+            Instruction::StorePointer(address, value) => vec![*address, *value],
+            Instruction::Phi(_, incoming) => incoming.iter().map(|(_, operand)| *operand).collect(),
+            Instruction::Load(_, _) | Instruction::AddressOf(_, _) => vec![],
+        }
+    }
+
+    // Every operand read by this instruction, so they can be rewritten in place
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Instruction::Copy(_, operand)
+            | Instruction::Unary(_, _, operand)
+            | Instruction::Store(_, operand)
+            | Instruction::LoadPointer(_, operand) => vec![operand],
+            Instruction::Binary(_, _, left, right) => vec![left, right],
+            Instruction::StorePointer(address, value) => vec![address, value],
+            Instruction::Phi(_, incoming) => {
+                incoming.iter_mut().map(|(_, operand)| operand).collect()
+            }
+            Instruction::Load(_, _) | Instruction::AddressOf(_, _) => vec![],
+        }
+    }
+
+    pub fn is_phi(&self) -> bool {
+        matches!(self, Instruction::Phi(_, _))
+    }
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch(_, if_true, if_false) => vec![*if_true, *if_false],
+            Terminator::Exit(_) => vec![],
+        }
+    }
+
+    pub fn operands(&self) -> Vec<Operand> {
+        match self {
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch(condition, _, _) => vec![*condition],
+            Terminator::Exit(operand) => vec![*operand],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Terminator::Jump(_) => vec![],
+            Terminator::Branch(condition, _, _) => vec![condition],
+            Terminator::Exit(operand) => vec![operand],
+        }
+    }
+
+    // Point every jump to one block at another block instead
+    pub fn replace_successor(&mut self, from: BlockId, to: BlockId) {
+        match self {
+            Terminator::Jump(target) => {
+                if *target == from {
+                    *target = to;
+                }
+            }
+            Terminator::Branch(_, if_true, if_false) => {
+                if *if_true == from {
+                    *if_true = to;
+                }
+                if *if_false == from {
+                    *if_false = to;
+                }
+            }
+            Terminator::Exit(_) => (),
+        }
+    }
+}
+
+impl Function {
+    pub fn new_temp(&mut self) -> Temp {
+        self.temp_count += 1;
+        Temp(self.temp_count - 1)
+    }
+
+    pub fn new_block_id(&mut self) -> BlockId {
+        self.block_count += 1;
+        BlockId(self.block_count - 1)
+    }
+
+    pub fn block(&self, id: BlockId) -> &BasicBlock {
+        self.blocks
+            .iter()
+            .find(|block| block.id == id)
+            .expect("Block ids should always refer to a block in the function")
+    }
+
+    pub fn block_mut(&mut self, id: BlockId) -> &mut BasicBlock {
+        self.blocks
+            .iter_mut()
+            .find(|block| block.id == id)
+            .expect("Block ids should always refer to a block in the function")
+    }
+}
+
+// The textual form of the IR, with each block written as its label followed by its
+// instructions, such as:
+// block_0:
+//     t0 = load x
+//     t1 = add t0, 1
+//     branch t1, block_1, block_2
+impl fmt::Display for Temp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "t{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block_{}", self.0)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Temp(temp) => write!(f, "{}", temp),
+            Operand::Const(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            BinaryOp::Mul => "mul",
+            BinaryOp::Div => "div",
+            BinaryOp::Mod => "mod",
+            BinaryOp::Eq => "eq",
+            BinaryOp::Ne => "ne",
+            BinaryOp::Lt => "lt",
+            BinaryOp::Gt => "gt",
+            BinaryOp::Le => "le",
+            BinaryOp::Ge => "ge",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnaryOp::Neg => write!(f, "neg"),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Copy(dest, operand) => write!(f, "{} = copy {}", dest, operand),
+            Instruction::Binary(dest, op, left, right) => {
+                write!(f, "{} = {} {}, {}", dest, op, left, right)
+            }
+            Instruction::Unary(dest, op, operand) => write!(f, "{} = {} {}", dest, op, operand),
+            Instruction::Load(dest, id) => write!(f, "{} = load {}", dest, id),
+            Instruction::Store(id, operand) => write!(f, "store {}, {}", id, operand),
+            Instruction::AddressOf(dest, id) => write!(f, "{} = addr {}", dest, id),
+            Instruction::LoadPointer(dest, address) => {
+                write!(f, "{} = loadptr {}", dest, address)
+            }
+            Instruction::StorePointer(address, value) => {
+                write!(f, "storeptr {}, {}", address, value)
+            }
+            Instruction::Phi(dest, incoming) => {
+                write!(f, "{} = phi", dest)?;
+                for (index, (block, operand)) in incoming.iter().enumerate() {
+                    let separator = if index == 0 { " " } else { ", " };
+                    write!(f, "{}[{}: {}]", separator, block, operand)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Terminator::Jump(target) => write!(f, "jump {}", target),
+            Terminator::Branch(condition, if_true, if_false) => {
+                write!(f, "branch {}, {}, {}", condition, if_true, if_false)
+            }
+            Terminator::Exit(operand) => write!(f, "exit {}", operand),
+        }
+    }
+}
+
+impl fmt::Display for BasicBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}:", self.id)?;
+        for instruction in &self.instructions {
+            writeln!(f, "    {}", instruction)?;
+        }
+        writeln!(f, "    {}", self.terminator)
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for block in &self.blocks {
+            write!(f, "{}", block)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+
+use crate::ir::{
+    BasicBlock, BinaryOp, BlockId, Function, Instruction, Operand, Temp, Terminator, UnaryOp,
+};
+use crate::representations::{Symbol, Type};
+
+// The textual form of a whole program: the variables it uses, one per line as
+// `mut x: int` or `const l: int[3]`, followed by the IR for its blocks. A * binds looser than
+// [n], so `*int[3]` is a pointer to an array and an array of pointers is `(*int)[3]`. Lines
+// starting with ; are comments
+
+// Write a program in the form that parse_ir reads back in. Variables are sorted by name so the
+// output is the same on every run
+pub fn write_ir(function: &Function, symbol_table: &HashMap<String, Symbol>) -> String {
+    let mut ids: Vec<&String> = symbol_table.keys().collect();
+    ids.sort();
+    let mut text = String::new();
+    for id in &ids {
+        let symbol_info = &symbol_table[*id];
+        let keyword = if symbol_info.mutable { "mut" } else { "const" };
+        text.push_str(&format!(
+            "{} {}: {}\n",
+            keyword,
+            id,
+            format_type(&symbol_info._type)
+        ));
+    }
+    if !ids.is_empty() {
+        text.push('\n');
+    }
+    text.push_str(&function.to_string());
+    text
+}
+
+fn format_type(type_to_format: &Type) -> String {
+    match type_to_format {
+        Type::Int => "int".to_string(),
+        Type::Bool => "bool".to_string(),
+        Type::None => "none".to_string(),
+        Type::Pointer(inner) => format!("*{}", format_type(inner)),
+        // *int[3] is a pointer to an array, so an array of pointers needs brackets
+        Type::Array(inner, length) => match **inner {
+            Type::Pointer(_) => format!("({})[{}]", format_type(inner), length),
+            _ => format!("{}[{}]", format_type(inner), length),
+        },
+    }
+}
+
+// Read a program written by write_ir. The IR isn't checked beyond its syntax, so it should be
+// passed to the verifier before anything else is done with it
+pub fn parse_ir(text: &str) -> (Function, HashMap<String, Symbol>) {
+    let mut symbol_table = HashMap::new();
+    let mut blocks = Vec::new();
+    let mut current_block: Option<(BlockId, Vec<Instruction>)> = None;
+    let mut temp_count = 0;
+    let mut block_count = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = match line.split_once(';') {
+            Some((code, _)) => code.trim(),
+            None => line.trim(),
+        };
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some((keyword, declaration)) = line.split_once(' ') {
+            if keyword == "mut" || keyword == "const" {
+                if current_block.is_some() || !blocks.is_empty() {
+                    panic!(
+                        "Variables must be declared before the first block at line {}",
+                        line_number
+                    );
+                }
+                let (id, type_text) = declaration
+                    .split_once(':')
+                    .unwrap_or_else(|| panic!("Expected `id: type` at line {}", line_number));
+                let symbol_info = Symbol {
+                    stack_offset: None,
+                    _type: parse_type(type_text.trim(), line_number),
+                    mutable: keyword == "mut",
+                    init_line: line_number,
+                    last_ref: line_number,
+                    live_range: None,
+                };
+                if symbol_table
+                    .insert(id.trim().to_string(), symbol_info)
+                    .is_some()
+                {
+                    panic!(
+                        "Variable {} is declared twice at line {}",
+                        id.trim(),
+                        line_number
+                    );
+                }
+                continue;
+            }
+        }
+
+        if let Some(label) = line.strip_suffix(':') {
+            if let Some((id, _)) = current_block {
+                panic!(
+                    "{} doesn't end with a terminator before line {}",
+                    id, line_number
+                );
+            }
+            let id = parse_block_id(label, line_number);
+            block_count = block_count.max(id.0 + 1);
+            current_block = Some((id, Vec::new()));
+            continue;
+        }
+
+        let Some((block_id, instructions)) = current_block.as_mut() else {
+            panic!("Instruction outside of a block at line {}", line_number);
+        };
+        let tokens: Vec<&str> = line
+            .split(|c: char| c.is_whitespace() || c == ',' || c == '[' || c == ']')
+            .filter(|token| !token.is_empty())
+            .collect();
+        let mut line_parser = LineParser {
+            line_number,
+            temp_count: &mut temp_count,
+        };
+        match line_parser.parse_terminator(&tokens) {
+            Some(terminator) => {
+                blocks.push(BasicBlock {
+                    id: *block_id,
+                    instructions: std::mem::take(instructions),
+                    terminator,
+                });
+                current_block = None;
+            }
+            None => instructions.push(line_parser.parse_instruction(&tokens)),
+        }
+    }
+    if let Some((id, _)) = current_block {
+        panic!("{} doesn't end with a terminator", id);
+    }
+
+    let function = Function {
+        blocks,
+        temp_count,
+        block_count,
+    };
+    (function, symbol_table)
+}
+
+fn parse_type(text: &str, line_number: usize) -> Type {
+    if let Some(inner) = text
+        .strip_prefix('(')
+        .and_then(|text| text.strip_suffix(')'))
+    {
+        return parse_type(inner, line_number);
+    }
+    if let Some(inner) = text.strip_prefix('*') {
+        return Type::Pointer(Box::new(parse_type(inner, line_number)));
+    }
+    if let Some(array) = text.strip_suffix(']') {
+        if let Some((inner, length)) = array.rsplit_once('[') {
+            let length = length.parse().unwrap_or_else(|_| {
+                panic!("Invalid array length {} at line {}", length, line_number)
+            });
+            return Type::Array(Box::new(parse_type(inner, line_number)), length);
+        }
+    }
+    match text {
+        "int" => Type::Int,
+        "bool" => Type::Bool,
+        "none" => Type::None,
+        _ => panic!("Unrecognised type {} at line {}", text, line_number),
+    }
+}
+
+fn parse_block_id(text: &str, line_number: usize) -> BlockId {
+    text.strip_prefix("block_")
+        .and_then(|number| number.parse().ok())
+        .map(BlockId)
+        .unwrap_or_else(|| {
+            panic!(
+                "Expected a block label but found {} at line {}",
+                text, line_number
+            )
+        })
+}
+
+// Parses the tokens of one line, keeping track of the highest numbered temp seen so the
+// function knows where to start numbering new temps
+struct LineParser<'a> {
+    line_number: usize,
+    temp_count: &'a mut u32,
+}
+
+impl LineParser<'_> {
+    fn parse_terminator(&mut self, tokens: &[&str]) -> Option<Terminator> {
+        let terminator = match tokens {
+            ["jump", target] => Terminator::Jump(self.parse_block(target)),
+            ["branch", condition, if_true, if_false] => Terminator::Branch(
+                self.parse_operand(condition),
+                self.parse_block(if_true),
+                self.parse_block(if_false),
+            ),
+            ["exit", operand] => Terminator::Exit(self.parse_operand(operand)),
+            _ => return None,
+        };
+        Some(terminator)
+    }
+
+    fn parse_instruction(&mut self, tokens: &[&str]) -> Instruction {
+        match tokens {
+            ["store", id, operand] => {
+                Instruction::Store(id.to_string(), self.parse_operand(operand))
+            }
+            ["storeptr", address, value] => {
+                Instruction::StorePointer(self.parse_operand(address), self.parse_operand(value))
+            }
+            [dest, "=", "phi", incoming @ ..] => {
+                let dest = self.parse_temp(dest);
+                if incoming.len() % 2 != 0 {
+                    panic!(
+                        "Phi entries should be [block: operand] at line {}",
+                        self.line_number
+                    );
+                }
+                let incoming = incoming
+                    .chunks(2)
+                    .map(|entry| {
+                        let block = entry[0].strip_suffix(':').unwrap_or_else(|| {
+                            panic!(
+                                "Phi entries should be [block: operand] at line {}",
+                                self.line_number
+                            )
+                        });
+                        (self.parse_block(block), self.parse_operand(entry[1]))
+                    })
+                    .collect();
+                Instruction::Phi(dest, incoming)
+            }
+            [dest, "=", op, operands @ ..] => {
+                let dest = self.parse_temp(dest);
+                match (*op, operands) {
+                    ("copy", [operand]) => Instruction::Copy(dest, self.parse_operand(operand)),
+                    ("load", [id]) => Instruction::Load(dest, id.to_string()),
+                    ("addr", [id]) => Instruction::AddressOf(dest, id.to_string()),
+                    ("loadptr", [address]) => {
+                        Instruction::LoadPointer(dest, self.parse_operand(address))
+                    }
+                    ("neg", [operand]) => {
+                        Instruction::Unary(dest, UnaryOp::Neg, self.parse_operand(operand))
+                    }
+                    (op, [left, right]) => Instruction::Binary(
+                        dest,
+                        self.parse_binary_op(op),
+                        self.parse_operand(left),
+                        self.parse_operand(right),
+                    ),
+                    _ => panic!("Unrecognised IR instruction at line {}", self.line_number),
+                }
+            }
+            _ => panic!("Unrecognised IR instruction at line {}", self.line_number),
+        }
+    }
+
+    fn parse_binary_op(&self, text: &str) -> BinaryOp {
+        match text {
+            "add" => BinaryOp::Add,
+            "sub" => BinaryOp::Sub,
+            "mul" => BinaryOp::Mul,
+            "div" => BinaryOp::Div,
+            "mod" => BinaryOp::Mod,
+            "eq" => BinaryOp::Eq,
+            "ne" => BinaryOp::Ne,
+            "lt" => BinaryOp::Lt,
+            "gt" => BinaryOp::Gt,
+            "le" => BinaryOp::Le,
+            "ge" => BinaryOp::Ge,
+            _ => panic!(
+                "Unrecognised operation {} at line {}",
+                text, self.line_number
+            ),
+        }
+    }
+
+    fn parse_operand(&mut self, text: &str) -> Operand {
+        match text.parse() {
+            Ok(value) => Operand::Const(value),
+            Err(_) => Operand::Temp(self.parse_temp(text)),
+        }
+    }
+
+    fn parse_temp(&mut self, text: &str) -> Temp {
+        let temp = text
+            .strip_prefix('t')
+            .and_then(|number| number.parse().ok())
+            .map(Temp)
+            .unwrap_or_else(|| {
+                panic!(
+                    "Expected a temp but found {} at line {}",
+                    text, self.line_number
+                )
+            });
+        *self.temp_count = (*self.temp_count).max(temp.0 + 1);
+        temp
+    }
+
+    fn parse_block(&self, text: &str) -> BlockId {
+        parse_block_id(text, self.line_number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pointers_and_arrays_read_back_as_written() {
+        let int = || Box::new(Type::Int);
+        let types = [
+            Type::Pointer(Box::new(Type::Array(int(), 3))),
+            Type::Array(Box::new(Type::Pointer(int())), 3),
+            Type::Pointer(Box::new(Type::Array(Box::new(Type::Pointer(int())), 2))),
+        ];
+        assert_eq!(format_type(&types[0]), "*int[3]");
+        assert_eq!(format_type(&types[1]), "(*int)[3]");
+        for _type in types {
+            assert_eq!(parse_type(&format_type(&_type), 1), _type);
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crate::asm;
+use crate::encoder::encode;
+
+// Runs a program inside the compiler's own process, without writing or starting an executable.
+// The program has to have been built with ProgramExit::Return so that it returns its exit value
+// instead of ending the process. A program that crashes or never finishes takes the compiler
+// with it
+
+// Encode the program into executable memory and call it, returning its exit value
+pub fn run_jit(instructions: &[asm::Instruction]) -> i64 {
+    let code = encode(instructions);
+    let memory = ExecutableMemory::new(&code);
+    // SAFETY: the memory holds a whole program built to be called as a function with no
+    // arguments, which only touches the stack below its own frame and returns its exit value in
+    // rax, as the System V calling convention expects
+    let program: extern "C" fn() -> i64 = unsafe { std::mem::transmute(memory.pointer) };
+    program()
+}
+
+// A mapping that is written while it is writable and then made executable, so it is never both
+struct ExecutableMemory {
+    pointer: *mut libc::c_void,
+    length: usize,
+}
+
+impl ExecutableMemory {
+    fn new(code: &[u8]) -> Self {
+        // An empty mapping isn't allowed
+        let length = code.len().max(1);
+        // SAFETY: a new anonymous mapping doesn't alias any existing memory
+        let pointer = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                length,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if pointer == libc::MAP_FAILED {
+            panic!(
+                "Couldn't map memory for the program: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let memory = ExecutableMemory { pointer, length };
+        // SAFETY: the mapping is at least code.len() bytes long and writable
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), pointer.cast::<u8>(), code.len());
+        }
+        // SAFETY: the pointer and length are exactly those of the mapping
+        if unsafe { libc::mprotect(pointer, length, libc::PROT_READ | libc::PROT_EXEC) } != 0 {
+            panic!(
+                "Couldn't make the program executable: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        memory
+    }
+}
+
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is no longer used once the program has returned
+        unsafe {
+            libc::munmap(self.pointer, self.length);
+        }
+    }
+}
+
+// The tests execute the generated machine code, so they need an x86-64 Linux host.
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::backend::ProgramExit;
+    use crate::{compile, Options};
+
+    fn run_source(source: &str, opt_level: u8) -> i64 {
+        let options = Options {
+            opt_level,
+            program_exit: ProgramExit::Return,
+            ..Options::default()
+        };
+        let artifact = compile(source, &options).expect("The program is valid");
+        run_jit(&artifact.instructions)
+    }
+
+    #[test]
+    fn returns_the_exit_value() {
+        let source = "mut int total = 0;
+            mut int i = 0;
+            while i < 10 {
+                total = total + i * 3;
+                i = i + 1;
+            }
+            const int exit = total - 1000;";
+        for opt_level in 0..=2 {
+            assert_eq!(run_source(source, opt_level), -865);
+        }
+    }
+}
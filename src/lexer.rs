@@ -1,6 +1,7 @@
 use std::collections::VecDeque;
 
 use crate::representations::{Token, TokenType, Type};
+use crate::trace;
 
 pub fn lexer(mut src: String) -> VecDeque<Token> {
     let mut tokens = VecDeque::<Token>::new();
@@ -41,6 +42,13 @@ pub fn lexer(mut src: String) -> VecDeque<Token> {
                 src_index += 1;
                 line_index += 1;
             }
+            // Comments run to the end of the line, leaving the newline to be counted
+            '/' if src.starts_with("//") => {
+                let comment_length = src.find('\n').unwrap_or(src.len());
+                src.drain(..comment_length);
+                src_index += comment_length;
+                line_index += comment_length;
+            }
             '+' | '-' | '/' | '*' | '%' | '&' => {
                 tokens.push_back(consume_token(
                     &mut src,
@@ -93,7 +101,14 @@ fn consume_token(
 ) -> Token {
     // remove the amount of characters specified from the string
     let lexeme = src.drain(..chars_to_consume);
-    /* println!("lexeme is {}", &lexeme.as_str()); */
+    trace!(
+        Lexer,
+        "{:?} {:?} at {}:{}",
+        token_type,
+        lexeme.as_str(),
+        line_number,
+        line_index
+    );
     // Return the token created
     Token::new(
         _type,
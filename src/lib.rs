@@ -0,0 +1,408 @@
+pub mod asm;
+pub mod ast_printer;
+pub mod backend;
+pub mod cfg;
+pub mod constant_folding;
+pub mod dead_code;
+pub mod difftest;
+pub mod elf;
+pub mod encoder;
+pub mod generator;
+pub mod interp;
+pub mod ir;
+pub mod ir_text;
+pub mod jit;
+pub mod lexer;
+pub mod liveness;
+pub mod loops;
+pub mod lowering;
+pub mod parser;
+pub mod passes;
+pub mod peephole;
+pub mod reduce;
+pub mod repl;
+pub mod representations;
+pub mod sandbox;
+pub mod ssa;
+pub mod timing;
+pub mod toolchain;
+pub mod trace;
+pub mod verifier;
+
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+use std::time::Instant;
+
+use crate::ast_printer::statement_tree;
+use crate::backend::{allocate, build, ProgramExit};
+use crate::elf::{write_executable, write_object, Object};
+use crate::ir_text::{parse_ir, write_ir};
+use crate::lexer::lexer;
+use crate::lowering::lower;
+use crate::parser::parse_tokens;
+use crate::passes::PassManager;
+use crate::representations::{Statement, Symbol, Token};
+use crate::timing::TimeReport;
+use crate::verifier::verify;
+
+// The compiler as a library. compile takes a program's source all the way to machine code, and
+// each phase can also be run on its own: lex, parse, check and codegen. The phases report
+// problems by panicking, so each one is run with panics caught and turned into diagnostics.
+// Only panics are caught: a program that makes the compiler overflow its stack or abort still
+// takes down whatever is embedding it
+
+// How to compile a program
+pub struct Options {
+    // 0 emits the program as written, 1 folds constants, removes dead code and cleans up the
+    // generated assembly, 2 also optimises loops
+    pub opt_level: u8,
+    // Names of passes to skip
+    pub disabled_passes: Vec<String>,
+    // Names of passes to print the program after
+    pub print_after: Vec<String>,
+    // Report what the optimisations did and how long each pass took
+    pub verbose: bool,
+    pub program_exit: ProgramExit,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            opt_level: 0,
+            disabled_passes: Vec::new(),
+            print_after: Vec::new(),
+            verbose: false,
+            program_exit: ProgramExit::Syscall,
+        }
+    }
+}
+
+// Which phase found a problem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Lexing,
+    Parsing,
+    Checking,
+    Codegen,
+    Assembling,
+    Linking,
+    Writing,
+    Running,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub phase: Phase,
+    pub message: String,
+}
+
+// A program the front end has accepted
+#[derive(Debug)]
+pub struct Program {
+    pub statements: VecDeque<Statement>,
+    pub symbol_table: HashMap<String, Symbol>,
+}
+
+// A program in well formed IR, ready to be optimised and turned into machine code
+#[derive(Debug)]
+pub struct Ir {
+    pub function: ir::Function,
+    pub symbol_table: HashMap<String, Symbol>,
+}
+
+// A compiled program
+#[derive(Debug)]
+pub struct Artifact {
+    // The optimised IR, as text
+    pub ir: String,
+    pub instructions: Vec<asm::Instruction>,
+    // Every variable, with where the backend put it
+    pub symbol_table: HashMap<String, Symbol>,
+    // How long optimising, allocating and generating code took
+    pub timings: TimeReport,
+    // What the optimisations did with verbose set, and the program after each pass in
+    // print_after, in the order they happened. Each report ends with a newline
+    pub reports: Vec<String>,
+}
+
+// Compile a program from source to machine code
+pub fn compile(source: &str, options: &Options) -> Result<Artifact, Vec<Diagnostic>> {
+    let tokens = lex(source)?;
+    let program = parse(tokens)?;
+    let ir = check(program)?;
+    codegen(ir, options)
+}
+
+pub fn lex(source: &str) -> Result<VecDeque<Token>, Vec<Diagnostic>> {
+    run_phase(Phase::Lexing, || lexer(source.to_string()))
+}
+
+// Parse the tokens into statements, checking types and declarations as it goes
+pub fn parse(mut tokens: VecDeque<Token>) -> Result<Program, Vec<Diagnostic>> {
+    run_phase(Phase::Parsing, || {
+        let mut symbol_table = HashMap::new();
+        let statements = parse_tokens(&mut tokens, &mut symbol_table);
+        for statement in &statements {
+            trace!(Ast, "{}", statement_tree(statement));
+        }
+        Program {
+            statements,
+            symbol_table,
+        }
+    })
+}
+
+// Lower the program to IR and check the IR is well formed, reporting every problem found
+pub fn check(program: Program) -> Result<Ir, Vec<Diagnostic>> {
+    let Program {
+        statements,
+        mut symbol_table,
+    } = program;
+    let function = run_phase(Phase::Checking, || lower(&statements, &mut symbol_table))?;
+    verified(Ir {
+        function,
+        symbol_table,
+    })
+}
+
+// Read a program written as IR text. It skips the front end entirely, so it has to be checked
+// before it's optimised
+pub fn load_ir(text: &str) -> Result<Ir, Vec<Diagnostic>> {
+    let (function, symbol_table) = run_phase(Phase::Parsing, || parse_ir(text))?;
+    verified(Ir {
+        function,
+        symbol_table,
+    })
+}
+
+fn verified(ir: Ir) -> Result<Ir, Vec<Diagnostic>> {
+    let problems = verify(&ir.function, &ir.symbol_table);
+    if problems.is_empty() {
+        return Ok(ir);
+    }
+    Err(problems
+        .into_iter()
+        .map(|message| Diagnostic {
+            phase: Phase::Checking,
+            message,
+        })
+        .collect())
+}
+
+// Optimise the IR, which is in SSA form while the optimisations work on it and converted back
+// out before the backend sees it, and generate the program's machine code
+pub fn codegen(ir: Ir, options: &Options) -> Result<Artifact, Vec<Diagnostic>> {
+    let Ir {
+        mut function,
+        mut symbol_table,
+    } = ir;
+    run_phase(Phase::Codegen, || {
+        let mut timings = TimeReport::new();
+        let start = Instant::now();
+        let mut pass_manager = PassManager::new(
+            options.opt_level,
+            &options.disabled_passes,
+            &options.print_after,
+            options.verbose,
+        );
+        pass_manager.run_ir_passes(&mut function, &symbol_table);
+        let ir = write_ir(&function, &symbol_table);
+        let ir_instructions = instruction_count(&function);
+        timings.record(
+            "optimisation",
+            start,
+            Some((ir_instructions, "IR instructions")),
+        );
+
+        let start = Instant::now();
+        let allocation = allocate(&function, &mut symbol_table);
+        let allocated = symbol_table
+            .values()
+            .filter(|symbol| symbol.stack_offset.is_some())
+            .count();
+        timings.record("stack allocation", start, Some((allocated, "variables")));
+        trace!(
+            Backend,
+            "symbols after stack allocation:\n{:#?}",
+            symbol_table
+        );
+
+        let start = Instant::now();
+        let mut instructions = build(&function, &symbol_table, allocation, options.program_exit);
+        pass_manager.run_assembly_passes(&mut instructions);
+        timings.record("codegen", start, Some((instructions.len(), "instructions")));
+        if options.verbose {
+            let timings_report = pass_manager.timings_report();
+            pass_manager.reports.push(timings_report);
+        }
+        Artifact {
+            ir,
+            instructions,
+            symbol_table,
+            timings,
+            reports: pass_manager.reports,
+        }
+    })
+}
+
+// How many instructions there are in all of a function's blocks
+pub fn instruction_count(function: &ir::Function) -> usize {
+    function
+        .blocks
+        .iter()
+        .map(|block| block.instructions.len())
+        .sum()
+}
+
+thread_local! {
+    // Whether this thread is inside catch_panic, so its panics shouldn't be printed
+    static SUPPRESS_PANICS: Cell<bool> = const { Cell::new(false) };
+}
+
+// The panic hook is shared by every thread, so it's replaced once with one that stays quiet for
+// threads inside catch_panic and passes every other panic on to the hook that was there before
+static INSTALL_PANIC_HOOK: Once = Once::new();
+
+// Run a function that reports problems by panicking, without printing the panic, returning the
+// panic's message if it did
+pub fn catch_panic<T>(function: impl FnOnce() -> T) -> Result<T, String> {
+    INSTALL_PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !SUPPRESS_PANICS.with(Cell::get) {
+                previous(info);
+            }
+        }));
+    });
+    let suppressed = SUPPRESS_PANICS.with(|suppress| suppress.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(function));
+    SUPPRESS_PANICS.with(|suppress| suppress.set(suppressed));
+    result.map_err(|payload| {
+        if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else {
+            "the compiler panicked".to_string()
+        }
+    })
+}
+
+fn run_phase<T>(phase: Phase, function: impl FnOnce() -> T) -> Result<T, Vec<Diagnostic>> {
+    catch_panic(function).map_err(|message| vec![Diagnostic { phase, message }])
+}
+
+impl Artifact {
+    // The program as nasm assembly
+    pub fn assembly(&self) -> String {
+        let mut assembly = "global _start\n\nsection .text\n\n_start:\n".to_string();
+        for instruction in &self.instructions {
+            let line = instruction.to_string();
+            if !line.contains(':') {
+                assembly.push_str("    ");
+            }
+            assembly.push_str(&line);
+            assembly.push('\n');
+        }
+        assembly
+    }
+
+    // A relocatable ELF object, for linking with other tools
+    pub fn object(&self) -> Vec<u8> {
+        write_object(&Object::from_program(&self.instructions))
+    }
+
+    // A static ELF executable, ready to run
+    pub fn executable(&self) -> Vec<u8> {
+        write_executable(&Object::from_program(&self.instructions))
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Lexing => write!(f, "lexing"),
+            Phase::Parsing => write!(f, "parsing"),
+            Phase::Checking => write!(f, "checking"),
+            Phase::Codegen => write!(f, "codegen"),
+            Phase::Assembling => write!(f, "assembling"),
+            Phase::Linking => write!(f, "linking"),
+            Phase::Writing => write!(f, "writing"),
+            Phase::Running => write!(f, "running"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error while {}: {}", self.phase, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles_a_program() {
+        let artifact = compile(
+            "const int x = 4;\nconst int exit = x * 2;",
+            &Options::default(),
+        )
+        .expect("The program is valid");
+        assert!(artifact.assembly().contains("syscall"));
+        assert_eq!(&artifact.executable()[..4], b"\x7fELF");
+        let phases: Vec<&str> = artifact
+            .timings
+            .phases
+            .iter()
+            .map(|phase| phase.phase)
+            .collect();
+        assert_eq!(phases, ["optimisation", "stack allocation", "codegen"]);
+    }
+
+    #[test]
+    fn returns_reports_instead_of_printing_them() {
+        let options = Options {
+            opt_level: 2,
+            verbose: true,
+            ..Options::default()
+        };
+        let artifact =
+            compile("mut int x = 1;\nconst int exit = x;", &options).expect("The program is valid");
+        let reports = artifact.reports.concat();
+        assert!(reports.contains("dce: removed"), "{}", reports);
+        assert!(reports.contains("peephole: removed"), "{}", reports);
+        assert!(reports.contains("pass out-of-ssa:"), "{}", reports);
+        assert!(compile("const int exit = 0;", &Options::default())
+            .unwrap()
+            .reports
+            .is_empty());
+    }
+
+    #[test]
+    fn reports_problems_as_diagnostics() {
+        let diagnostics =
+            compile("const int exit = y;", &Options::default()).expect_err("y isn't declared");
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                phase: Phase::Parsing,
+                message: "Unrecognised identifier y in expr".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_every_problem_in_malformed_ir() {
+        let text = "mut x: int\n\nblock_0:\n    t1 = add t0, 1\n    exit t2\n";
+        let diagnostics = load_ir(text).expect_err("t0 and t2 are never defined");
+        assert!(diagnostics.len() >= 2, "{:?}", diagnostics);
+        assert!(diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.phase == Phase::Checking));
+    }
+}
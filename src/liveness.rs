@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+
+use crate::ir::{Function, Instruction, Operand, Temp};
+use crate::representations::{LiveRange, Symbol};
+
+// Anything that can hold a value between instructions: a named variable in memory, or a temp
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Value {
+    Variable(String),
+    Temp(Temp),
+}
+
+// A set of value indices stored one bit per value
+#[derive(Debug, Clone, PartialEq)]
+struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    fn new(size: usize) -> Self {
+        Self {
+            words: vec![0; size.div_ceil(64)],
+        }
+    }
+
+    fn insert(&mut self, index: usize) {
+        self.words[index / 64] |= 1 << (index % 64);
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.words[index / 64] &= !(1 << (index % 64));
+    }
+
+    fn union_with(&mut self, other: &BitSet) {
+        for (word, other_word) in self.words.iter_mut().zip(&other.words) {
+            *word |= other_word;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words
+            .iter()
+            .enumerate()
+            .flat_map(|(word_index, word)| {
+                (0..64)
+                    .filter(move |bit| word & (1 << bit) != 0)
+                    .map(move |bit| word_index * 64 + bit)
+            })
+    }
+}
+
+// The values read and written at a single point. Every instruction and every terminator is a
+// point, numbered in the order the blocks are laid out
+struct ProgramPoint {
+    uses: Vec<usize>,
+    defs: Vec<usize>,
+}
+
+// Compute the live range of every variable and temp in the function. Variable ranges are saved
+// into the symbol table for stack allocation, and temp ranges are returned for register
+// allocation
+pub fn analyse_liveness(
+    function: &Function,
+    symbol_table: &mut HashMap<String, Symbol>,
+) -> HashMap<Temp, LiveRange> {
+    let mut value_indices = HashMap::<Value, usize>::new();
+    let mut values = Vec::<Value>::new();
+    let mut index_of = |value: Value| -> usize {
+        *value_indices.entry(value.clone()).or_insert_with(|| {
+            values.push(value);
+            values.len() - 1
+        })
+    };
+
+    // Variables that have their address taken. A pointer to these can be read at any time, so
+    // they are treated as live for the whole program
+    let mut escaped = Vec::<String>::new();
+    let mut block_points = Vec::<Vec<ProgramPoint>>::new();
+    for block in &function.blocks {
+        let mut points = Vec::new();
+        for instruction in &block.instructions {
+            let mut uses: Vec<usize> = operand_temps(&instruction.operands())
+                .map(|temp| index_of(Value::Temp(temp)))
+                .collect();
+            let mut defs: Vec<usize> = instruction
+                .dest()
+                .map(|temp| index_of(Value::Temp(temp)))
+                .into_iter()
+                .collect();
+            match instruction {
+                Instruction::Load(_, variable) => {
+                    uses.push(index_of(Value::Variable(variable.clone())))
+                }
+                Instruction::Store(variable, _) => {
+                    defs.push(index_of(Value::Variable(variable.clone())))
+                }
+                Instruction::AddressOf(_, variable) => escaped.push(variable.clone()),
+                _ => (),
+            }
+            points.push(ProgramPoint { uses, defs });
+        }
+        let uses = operand_temps(&block.terminator.operands())
+            .map(|temp| index_of(Value::Temp(temp)))
+            .collect();
+        points.push(ProgramPoint { uses, defs: vec![] });
+        block_points.push(points);
+    }
+
+    let live_out = solve_liveness(function, &block_points, values.len());
+
+    // Walk each block backwards from its live out set, extending the range of every value that
+    // is live or written at each point
+    let mut ranges: Vec<Option<LiveRange>> = vec![None; values.len()];
+    let mut extend = |value: usize, point: usize| {
+        let range = ranges[value].get_or_insert(LiveRange {
+            start: point,
+            end: point,
+        });
+        range.start = range.start.min(point);
+        range.end = range.end.max(point);
+    };
+    let mut block_start = 0;
+    for (points, block_live_out) in block_points.iter().zip(&live_out) {
+        let mut live = block_live_out.clone();
+        for (offset, point) in points.iter().enumerate().rev() {
+            let point_index = block_start + offset;
+            for value in live.iter() {
+                extend(value, point_index);
+            }
+            for def in &point.defs {
+                extend(*def, point_index);
+                live.remove(*def);
+            }
+            for used in &point.uses {
+                extend(*used, point_index);
+                live.insert(*used);
+            }
+        }
+        block_start += points.len();
+    }
+    let last_point = block_start.saturating_sub(1);
+
+    for symbol_info in symbol_table.values_mut() {
+        symbol_info.live_range = None;
+    }
+    let mut temp_ranges = HashMap::new();
+    for (value, range) in values.into_iter().zip(ranges) {
+        match value {
+            Value::Temp(temp) => {
+                if let Some(range) = range {
+                    temp_ranges.insert(temp, range);
+                }
+            }
+            Value::Variable(variable) => {
+                symbol_table
+                    .get_mut(&variable)
+                    .expect("Every variable in the IR should be in the symbol table")
+                    .live_range = range;
+            }
+        }
+    }
+    for variable in escaped {
+        symbol_table
+            .get_mut(&variable)
+            .expect("Every variable in the IR should be in the symbol table")
+            .live_range = Some(LiveRange {
+            start: 0,
+            end: last_point,
+        });
+    }
+    temp_ranges
+}
+
+fn operand_temps(operands: &[Operand]) -> impl Iterator<Item = Temp> + '_ {
+    operands.iter().filter_map(|operand| operand.as_temp())
+}
+
+// Standard backwards dataflow over the blocks: a value is live into a block if the block reads
+// it before writing it, or if it is live out of the block and not written there. Iterate until
+// nothing changes. Returns the live out set of each block
+fn solve_liveness(
+    function: &Function,
+    block_points: &[Vec<ProgramPoint>],
+    value_count: usize,
+) -> Vec<BitSet> {
+    let block_indices: HashMap<_, _> = function
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(index, block)| (block.id, index))
+        .collect();
+
+    // The values each block reads before writing, and the values it writes
+    let mut upward_uses = Vec::new();
+    let mut block_defs = Vec::new();
+    for points in block_points {
+        let mut uses = BitSet::new(value_count);
+        let mut defs = BitSet::new(value_count);
+        for point in points.iter().rev() {
+            for def in &point.defs {
+                uses.remove(*def);
+                defs.insert(*def);
+            }
+            for used in &point.uses {
+                uses.insert(*used);
+            }
+        }
+        upward_uses.push(uses);
+        block_defs.push(defs);
+    }
+
+    let mut live_in = upward_uses.clone();
+    let mut live_out = vec![BitSet::new(value_count); function.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (index, block) in function.blocks.iter().enumerate().rev() {
+            let mut new_out = BitSet::new(value_count);
+            for successor in block.terminator.successors() {
+                new_out.union_with(&live_in[block_indices[&successor]]);
+            }
+            let mut new_in = new_out.clone();
+            for (word, def_word) in new_in.words.iter_mut().zip(&block_defs[index].words) {
+                *word &= !def_word;
+            }
+            new_in.union_with(&upward_uses[index]);
+            if new_in != live_in[index] || new_out != live_out[index] {
+                live_in[index] = new_in;
+                live_out[index] = new_out;
+                changed = true;
+            }
+        }
+    }
+    live_out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir_text::parse_ir;
+
+    #[test]
+    fn values_read_after_a_loop_stay_live_through_it() {
+        let (function, mut symbol_table) = parse_ir(
+            "mut i: int
+            mut total: int
+
+            block_0:
+                store total, 7
+                store i, 0
+                jump block_1
+            block_1:
+                t0 = load i
+                t1 = lt t0, 3
+                branch t1, block_2, block_3
+            block_2:
+                t2 = add t0, 1
+                store i, t2
+                jump block_1
+            block_3:
+                t3 = load total
+                exit t3",
+        );
+        let temp_ranges = analyse_liveness(&function, &mut symbol_table);
+        let total = symbol_table["total"].live_range.unwrap();
+        let i = symbol_table["i"].live_range.unwrap();
+        // total is written at the first point and read in the last block, so it spans the loop
+        assert_eq!(total.start, 0);
+        assert!(total.end >= i.end, "{:?} {:?}", total, i);
+        // t0 is read again by the add in the loop body
+        assert!(temp_ranges[&Temp(0)].end > temp_ranges[&Temp(1)].start);
+    }
+
+    #[test]
+    fn loop_carried_temps_stay_live_across_the_back_edge() {
+        // Out of SSA form, so t0 is redefined at the end of the loop body and read again by the
+        // header on the next iteration
+        let (function, mut symbol_table) = parse_ir(
+            "block_0:
+                t0 = copy 0
+                jump block_1
+            block_1:
+                t1 = lt t0, 5
+                branch t1, block_3, block_2
+            block_2:
+                exit 0
+            block_3:
+                t0 = add t0, 1
+                jump block_1",
+        );
+        let temp_ranges = analyse_liveness(&function, &mut symbol_table);
+        // The jump back to the header is the last point
+        assert_eq!(temp_ranges[&Temp(0)], LiveRange { start: 0, end: 6 });
+        assert_eq!(temp_ranges[&Temp(1)], LiveRange { start: 2, end: 3 });
+    }
+
+    #[test]
+    fn temps_used_in_both_arms_of_a_branch_reach_the_later_arm() {
+        let (function, mut symbol_table) = parse_ir(
+            "block_0:
+                t0 = copy 4
+                t1 = lt t0, 5
+                branch t1, block_1, block_2
+            block_1:
+                t2 = add t0, 1
+                exit t2
+            block_2:
+                t3 = add t0, 2
+                exit t3",
+        );
+        let temp_ranges = analyse_liveness(&function, &mut symbol_table);
+        assert_eq!(temp_ranges[&Temp(0)], LiveRange { start: 0, end: 5 });
+        assert_eq!(temp_ranges[&Temp(2)], LiveRange { start: 3, end: 4 });
+        assert_eq!(temp_ranges[&Temp(3)], LiveRange { start: 5, end: 6 });
+    }
+
+    #[test]
+    fn temps_that_are_never_read_only_cover_their_definition() {
+        let (function, mut symbol_table) = parse_ir(
+            "block_0:
+                t0 = copy 1
+                t1 = copy 2
+                exit t1",
+        );
+        let temp_ranges = analyse_liveness(&function, &mut symbol_table);
+        assert_eq!(temp_ranges[&Temp(0)], LiveRange { start: 0, end: 0 });
+        assert_eq!(temp_ranges[&Temp(1)], LiveRange { start: 1, end: 2 });
+    }
+}
@@ -0,0 +1,581 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::cfg::{ControlFlowGraph, DominatorTree};
+use crate::dead_code::has_side_effect;
+use crate::ir::{BasicBlock, BinaryOp, BlockId, Function, Instruction, Operand, Temp, Terminator};
+
+// What the loop optimisations did, so it can be reported in verbose mode
+#[derive(Debug, Default)]
+pub struct LoopReport {
+    pub loops: usize,
+    pub hoisted_instructions: usize,
+    pub reduced_multiplications: usize,
+}
+
+// A natural loop: the header dominates every block in the loop, and every block in the loop
+// can get back to the header without leaving it
+struct Loop {
+    header: BlockId,
+    blocks: HashSet<BlockId>,
+}
+
+// A phi in a loop header that goes up by the same constant every time around the loop
+#[derive(Clone, Copy)]
+struct InductionVariable {
+    initial: Operand,
+    step: i64,
+    // The value for the next iteration and the block it comes back from
+    next: Temp,
+    latch: BlockId,
+}
+
+impl fmt::Display for LoopReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "loops: found {} loops", self.loops)?;
+        writeln!(
+            f,
+            "loops: hoisted {} loop invariant instructions",
+            self.hoisted_instructions
+        )?;
+        writeln!(
+            f,
+            "loops: replaced {} induction variable multiplications with additions",
+            self.reduced_multiplications
+        )
+    }
+}
+
+// Find the loops in the function, then move everything that works out the same value on every
+// iteration into a block that runs once before the loop, and turn multiplications of an
+// induction variable by a constant into a running total. Runs on SSA form. Inner loops are
+// done first so that anything hoisted out of them can then be hoisted out of the loops around
+// them
+pub fn optimise_loops(function: &mut Function) -> LoopReport {
+    let cfg = ControlFlowGraph::new(function);
+    let dominator_tree = DominatorTree::new(&cfg);
+    let mut loops = find_loops(&cfg, &dominator_tree);
+    let mut report = LoopReport {
+        loops: loops.len(),
+        ..Default::default()
+    };
+
+    let mut preheaders = Vec::new();
+    for index in 0..loops.len() {
+        let preheader = insert_preheader(function, &cfg, &loops[index]);
+        // A new preheader belongs to every loop the loop is nested in
+        if let Some(preheader) = preheader {
+            let header = loops[index].header;
+            for outer in &mut loops[index + 1..] {
+                if outer.blocks.contains(&header) {
+                    outer.blocks.insert(preheader);
+                }
+            }
+        }
+        preheaders.push(preheader);
+    }
+
+    for (found_loop, preheader) in loops.iter().zip(preheaders) {
+        // A loop that starts at the entry has nowhere to hoist to
+        let Some(preheader) = preheader else {
+            continue;
+        };
+        report.hoisted_instructions += hoist_invariants(function, found_loop, preheader);
+        report.reduced_multiplications +=
+            reduce_induction_variables(function, found_loop, preheader);
+    }
+    report
+}
+
+// Every edge to a block that dominates where it comes from is a back edge, and the loop is every
+// block that can reach the back edge without going through the header. Loops sharing a header
+// are merged. Returns the loops with inner loops before the loops around them
+fn find_loops(cfg: &ControlFlowGraph, dominator_tree: &DominatorTree) -> Vec<Loop> {
+    let mut bodies = HashMap::<BlockId, HashSet<BlockId>>::new();
+    for block in &cfg.reverse_postorder {
+        for successor in &cfg.successors[block] {
+            if !dominator_tree.dominates(*successor, *block) {
+                continue;
+            }
+            let body = bodies
+                .entry(*successor)
+                .or_insert_with(|| HashSet::from([*successor]));
+            let mut worklist = vec![*block];
+            while let Some(body_block) = worklist.pop() {
+                if body.insert(body_block) {
+                    worklist.extend(&cfg.predecessors[&body_block]);
+                }
+            }
+        }
+    }
+    let mut loops: Vec<Loop> = bodies
+        .into_iter()
+        .map(|(header, blocks)| Loop { header, blocks })
+        .collect();
+    loops.sort_by_key(|found_loop| (found_loop.blocks.len(), found_loop.header));
+    loops
+}
+
+// Make sure the loop has a block that runs exactly once each time the loop is entered and
+// jumps straight to the header. A block before the loop that only jumps to the header is used
+// as it is, otherwise a new block is placed on every edge into the loop. Returns None if the
+// loop header is the entry block
+fn insert_preheader(
+    function: &mut Function,
+    cfg: &ControlFlowGraph,
+    found_loop: &Loop,
+) -> Option<BlockId> {
+    let header = found_loop.header;
+    let entering: Vec<BlockId> = cfg.predecessors[&header]
+        .iter()
+        .filter(|pred| !found_loop.blocks.contains(pred))
+        .copied()
+        .collect();
+    match entering.as_slice() {
+        [] => return None,
+        [pred] if cfg.successors[pred].len() == 1 => return Some(*pred),
+        _ => (),
+    }
+
+    let preheader = function.new_block_id();
+    for pred in &entering {
+        function
+            .block_mut(*pred)
+            .terminator
+            .replace_successor(header, preheader);
+    }
+    // The values each header phi takes from outside the loop now come through the preheader,
+    // merged by a phi of its own when there is more than one
+    let mut preheader_phis = Vec::new();
+    let mut header_instructions = std::mem::take(&mut function.block_mut(header).instructions);
+    for instruction in &mut header_instructions {
+        let Instruction::Phi(_, incoming) = instruction else {
+            break;
+        };
+        let (from_outside, from_loop): (Vec<_>, Vec<_>) = incoming
+            .drain(..)
+            .partition(|(pred, _)| entering.contains(pred));
+        let value = match from_outside.as_slice() {
+            [(_, value)] => *value,
+            _ => {
+                let merged = function.new_temp();
+                preheader_phis.push(Instruction::Phi(merged, from_outside));
+                Operand::Temp(merged)
+            }
+        };
+        *incoming = from_loop;
+        incoming.push((preheader, value));
+    }
+    function.block_mut(header).instructions = header_instructions;
+
+    let position = function
+        .blocks
+        .iter()
+        .position(|block| block.id == header)
+        .expect("Block is in the function");
+    function.blocks.insert(
+        position,
+        BasicBlock {
+            id: preheader,
+            instructions: preheader_phis,
+            terminator: Terminator::Jump(header),
+        },
+    );
+    Some(preheader)
+}
+
+// Move every instruction whose operands don't change inside the loop to the end of the
+// preheader. Only instructions that can't trap are moved, as the loop body might never run.
+// Returns the number of instructions moved
+fn hoist_invariants(function: &mut Function, found_loop: &Loop, preheader: BlockId) -> usize {
+    // Temps defined in the loop, and temps defined more than once, which only happens when SSA
+    // construction was disabled and can't be moved safely
+    let mut defined_in_loop = HashSet::<Temp>::new();
+    let mut redefined = HashSet::<Temp>::new();
+    let mut defined = HashSet::<Temp>::new();
+    let mut stored = HashSet::<String>::new();
+    let mut writes_through_pointer = false;
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            if let Some(dest) = instruction.dest() {
+                if !defined.insert(dest) {
+                    redefined.insert(dest);
+                }
+            }
+            if !found_loop.blocks.contains(&block.id) {
+                continue;
+            }
+            if let Some(dest) = instruction.dest() {
+                defined_in_loop.insert(dest);
+            }
+            match instruction {
+                Instruction::Store(id, _) => {
+                    stored.insert(id.clone());
+                }
+                Instruction::StorePointer(_, _) => writes_through_pointer = true,
+                _ => (),
+            }
+        }
+    }
+
+    // Keep going until no more instructions are found to be invariant, recording them in the
+    // order found so that each one comes after the instructions it depends on
+    let mut invariant = HashSet::<Temp>::new();
+    let mut order = Vec::<Temp>::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let loop_blocks = function
+            .blocks
+            .iter()
+            .filter(|block| found_loop.blocks.contains(&block.id));
+        for block in loop_blocks {
+            for instruction in &block.instructions {
+                let Some(dest) = instruction.dest() else {
+                    continue;
+                };
+                let can_hoist = match instruction {
+                    Instruction::Phi(_, _) | Instruction::LoadPointer(_, _) => false,
+                    Instruction::Load(_, id) => !writes_through_pointer && !stored.contains(id),
+                    _ => !has_side_effect(instruction),
+                };
+                if invariant.contains(&dest) || redefined.contains(&dest) || !can_hoist {
+                    continue;
+                }
+                let operands_invariant =
+                    instruction.operands().iter().all(|operand| match operand {
+                        Operand::Const(_) => true,
+                        Operand::Temp(temp) => {
+                            invariant.contains(temp) || !defined_in_loop.contains(temp)
+                        }
+                    });
+                if operands_invariant {
+                    invariant.insert(dest);
+                    order.push(dest);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let mut hoisted = HashMap::<Temp, Instruction>::new();
+    for block in &mut function.blocks {
+        if !found_loop.blocks.contains(&block.id) {
+            continue;
+        }
+        let (moving, staying): (Vec<_>, Vec<_>) =
+            block.instructions.drain(..).partition(|instruction| {
+                instruction
+                    .dest()
+                    .is_some_and(|dest| invariant.contains(&dest))
+            });
+        block.instructions = staying;
+        for instruction in moving {
+            let dest = instruction
+                .dest()
+                .expect("Only instructions with a dest are moved");
+            hoisted.insert(dest, instruction);
+        }
+    }
+    function.block_mut(preheader).instructions.extend(
+        order
+            .iter()
+            .map(|dest| hoisted.remove(dest).expect("Every temp was moved")),
+    );
+    order.len()
+}
+
+// Replace i * c, where i is an induction variable and c is a constant, with a new induction
+// variable that starts at the initial value of i * c and goes up by step * c each time around.
+// Wrapping arithmetic means the running total always matches the product. Returns the number of
+// multiplications replaced
+fn reduce_induction_variables(
+    function: &mut Function,
+    found_loop: &Loop,
+    preheader: BlockId,
+) -> usize {
+    let induction_variables = find_induction_variables(function, found_loop, preheader);
+    let mut multiplications = Vec::<(Temp, Temp, i64)>::new();
+    for block in &function.blocks {
+        if !found_loop.blocks.contains(&block.id) {
+            continue;
+        }
+        for instruction in &block.instructions {
+            if let Instruction::Binary(
+                dest,
+                BinaryOp::Mul,
+                Operand::Temp(variable),
+                Operand::Const(factor),
+            )
+            | Instruction::Binary(
+                dest,
+                BinaryOp::Mul,
+                Operand::Const(factor),
+                Operand::Temp(variable),
+            ) = instruction
+            {
+                if induction_variables.contains_key(variable) {
+                    multiplications.push((*dest, *variable, *factor));
+                }
+            }
+        }
+    }
+    if multiplications.is_empty() {
+        return 0;
+    }
+
+    // Multiplications of the same variable by the same factor share one running total
+    let mut totals = HashMap::<(Temp, i64), Temp>::new();
+    let mut replacements = HashMap::<Temp, Temp>::new();
+    for (dest, variable, factor) in multiplications {
+        let total = match totals.get(&(variable, factor)) {
+            Some(total) => *total,
+            None => {
+                let total = build_running_total(
+                    function,
+                    found_loop.header,
+                    preheader,
+                    induction_variables[&variable],
+                    factor,
+                );
+                totals.insert((variable, factor), total);
+                total
+            }
+        };
+        replacements.insert(dest, total);
+    }
+
+    for block in &mut function.blocks {
+        block.instructions.retain(|instruction| {
+            !instruction
+                .dest()
+                .is_some_and(|dest| replacements.contains_key(&dest))
+        });
+        let instruction_operands = block
+            .instructions
+            .iter_mut()
+            .flat_map(|instruction| instruction.operands_mut());
+        for operand in instruction_operands.chain(block.terminator.operands_mut()) {
+            if let Operand::Temp(temp) = operand {
+                if let Some(total) = replacements.get(temp) {
+                    *temp = *total;
+                }
+            }
+        }
+    }
+    replacements.len()
+}
+
+// Header phis with one value from the preheader and one from a single block in the loop, where
+// the value from the loop is the phi plus or minus a constant
+fn find_induction_variables(
+    function: &Function,
+    found_loop: &Loop,
+    preheader: BlockId,
+) -> HashMap<Temp, InductionVariable> {
+    let mut definitions = HashMap::<Temp, &Instruction>::new();
+    for block in &function.blocks {
+        if found_loop.blocks.contains(&block.id) {
+            for instruction in &block.instructions {
+                if let Some(dest) = instruction.dest() {
+                    definitions.insert(dest, instruction);
+                }
+            }
+        }
+    }
+
+    let mut induction_variables = HashMap::new();
+    let header = function.block(found_loop.header);
+    for instruction in header.instructions.iter().take_while(|i| i.is_phi()) {
+        let Instruction::Phi(phi, incoming) = instruction else {
+            continue;
+        };
+        let (initial, latch, next) = match incoming.as_slice() {
+            [(first, first_value), (second, Operand::Temp(second_value))]
+                if *first == preheader =>
+            {
+                (*first_value, *second, *second_value)
+            }
+            [(first, Operand::Temp(first_value)), (second, second_value)]
+                if *second == preheader =>
+            {
+                (*second_value, *first, *first_value)
+            }
+            _ => continue,
+        };
+        let step = match definitions.get(&next) {
+            Some(Instruction::Binary(
+                _,
+                BinaryOp::Add,
+                Operand::Temp(base),
+                Operand::Const(step),
+            ))
+            | Some(Instruction::Binary(
+                _,
+                BinaryOp::Add,
+                Operand::Const(step),
+                Operand::Temp(base),
+            )) if base == phi => *step,
+            Some(Instruction::Binary(
+                _,
+                BinaryOp::Sub,
+                Operand::Temp(base),
+                Operand::Const(step),
+            )) if base == phi => step.wrapping_neg(),
+            _ => continue,
+        };
+        induction_variables.insert(
+            *phi,
+            InductionVariable {
+                initial,
+                step,
+                next,
+                latch,
+            },
+        );
+    }
+    induction_variables
+}
+
+// Add a phi to the header that holds variable * factor, starting from the initial value worked
+// out in the preheader and increased straight after the variable is. Returns the phi's temp
+fn build_running_total(
+    function: &mut Function,
+    header: BlockId,
+    preheader: BlockId,
+    variable: InductionVariable,
+    factor: i64,
+) -> Temp {
+    let initial = match variable.initial {
+        Operand::Const(value) => Operand::Const(value.wrapping_mul(factor)),
+        Operand::Temp(_) => {
+            let initial = function.new_temp();
+            function
+                .block_mut(preheader)
+                .instructions
+                .push(Instruction::Binary(
+                    initial,
+                    BinaryOp::Mul,
+                    variable.initial,
+                    Operand::Const(factor),
+                ));
+            Operand::Temp(initial)
+        }
+    };
+    let total = function.new_temp();
+    let next_total = function.new_temp();
+    function.block_mut(header).instructions.insert(
+        0,
+        Instruction::Phi(
+            total,
+            vec![
+                (preheader, initial),
+                (variable.latch, Operand::Temp(next_total)),
+            ],
+        ),
+    );
+    let increment = Instruction::Binary(
+        next_total,
+        BinaryOp::Add,
+        Operand::Temp(total),
+        Operand::Const(variable.step.wrapping_mul(factor)),
+    );
+    for block in &mut function.blocks {
+        if let Some(position) = block
+            .instructions
+            .iter()
+            .position(|instruction| instruction.dest() == Some(variable.next))
+        {
+            block.instructions.insert(position + 1, increment);
+            break;
+        }
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir_text::parse_ir;
+
+    // i starts at 1 and goes up by n * 3 each time around until it reaches 100
+    const LOOP: &str = "mut n: int
+
+        block_0:
+            t0 = load n
+            jump block_1
+        block_1:
+            t1 = phi [block_0: 1], [block_2: t4]
+            t2 = lt t1, 100
+            branch t2, block_2, block_3
+        block_2:
+            t3 = mul t0, 3
+            t5 = div 10, t0
+            t6 = mul t1, 4
+            t4 = add t1, t3
+            jump block_1
+        block_3:
+            exit t1";
+
+    #[test]
+    fn hoists_invariants_that_cant_trap() {
+        let (mut function, _) = parse_ir(LOOP);
+        let report = optimise_loops(&mut function);
+        assert_eq!(report.loops, 1);
+        assert_eq!(report.hoisted_instructions, 1);
+        // block_0 only jumps to the header, so it is used as the preheader
+        assert_eq!(
+            function.block(BlockId(0)).instructions,
+            vec![
+                Instruction::Load(Temp(0), "n".to_string()),
+                Instruction::Binary(
+                    Temp(3),
+                    BinaryOp::Mul,
+                    Operand::Temp(Temp(0)),
+                    Operand::Const(3)
+                )
+            ]
+        );
+        // Dividing by n could trap, so it only happens if the loop body runs
+        assert!(function
+            .block(BlockId(2))
+            .instructions
+            .contains(&Instruction::Binary(
+                Temp(5),
+                BinaryOp::Div,
+                Operand::Const(10),
+                Operand::Temp(Temp(0))
+            )));
+    }
+
+    #[test]
+    fn leaves_variables_that_dont_step_by_a_constant() {
+        // i goes up by n * 3, which is the same every time around but not known, so i * 4 has
+        // no constant step to build a running total from
+        let (mut function, _) = parse_ir(LOOP);
+        let report = optimise_loops(&mut function);
+        assert_eq!(report.reduced_multiplications, 0);
+        assert!(function
+            .block(BlockId(2))
+            .instructions
+            .contains(&Instruction::Binary(
+                Temp(6),
+                BinaryOp::Mul,
+                Operand::Temp(Temp(1)),
+                Operand::Const(4)
+            )));
+
+        // Once the step is a constant the multiplication becomes a running total
+        let (mut function, _) = parse_ir(&LOOP.replace("t4 = add t1, t3", "t4 = add t1, 2"));
+        let report = optimise_loops(&mut function);
+        assert_eq!(report.reduced_multiplications, 1);
+        assert!(!function
+            .blocks
+            .iter()
+            .flat_map(|block| &block.instructions)
+            .any(|instruction| matches!(
+                instruction,
+                Instruction::Binary(_, BinaryOp::Mul, Operand::Temp(Temp(1)), _)
+            )));
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::ir::{
+    BasicBlock, BinaryOp, BlockId, Function, Instruction, Operand, Terminator, UnaryOp,
+};
+use crate::representations::{
+    Assignment, Block, Expression, List, Literal, Statement, Symbol, Token, Type,
+};
+
+// Holds the function being built along with the block that instructions are currently being
+// added to. The current block is pushed into the function once it gets a terminator
+struct Lowerer<'a> {
+    function: Function,
+    current_id: BlockId,
+    current_instructions: Vec<Instruction>,
+    // The block that each enclosing while loop exits to, for breaks
+    loop_exits: Vec<BlockId>,
+    symbol_table: &'a mut HashMap<String, Symbol>,
+    list_counter: u32,
+}
+
+// Lower a list of statements into IR: module entry point
+pub fn lower(
+    statements: &VecDeque<Statement>,
+    symbol_table: &mut HashMap<String, Symbol>,
+) -> Function {
+    let exit_symbol = get_exit_symbol(symbol_table);
+    let mut function = Function {
+        blocks: Vec::new(),
+        temp_count: 0,
+        block_count: 0,
+    };
+    let entry = function.new_block_id();
+    let mut lowerer = Lowerer {
+        function,
+        current_id: entry,
+        current_instructions: Vec::new(),
+        loop_exits: Vec::new(),
+        symbol_table,
+        list_counter: 1,
+    };
+    for stmt in statements {
+        lowerer.lower_statement(stmt);
+    }
+    // FOR DEBUG ONLY: the last variable assigned acts as the exit code of the program
+    let exit_value = lowerer.function.new_temp();
+    lowerer
+        .current_instructions
+        .push(Instruction::Load(exit_value, exit_symbol));
+    lowerer.finish_block(Terminator::Exit(Operand::Temp(exit_value)));
+    lowerer.function
+}
+
+// The last variable to be assigned is used as the exit code of the program. Ties are broken by
+// name so the same symbol is picked every time
+pub fn get_exit_symbol(symbol_table: &HashMap<String, Symbol>) -> String {
+    symbol_table
+        .iter()
+        .max_by(|(a_id, a), (b_id, b)| a.last_ref.cmp(&b.last_ref).then(b_id.cmp(a_id)))
+        .expect("Should always be at least 1 var in program")
+        .0
+        .clone()
+}
+
+impl Lowerer<'_> {
+    // End the current block with a terminator and push it into the function
+    fn finish_block(&mut self, terminator: Terminator) {
+        let instructions = std::mem::take(&mut self.current_instructions);
+        self.function.blocks.push(BasicBlock {
+            id: self.current_id,
+            instructions,
+            terminator,
+        });
+    }
+
+    // End the current block and start adding instructions to the next one
+    fn finish_and_start_block(&mut self, terminator: Terminator, next: BlockId) {
+        self.finish_block(terminator);
+        self.current_id = next;
+    }
+
+    fn lower_statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Assignment(assign_type, expr) => {
+                let value = self.lower_expression(expr);
+                let id = match assign_type {
+                    Assignment::Value(_, id)
+                    | Assignment::Pointer(_, id)
+                    | Assignment::Mutation(id) => id,
+                };
+                self.current_instructions
+                    .push(Instruction::Store(id.clone(), value));
+            }
+            Statement::If(expr, if_block) => {
+                let if_id = self.function.new_block_id();
+                let end_id = self.function.new_block_id();
+                let condition = self.lower_expression(expr);
+                self.finish_and_start_block(Terminator::Branch(condition, if_id, end_id), if_id);
+                self.lower_statement(if_block);
+                self.finish_and_start_block(Terminator::Jump(end_id), end_id);
+            }
+            Statement::IfElse(expr, if_block, else_block) => {
+                let if_id = self.function.new_block_id();
+                let else_id = self.function.new_block_id();
+                let end_id = self.function.new_block_id();
+                let condition = self.lower_expression(expr);
+                self.finish_and_start_block(Terminator::Branch(condition, if_id, else_id), if_id);
+                self.lower_statement(if_block);
+                self.finish_and_start_block(Terminator::Jump(end_id), else_id);
+                self.lower_statement(else_block);
+                self.finish_and_start_block(Terminator::Jump(end_id), end_id);
+            }
+            Statement::Block(block) => self.lower_block(block),
+            Statement::While(expr, while_block) => {
+                let start_id = self.function.new_block_id();
+                let body_id = self.function.new_block_id();
+                let end_id = self.function.new_block_id();
+                self.finish_and_start_block(Terminator::Jump(start_id), start_id);
+                let condition = self.lower_expression(expr);
+                self.finish_and_start_block(
+                    Terminator::Branch(condition, body_id, end_id),
+                    body_id,
+                );
+                self.loop_exits.push(end_id);
+                self.lower_statement(while_block);
+                self.loop_exits.pop();
+                self.finish_and_start_block(Terminator::Jump(start_id), end_id);
+            }
+            Statement::Break => {
+                let end_id = *self
+                    .loop_exits
+                    .last()
+                    .expect("Break outside of a while loop should be caught by the parser");
+                // Anything after the break is unreachable but still needs a block to live in
+                let unreachable_id = self.function.new_block_id();
+                self.finish_and_start_block(Terminator::Jump(end_id), unreachable_id);
+            }
+        }
+    }
+
+    fn lower_block(&mut self, block: &Block) {
+        match block {
+            Block::Statement(stmt) => self.lower_statement(stmt),
+            Block::Block(stmt, block) => {
+                self.lower_statement(stmt);
+                self.lower_block(block);
+            }
+        }
+    }
+
+    fn lower_expression(&mut self, expr: &Expression) -> Operand {
+        match expr {
+            Expression::Binary(left, op, right) => {
+                let left = self.lower_expression(left);
+                let right = self.lower_expression(right);
+                let dest = self.function.new_temp();
+                self.current_instructions.push(Instruction::Binary(
+                    dest,
+                    get_binary_op(op),
+                    left,
+                    right,
+                ));
+                Operand::Temp(dest)
+            }
+            Expression::Unary(op, inner) => match op.lexeme() {
+                "-" => {
+                    let inner = self.lower_expression(inner);
+                    let dest = self.function.new_temp();
+                    self.current_instructions
+                        .push(Instruction::Unary(dest, UnaryOp::Neg, inner));
+                    Operand::Temp(dest)
+                }
+                "&" => match inner.as_ref() {
+                    Expression::Literal(Literal::Symbol(token)) => {
+                        let dest = self.function.new_temp();
+                        self.current_instructions
+                            .push(Instruction::AddressOf(dest, token.lexeme().to_string()));
+                        Operand::Temp(dest)
+                    }
+                    _ => panic!(
+                        "Attempted to reference a non-memory location: this should never happen!"
+                    ),
+                },
+                "*" => {
+                    let address = self.lower_expression(inner);
+                    let dest = self.function.new_temp();
+                    self.current_instructions
+                        .push(Instruction::LoadPointer(dest, address));
+                    Operand::Temp(dest)
+                }
+                _ => panic!("Unrecognised unary op {}", op.lexeme()),
+            },
+            Expression::Literal(literal) => self.lower_literal(literal),
+            // A group just recurses straight away
+            Expression::Group(_, inner, _) => self.lower_expression(inner),
+        }
+    }
+
+    fn lower_literal(&mut self, literal: &Literal) -> Operand {
+        match literal {
+            Literal::Int(token) => Operand::Const(
+                token
+                    .lexeme()
+                    .parse()
+                    .unwrap_or_else(|_| panic!("{} is not a valid int literal", token.lexeme())),
+            ),
+            Literal::Bool(token) => Operand::Const(bool_to_int(token.lexeme())),
+            Literal::Symbol(token) => {
+                if !self.symbol_table.contains_key(token.lexeme()) {
+                    panic!("{} is referenced before declaration", token.lexeme())
+                }
+                let dest = self.function.new_temp();
+                self.current_instructions
+                    .push(Instruction::Load(dest, token.lexeme().to_string()));
+                Operand::Temp(dest)
+            }
+            Literal::List(list) => self.lower_list(list),
+        }
+    }
+
+    // A list literal gets its own anonymous variable to hold the elements, and evaluates to
+    // the address of the first element
+    fn lower_list(&mut self, list_literal: &List) -> Operand {
+        let mut elements = Vec::new();
+        let mut list = list_literal;
+        loop {
+            match list {
+                List::Literal(literal) => {
+                    elements.push(self.lower_literal(literal));
+                    break;
+                }
+                List::List(literal, next) => {
+                    elements.push(self.lower_literal(literal));
+                    list = next;
+                }
+            }
+        }
+
+        // '.' can't appear in an identifier so this never clashes with a user variable
+        let storage = format!("list.{}", self.list_counter);
+        self.list_counter += 1;
+        self.symbol_table.insert(
+            storage.clone(),
+            Symbol {
+                stack_offset: None,
+                _type: self.get_list_type(list_literal),
+                mutable: false,
+                // Anonymous storage is never picked as the exit symbol
+                init_line: 0,
+                last_ref: 0,
+                live_range: None,
+            },
+        );
+
+        let base = self.function.new_temp();
+        self.current_instructions
+            .push(Instruction::AddressOf(base, storage));
+        for (index, element) in elements.into_iter().enumerate() {
+            let address = if index == 0 {
+                Operand::Temp(base)
+            } else {
+                let address = self.function.new_temp();
+                self.current_instructions.push(Instruction::Binary(
+                    address,
+                    BinaryOp::Add,
+                    Operand::Temp(base),
+                    Operand::Const(8 * index as i64),
+                ));
+                Operand::Temp(address)
+            };
+            self.current_instructions
+                .push(Instruction::StorePointer(address, element));
+        }
+        Operand::Temp(base)
+    }
+}
+
+impl Lowerer<'_> {
+    fn get_list_type(&self, list: &List) -> Type {
+        let (first, mut length) = match list {
+            List::Literal(literal) => (literal, 1),
+            List::List(literal, _) => (literal, 1),
+        };
+        let mut next = list;
+        while let List::List(_, rest) = next {
+            length += 1;
+            next = rest;
+        }
+        let element_type = match first {
+            Literal::Int(_) => Type::Int,
+            Literal::Bool(_) => Type::Bool,
+            Literal::Symbol(token) => self
+                .symbol_table
+                .get(token.lexeme())
+                .expect("Symbols in a list should already be declared")
+                ._type
+                .clone(),
+            Literal::List(inner) => Type::Pointer(Box::new(self.get_list_type(inner))),
+        };
+        Type::Array(Box::new(element_type), length)
+    }
+}
+
+fn get_binary_op(op: &Token) -> BinaryOp {
+    match op.lexeme() {
+        "+" => BinaryOp::Add,
+        "-" => BinaryOp::Sub,
+        "*" => BinaryOp::Mul,
+        "/" => BinaryOp::Div,
+        "%" => BinaryOp::Mod,
+        "==" => BinaryOp::Eq,
+        "!=" => BinaryOp::Ne,
+        "<" => BinaryOp::Lt,
+        ">" => BinaryOp::Gt,
+        "<=" => BinaryOp::Le,
+        ">=" => BinaryOp::Ge,
+        // Other types of op that aren't implemented yet like ^ etc
+        _ => panic!("Can't handle {} yet", op.lexeme()),
+    }
+}
+
+fn bool_to_int(bool: &str) -> i64 {
+    match bool {
+        "true" => 1,
+        "false" => 0,
+        _ => panic!("{} is not a bool value!", bool),
+    }
+}
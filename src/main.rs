@@ -1,81 +1,566 @@
-pub mod ast_printer;
-pub mod backend;
-pub mod lexer;
-pub mod parser;
-pub mod representations;
-
-use crate::ast_printer::statement_pretty_printer;
-use crate::backend::build;
-use crate::lexer::lexer;
-use crate::parser::parse_tokens;
-use std::collections::HashMap;
-use std::fs::File;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 use std::io::Write;
-use std::path::PathBuf;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::time::Instant;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use testcomp::backend::ProgramExit;
+use testcomp::difftest::run_difftest;
+use testcomp::elf::{write_executable, Object};
+use testcomp::generator::generate;
+use testcomp::interp::interpret;
+use testcomp::jit::run_jit;
+use testcomp::lexer::lexer;
+use testcomp::passes::{DISABLEABLE_PASS_NAMES, PASS_NAMES};
+use testcomp::reduce::{reduce, CommandPredicate};
+use testcomp::repl::run_repl;
+use testcomp::sandbox::{run_sandboxed, Limits};
+use testcomp::timing::{PhaseTime, TimeReport};
+use testcomp::toolchain::{ExternalToolchain, TempDir};
+use testcomp::trace::{self, Subsystem, SUBSYSTEM_NAMES};
+use testcomp::{
+    check, codegen, instruction_count, lex, load_ir, parse, Artifact, Diagnostic, Options, Phase,
+};
 
-fn main() {
-    let now = Instant::now();
+// How often watch looks for changes to the program
+const WATCH_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Parser)]
+#[command(
+    about = "Compiles a .ttc program to x86-64, and runs it with --run",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[command(flatten)]
+    options: CompileArgs,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Compile a program and run it
+    Run {
+        /// Run the program inside the compiler's process instead of writing an executable
+        #[arg(long)]
+        jit: bool,
+
+        /// Interpret the program's syntax tree instead of compiling it
+        #[arg(long, conflicts_with = "jit")]
+        interp: bool,
+
+        #[command(flatten)]
+        options: CompileArgs,
+    },
+    /// Recompile a program, and rerun it with --run, every time it changes
+    Watch {
+        #[command(flatten)]
+        options: CompileArgs,
+    },
+    /// Enter statements and expressions one at a time and see what they do
+    Repl,
+    /// Check that compiled programs exit the same way as the interpreter says they should
+    Difftest {
+        /// Programs to check, or directories to search for .ttc files
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Optimisation levels to compile at, all of them by default
+        #[arg(short = 'O', value_parser = clap::value_parser!(u8).range(0..=2))]
+        opt_levels: Vec<u8>,
+    },
+    /// Write a random program that the compiler should accept
+    Generate {
+        /// Programs generated from the same seed and size are identical
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Roughly how many statements the program has
+        #[arg(long, default_value_t = 20)]
+        size: usize,
+
+        /// Where to write the program, instead of printing it
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Shrink a program that makes the compiler go wrong to a small one that still does
+    Reduce {
+        /// The program to shrink
+        file_path: PathBuf,
+
+        /// A shell command that exits with status 0 when a program still goes wrong. The program's
+        /// path replaces {} in the command, or is added to the end if there is no {}
+        #[arg(long)]
+        predicate: String,
+
+        /// Where to write the reduced program, next to the original by default
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+struct CompileArgs {
+    /// The source file to compile, or a .ir file holding a program's IR
+    #[arg(default_value = "test.ttc")]
+    file_path: PathBuf,
+
+    /// Optimisation level: 0 emits the program as written, 1 folds constants, removes dead code
+    /// and cleans up the generated assembly, 2 also optimises loops
+    #[arg(short = 'O', default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2))]
+    opt_level: u8,
+
+    /// Print the program after the named pass runs
+    #[arg(long, value_name = "PASS", value_parser = PASS_NAMES)]
+    print_after: Vec<String>,
+
+    /// Skip the named pass
+    #[arg(long, value_name = "PASS", value_parser = DISABLEABLE_PASS_NAMES)]
+    disable_pass: Vec<String>,
+
+    /// Report what the optimisations did and how long each pass took
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Print debugging output from the named parts of the compiler to stderr: every token, the
+    /// parser's lookaheads and types, each statement's tree, or where every variable ended up
+    #[arg(long, value_name = "SUBSYSTEM", value_delimiter = ',', value_parser = SUBSYSTEM_NAMES)]
+    trace: Vec<String>,
 
-    let file_path = PathBuf::from("/home/aidan/PROJECTS/testcomp/test.ttc"); // for debug! change this
-    let raw_code = std::fs::read_to_string(file_path).unwrap();
+    /// Also write the assembly to the output path with an .asm extension, or write the optimised
+    /// IR to the output path with an .ir extension instead of building an executable
+    #[arg(long, value_enum)]
+    emit: Option<Emit>,
 
-    let compiler_time = Instant::now();
+    /// How the assembly becomes an executable: written directly as ELF, or assembled and linked
+    /// by an external assembler and linker
+    #[arg(long, value_enum, default_value_t = Toolchain::Builtin)]
+    toolchain: Toolchain,
 
-    let mut lexed_line: std::collections::VecDeque<representations::Token> = lexer(raw_code);
+    /// Where to write the executable
+    #[arg(short, long, default_value = "test")]
+    output: PathBuf,
 
-    let mut symbol_table = HashMap::<String, representations::Symbol>::new();
-    let mut statements = parse_tokens(&mut lexed_line, &mut symbol_table);
+    /// The assembler to use with --toolchain nasm
+    #[arg(long, value_name = "PATH", default_value = "nasm")]
+    assembler: PathBuf,
 
-    println!("\n\n");
-    for s in &statements {
-        statement_pretty_printer(s);
-        println!("\n")
+    /// An extra flag for the assembler, after the ones asking for a 64 bit ELF object
+    #[arg(long, value_name = "FLAG", allow_hyphen_values = true)]
+    assembler_flag: Vec<String>,
+
+    /// The linker to use with --toolchain nasm
+    #[arg(long, value_name = "PATH", default_value = "ld")]
+    linker: PathBuf,
+
+    /// An extra flag for the linker, after the ones asking for an x86-64 executable
+    #[arg(long, value_name = "FLAG", allow_hyphen_values = true)]
+    linker_flag: Vec<String>,
+
+    /// Run the executable once it's written, stopping it if it goes over the limits
+    #[arg(long)]
+    run: bool,
+
+    /// Seconds the program can run for before it's killed
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    timeout: u64,
+
+    /// Seconds of CPU time the program can use
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    cpu_limit: u64,
+
+    /// Megabytes of memory the program can map
+    #[arg(long, value_name = "MEGABYTES", default_value_t = 256)]
+    memory_limit: u64,
+
+    /// Megabytes of stack the program can use
+    #[arg(long, value_name = "MEGABYTES", default_value_t = 8)]
+    stack_limit: u64,
+
+    /// Report how long each phase took and how much it produced on stderr, as a table or, with
+    /// --time-passes=json, as JSON
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "table"
+    )]
+    time_passes: Option<TimeFormat>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    Asm,
+    Ir,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Toolchain {
+    Builtin,
+    Nasm,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TimeFormat {
+    Table,
+    Json,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let (args, jit, interp) = match cli.command {
+        Some(Commands::Run {
+            jit,
+            interp,
+            options,
+        }) => {
+            if interp && is_ir(&options.file_path) {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        "--interp only runs source programs, not IR",
+                    )
+                    .exit();
+            }
+            (
+                CompileArgs {
+                    run: true,
+                    ..options
+                },
+                jit,
+                interp,
+            )
+        }
+        Some(Commands::Watch { options }) => {
+            enable_traces(&options.trace);
+            watch(&options);
+        }
+        Some(Commands::Repl) => {
+            run_repl();
+            return;
+        }
+        Some(Commands::Difftest { paths, opt_levels }) => {
+            let opt_levels = if opt_levels.is_empty() {
+                vec![0, 1, 2]
+            } else {
+                opt_levels
+            };
+            let divergences = run_difftest(&paths, &opt_levels);
+            for divergence in &divergences {
+                divergence.print();
+            }
+            if !divergences.is_empty() {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Commands::Generate { seed, size, output }) => {
+            let program = generate(seed, size);
+            match output {
+                Some(path) => std::fs::write(&path, program).unwrap_or_else(|error| {
+                    exit_with_error(format!("couldn't write {}: {}", path.display(), error))
+                }),
+                None => print!("{}", program),
+            }
+            return;
+        }
+        Some(Commands::Reduce {
+            file_path,
+            predicate,
+            output,
+        }) => {
+            let source = std::fs::read_to_string(&file_path).unwrap_or_else(|error| {
+                exit_with_error(format!("couldn't read {}: {}", file_path.display(), error))
+            });
+            let predicate = CommandPredicate::new(&predicate).unwrap_or_else(|error| {
+                exit_with_error(format!("couldn't set up the predicate: {}", error))
+            });
+            let still_fails = |candidate: &str| {
+                predicate.still_fails(candidate).unwrap_or_else(|error| {
+                    exit_with_error(format!("couldn't run the predicate: {}", error))
+                })
+            };
+            if !still_fails(&source) {
+                exit_with_error(format!(
+                    "the predicate doesn't hold for {}, so there is nothing to reduce",
+                    file_path.display()
+                ));
+            }
+            let reduced = reduce(&source, still_fails);
+            let output = output.unwrap_or_else(|| file_path.with_extension("reduced.ttc"));
+            std::fs::write(&output, &reduced).unwrap_or_else(|error| {
+                exit_with_error(format!("couldn't write {}: {}", output.display(), error))
+            });
+            println!(
+                "Reduced {} from {} to {} tokens, written to {}",
+                file_path.display(),
+                lexer(source).len(),
+                lexer(reduced).len(),
+                output.display()
+            );
+            return;
+        }
+        None => (cli.options, false, false),
+    };
+    enable_traces(&args.trace);
+    compile_and_run(&args, jit, interp)
+        .unwrap_or_else(|diagnostics| report_diagnostics(diagnostics));
+}
+
+// Report a problem with the arguments or files a command was given, outside of compiling
+fn exit_with_error(message: String) -> ! {
+    eprintln!("error: {}", message);
+    std::process::exit(1)
+}
+
+fn enable_traces(names: &[String]) {
+    for name in names {
+        trace::enable(Subsystem::from_name(name));
     }
+}
 
-    let asm_lines = build(&mut statements, &mut symbol_table);
+// Compile a program every time it changes, until the watch is stopped with Ctrl-C. Problems are
+// reported as soon as they are found and the watch carries on
+fn watch(args: &CompileArgs) -> ! {
+    // Only the program itself, as the language has no imports yet
+    let watched = vec![args.file_path.clone()];
+    let mut last_modified = modification_times(&watched);
+    loop {
+        // Clear the screen and move the cursor back to the top
+        print!("\x1b[2J\x1b[H");
+        println!("Compiling {}\n", args.file_path.display());
+        let _ = std::io::stdout().flush();
+        if let Err(diagnostics) = compile_and_run(args, false, false) {
+            for diagnostic in diagnostics {
+                eprintln!("{}", diagnostic);
+            }
+        }
+        println!("\nWatching {} for changes", args.file_path.display());
+        loop {
+            thread::sleep(WATCH_INTERVAL);
+            let modified = modification_times(&watched);
+            if modified != last_modified {
+                last_modified = modified;
+                break;
+            }
+        }
+    }
+}
+
+// None for a file that can't be looked at, which counts as a change when it comes back
+fn modification_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+fn is_ir(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "ir")
+}
 
-    println!("\n{:#?}\n", symbol_table);
-    let comp_time = compiler_time.elapsed();
+// Compile a program and, if asked to, run it
+fn compile_and_run(args: &CompileArgs, jit: bool, interp: bool) -> Result<(), Vec<Diagnostic>> {
+    let raw_code = std::fs::read_to_string(&args.file_path).map_err(|error| {
+        vec![Diagnostic {
+            phase: Phase::Lexing,
+            message: format!("couldn't read {}: {}", args.file_path.display(), error),
+        }]
+    })?;
 
-    let output_file_path = PathBuf::from("test.asm");
-    let mut output_string: String = r#"global _start
+    let options = Options {
+        opt_level: args.opt_level,
+        disabled_passes: args.disable_pass.clone(),
+        print_after: args.print_after.clone(),
+        verbose: args.verbose,
+        program_exit: if jit {
+            ProgramExit::Return
+        } else {
+            ProgramExit::Syscall
+        },
+    };
 
-section .text
+    let mut timings = TimeReport::new();
+    let ir = if is_ir(&args.file_path) {
+        if interp {
+            return Err(vec![Diagnostic {
+                phase: Phase::Parsing,
+                message: "only source programs can be interpreted, not IR".to_string(),
+            }]);
+        }
+        let start = Instant::now();
+        let ir = load_ir(&raw_code)?;
+        let count = instruction_count(&ir.function);
+        timings.record("parsing", start, Some((count, "IR instructions")));
+        ir
+    } else {
+        let start = Instant::now();
+        let tokens = lex(&raw_code)?;
+        timings.record("lexing", start, Some((tokens.len(), "tokens")));
 
-_start:
-"#
-    .to_string();
+        let start = Instant::now();
+        let program = parse(tokens)?;
+        let count = program.statements.len();
+        timings.record("parsing", start, Some((count, "statements")));
 
-    for line in asm_lines {
-        if !line.contains(":") {
-            output_string.push_str("    ");
+        if interp {
+            let start = Instant::now();
+            match interpret(&program.statements, &program.symbol_table) {
+                Ok(exit_value) => println!("Exit value: {}\n", exit_value),
+                Err(trap) => println!("Program trapped: {}\n", trap),
+            }
+            timings.record("running", start, None);
+            print_timings(args.time_passes, &timings);
+            return Ok(());
         }
-        output_string.push_str(line.as_str());
-        output_string.push_str("\n");
+
+        let start = Instant::now();
+        let ir = check(program)?;
+        let count = instruction_count(&ir.function);
+        timings.record("checking", start, Some((count, "IR instructions")));
+        ir
+    };
+    let artifact = codegen(ir, &options)?;
+    for report in &artifact.reports {
+        print!("{}", report);
+    }
+    timings.append(artifact.timings.clone());
+    if args.emit == Some(Emit::Ir) {
+        write_file(&args.output.with_extension("ir"), artifact.ir.as_bytes())?;
+        print_timings(args.time_passes, &timings);
+        return Ok(());
+    }
+    if args.emit == Some(Emit::Asm) {
+        write_file(
+            &args.output.with_extension("asm"),
+            artifact.assembly().as_bytes(),
+        )?;
+    }
+    if jit {
+        let start = Instant::now();
+        let exit_value = run_jit(&artifact.instructions);
+        timings.record("running", start, None);
+        println!("Exit value: {}\n", exit_value);
+        print_timings(args.time_passes, &timings);
+        return Ok(());
     }
 
-    output_string.push_str(
-        format!(
-            r#"    mov rax, 60
-    syscall"#
+    match args.toolchain {
+        Toolchain::Builtin => {
+            let start = Instant::now();
+            let object = Object::from_program(&artifact.instructions);
+            let code_size = object.text.len();
+            timings.record("assembling", start, Some((code_size, "bytes of code")));
+
+            let start = Instant::now();
+            let executable = write_executable(&object);
+            write_file(&args.output, &executable)?;
+            std::fs::set_permissions(&args.output, std::fs::Permissions::from_mode(0o755))
+                .map_err(|error| {
+                    vec![Diagnostic {
+                        phase: Phase::Writing,
+                        message: format!(
+                            "couldn't make {} executable: {}",
+                            args.output.display(),
+                            error
+                        ),
+                    }]
+                })?;
+            timings.record("linking", start, Some((executable.len(), "bytes")));
+        }
+        Toolchain::Nasm => {
+            let toolchain = ExternalToolchain {
+                assembler: args.assembler.clone(),
+                assembler_flags: args.assembler_flag.clone(),
+                linker: args.linker.clone(),
+                linker_flags: args.linker_flag.clone(),
+            };
+            build_externally(&toolchain, &artifact, &args.output, &mut timings)?;
+        }
+    }
+    if args.run {
+        let limits = Limits {
+            timeout: Duration::from_secs(args.timeout),
+            cpu_seconds: args.cpu_limit,
+            memory_bytes: args.memory_limit << 20,
+            stack_bytes: args.stack_limit << 20,
+        };
+        // A bare file name would be looked up on PATH
+        let result = run_sandboxed(
+            &mut Command::new(Path::new(".").join(&args.output)),
+            &limits,
         )
-        .as_str(),
-    );
+        .map_err(|error| {
+            vec![Diagnostic {
+                phase: Phase::Running,
+                message: format!("couldn't run {}: {}", args.output.display(), error),
+            }]
+        })?;
+        timings.phases.push(PhaseTime {
+            phase: "running",
+            duration: result.duration,
+            items: None,
+        });
+        print!("{}", String::from_utf8_lossy(&result.stdout));
+        eprint!("{}", String::from_utf8_lossy(&result.stderr));
+        println!("{}\n", result);
+    }
 
-    let mut output_file = File::create(output_file_path).expect("should work");
-    output_file
-        .write(output_string.as_bytes())
-        .expect("shopulfd work");
+    print_timings(args.time_passes, &timings);
+    Ok(())
+}
 
-    let _ = Command::new("make").status().unwrap();
-    let compiled_status = Command::new("./test").status().unwrap();
+fn write_file(path: &Path, contents: &[u8]) -> Result<(), Vec<Diagnostic>> {
+    std::fs::write(path, contents).map_err(|error| {
+        vec![Diagnostic {
+            phase: Phase::Writing,
+            message: format!("couldn't write {}: {}", path.display(), error),
+        }]
+    })
+}
 
-    println!("{}\n", compiled_status);
+// Assemble and link a program with external tools, in a temporary directory that is gone by the
+// time any problem is reported
+fn build_externally(
+    toolchain: &ExternalToolchain,
+    artifact: &Artifact,
+    output: &Path,
+    timings: &mut TimeReport,
+) -> Result<(), Vec<Diagnostic>> {
+    let directory = TempDir::new("build");
 
-    let elapsed = now.elapsed();
+    let start = Instant::now();
+    let object = toolchain.assemble(&artifact.assembly(), &directory.path)?;
+    timings.record("assembling", start, None);
+
+    let start = Instant::now();
+    toolchain.link(&object, output)?;
+    timings.record("linking", start, None);
+    Ok(())
+}
 
-    println!("Total: {:.2?}", elapsed);
-    println!("Comp only: {:.2?}", comp_time);
+// Report how long each phase took to stderr, so that it can be collected apart from what the
+// program printed
+fn print_timings(format: Option<TimeFormat>, timings: &TimeReport) {
+    match format {
+        Some(TimeFormat::Table) => eprint!("{}", timings.table()),
+        Some(TimeFormat::Json) => eprint!("{}", timings.json()),
+        None => (),
+    }
+}
+
+// The program can't be compiled, so say why and stop
+fn report_diagnostics(diagnostics: Vec<Diagnostic>) -> ! {
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+    std::process::exit(1);
 }
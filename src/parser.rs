@@ -3,6 +3,7 @@ use crate::representations::{
     Type,
 };
 
+use crate::trace;
 use core::panic;
 use std::collections::{HashMap, VecDeque};
 
@@ -72,11 +73,14 @@ fn parse_statement(
                                                 mutable: false,
                                                 init_line: *token.line_number(),
                                                 last_ref: *token.line_number(),
+                                                live_range: None,
                                             },
                                         );
                                         let assign_type = match statement_type {
-                                            Type::Pointer(_) => Assignment::Pointer(statement_type, identifier),
-                                            _ => Assignment::Value(statement_type, identifier)
+                                            Type::Pointer(_) => {
+                                                Assignment::Pointer(statement_type, identifier)
+                                            }
+                                            _ => Assignment::Value(statement_type, identifier),
                                         };
                                         Statement::Assignment(assign_type, expr)
                                     }
@@ -122,11 +126,12 @@ fn parse_statement(
                                                 mutable: true,
                                                 init_line: *token.line_number(),
                                                 last_ref: *token.line_number(),
+                                                live_range: None,
                                             },
                                         );
                                         let assign_type = match stmt_type {
                                             Type::Pointer(_) => Assignment::Pointer(stmt_type, id),
-                                            _ => Assignment::Value(stmt_type, id)
+                                            _ => Assignment::Value(stmt_type, id),
                                         };
                                         Statement::Assignment(assign_type, expr)
                                     }
@@ -238,6 +243,7 @@ fn parse_statement(
                                                     mutable: true,
                                                     init_line: symbol_info.init_line,
                                                     last_ref: *token.line_number(),
+                                                    live_range: None,
                                                 },
                                             );
                                             let assign_type =
@@ -353,7 +359,7 @@ fn parse_type(token: Token) -> Type {
     }
 }
 
-fn parse_expression(
+pub fn parse_expression(
     tokens: &mut VecDeque<Token>,
     symbol_table: &HashMap<String, Symbol>,
 ) -> (Expression, Type) {
@@ -429,7 +435,13 @@ fn parse_term(
         match token.lexeme() {
             "+" | "-" => {
                 let (right_expr, right_type) = parse_factor(tokens, symbol_table);
-                println!("{:#?} == {:#?}", _type, right_type);
+                trace!(
+                    Parser,
+                    "term of {:?} {} {:?}",
+                    _type,
+                    token.lexeme(),
+                    right_type
+                );
                 if _type == Type::Int && right_type == Type::Int {
                     _type = Type::Int;
                     expr = Expression::Binary(Box::new(expr), token, Box::new(right_expr));
@@ -503,7 +515,7 @@ fn parse_unary(
         "*" => {
             let op = tokens.pop_front().expect("Should be op here");
             let (expr, _type) = parse_unary(tokens, symbol_table);
-            println!("inner unary type = {:#?}", _type);
+            trace!(Parser, "inner unary type = {:?}", _type);
             let inner_type = match _type {
                 Type::Pointer(inner_type) => inner_type,
                 wrong => panic!("{:?} cannot be dereferenced!", wrong),
@@ -561,10 +573,10 @@ fn parse_primary(
                         }
                         // Actual error: how is there another token type here?
                         "[" => {
-                            println!("Parsing list!");
+                            trace!(Parser, "parsing a list");
                             let (list, list_type, list_length) =
                                 parse_list_literal(tokens, symbol_table);
-                            println!("{:#?}", list);
+                            trace!(Parser, "list {:?}", list);
                             match tokens
                                 .pop_front()
                                 .expect("Unexpected EOF: expected ']'")
@@ -634,7 +646,12 @@ fn parse_list_literal(
 
 fn lookahead(tokens: &VecDeque<Token>, match_lexeme: &str) -> bool {
     if let Some(token) = tokens.get(0) {
-        println!("Token: {}, match: {}", token.lexeme(), match_lexeme);
+        trace!(
+            Parser,
+            "lookahead: {}, match: {}",
+            token.lexeme(),
+            match_lexeme
+        );
         if token.lexeme() == match_lexeme {
             return true;
         } else {
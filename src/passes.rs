@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::asm;
+use crate::constant_folding::fold_constants;
+use crate::dead_code::eliminate_dead_code;
+use crate::ir::Function;
+use crate::loops::optimise_loops;
+use crate::peephole::optimise_peephole;
+use crate::representations::Symbol;
+use crate::ssa::{construct_ssa, destruct_ssa};
+use crate::verifier::verify;
+
+// Every optimisation the compiler can run. IR passes run between lowering and the backend, and
+// assembly passes run on the backend's output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    ConstructSsa,
+    FoldConstants,
+    OptimiseLoops,
+    EliminateDeadCode,
+    DestructSsa,
+    Peephole,
+}
+
+// The names passes are given on the command line, in the order they run at -O2
+pub const PASS_NAMES: [&str; 6] = ["ssa", "fold", "loops", "dce", "out-of-ssa", "peephole"];
+
+// The passes that can be disabled, which is all of them but out-of-ssa
+pub const DISABLEABLE_PASS_NAMES: [&str; 5] = ["ssa", "fold", "loops", "dce", "peephole"];
+
+// Runs the passes for an optimisation level in order, skipping any that have been disabled and
+// keeping a copy of the program after any that were asked for. The time each pass takes is
+// recorded
+pub struct PassManager {
+    passes: Vec<Pass>,
+    disabled: Vec<Pass>,
+    print_after: Vec<Pass>,
+    verbose: bool,
+    pub timings: Vec<(Pass, Duration)>,
+    // What the passes reported in verbose mode and the program after each pass in print_after,
+    // in the order they ran, for whoever is running the compiler to print
+    pub reports: Vec<String>,
+}
+
+impl Pass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::ConstructSsa => "ssa",
+            Pass::FoldConstants => "fold",
+            Pass::OptimiseLoops => "loops",
+            Pass::EliminateDeadCode => "dce",
+            Pass::DestructSsa => "out-of-ssa",
+            Pass::Peephole => "peephole",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Pass {
+        match name {
+            "ssa" => Pass::ConstructSsa,
+            "fold" => Pass::FoldConstants,
+            "loops" => Pass::OptimiseLoops,
+            "dce" => Pass::EliminateDeadCode,
+            "out-of-ssa" => Pass::DestructSsa,
+            "peephole" => Pass::Peephole,
+            _ => panic!("Unrecognised pass {}", name),
+        }
+    }
+
+    fn is_assembly_pass(&self) -> bool {
+        matches!(self, Pass::Peephole)
+    }
+}
+
+impl PassManager {
+    // -O1 does the optimisations that are cheap and always pay off, and -O2 adds the loop
+    // optimisations, folding again afterwards to clean up what they leave behind
+    pub fn new(opt_level: u8, disabled: &[String], print_after: &[String], verbose: bool) -> Self {
+        let passes = match opt_level {
+            0 => vec![],
+            1 => vec![
+                Pass::ConstructSsa,
+                Pass::FoldConstants,
+                Pass::EliminateDeadCode,
+                Pass::DestructSsa,
+                Pass::Peephole,
+            ],
+            _ => vec![
+                Pass::ConstructSsa,
+                Pass::FoldConstants,
+                Pass::OptimiseLoops,
+                Pass::FoldConstants,
+                Pass::EliminateDeadCode,
+                Pass::DestructSsa,
+                Pass::Peephole,
+            ],
+        };
+        let disabled: Vec<Pass> = disabled.iter().map(|name| Pass::from_name(name)).collect();
+        // Phis can't reach the backend, so leaving the program in SSA form isn't an option.
+        // Converting out of SSA does nothing if SSA construction was disabled
+        if disabled.contains(&Pass::DestructSsa) {
+            panic!("The out-of-ssa pass can't be disabled, disable the ssa pass instead");
+        }
+        Self {
+            disabled,
+            print_after: print_after
+                .iter()
+                .map(|name| Pass::from_name(name))
+                .collect(),
+            verbose,
+            ..Self::with_passes(passes)
+        }
+    }
+
+    // Run exactly the given passes with nothing disabled or printed, as the IR tests do
+    pub fn with_passes(passes: Vec<Pass>) -> Self {
+        Self {
+            passes,
+            disabled: Vec::new(),
+            print_after: Vec::new(),
+            verbose: false,
+            timings: Vec::new(),
+            reports: Vec::new(),
+        }
+    }
+
+    pub fn run_ir_passes(
+        &mut self,
+        function: &mut Function,
+        symbol_table: &HashMap<String, Symbol>,
+    ) {
+        for pass in self.enabled_passes(false) {
+            let start = Instant::now();
+            match pass {
+                Pass::ConstructSsa => construct_ssa(function),
+                Pass::FoldConstants => fold_constants(function, symbol_table),
+                Pass::OptimiseLoops => {
+                    let report = optimise_loops(function);
+                    if self.verbose {
+                        self.reports.push(report.to_string());
+                    }
+                }
+                Pass::EliminateDeadCode => {
+                    let report = eliminate_dead_code(function);
+                    if self.verbose {
+                        self.reports.push(report.to_string());
+                    }
+                }
+                Pass::DestructSsa => destruct_ssa(function),
+                Pass::Peephole => panic!("The peephole pass runs on assembly, not IR"),
+            }
+            self.timings.push((pass, start.elapsed()));
+            if self.print_after.contains(&pass) {
+                self.reports
+                    .push(format!("; IR after {}\n{}\n", pass.name(), function));
+            }
+            // Catch a pass breaking the IR straight away rather than when the program is run
+            let problems = verify(function, symbol_table);
+            if !problems.is_empty() {
+                panic!(
+                    "The IR is malformed after the {} pass:\n{}",
+                    pass.name(),
+                    problems.join("\n")
+                );
+            }
+        }
+    }
+
+    pub fn run_assembly_passes(&mut self, instructions: &mut Vec<asm::Instruction>) {
+        for pass in self.enabled_passes(true) {
+            let start = Instant::now();
+            match pass {
+                Pass::Peephole => {
+                    let stats = optimise_peephole(instructions);
+                    if self.verbose {
+                        self.reports.push(stats.to_string());
+                    }
+                }
+                _ => panic!("The {} pass runs on IR, not assembly", pass.name()),
+            }
+            self.timings.push((pass, start.elapsed()));
+            if self.print_after.contains(&pass) {
+                let mut assembly = format!("; Assembly after {}\n", pass.name());
+                for instruction in instructions.iter() {
+                    let _ = writeln!(assembly, "{}", instruction);
+                }
+                self.reports.push(assembly);
+            }
+        }
+    }
+
+    pub fn timings_report(&self) -> String {
+        let mut report = String::new();
+        for (pass, duration) in &self.timings {
+            let _ = writeln!(report, "pass {}: {:?}", pass.name(), duration);
+        }
+        report
+    }
+
+    // Out of SSA conversion is skipped along with SSA construction
+    fn enabled_passes(&self, assembly: bool) -> Vec<Pass> {
+        let ssa_disabled = self.disabled.contains(&Pass::ConstructSsa);
+        self.passes
+            .iter()
+            .filter(|pass| pass.is_assembly_pass() == assembly)
+            .filter(|pass| !self.disabled.contains(pass))
+            .filter(|pass| !(ssa_disabled && **pass == Pass::DestructSsa))
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir_text::{parse_ir, write_ir};
+    use std::fs;
+    use std::path::Path;
+
+    // Each fixture in tests/ir starts with a `; passes:` line naming the passes to run, followed
+    // by the IR to run them on, then a `; expect` line and the IR that should come out
+    #[test]
+    fn ir_fixtures() {
+        let fixture_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/ir");
+        let mut paths: Vec<_> = fs::read_dir(fixture_dir)
+            .expect("The fixture directory should exist")
+            .map(|entry| entry.expect("Fixtures should be readable").path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "ir"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+
+        for path in paths {
+            let text = fs::read_to_string(&path).expect("Fixtures should be readable");
+            let (header, body) = text.split_once('\n').unwrap_or_default();
+            let passes = header
+                .strip_prefix("; passes:")
+                .unwrap_or_else(|| panic!("{} should start with `; passes:`", path.display()))
+                .split_whitespace()
+                .map(Pass::from_name)
+                .collect();
+            let (input, expected) = body
+                .split_once("; expect\n")
+                .unwrap_or_else(|| panic!("{} should have an `; expect` line", path.display()));
+
+            let (mut function, symbol_table) = parse_ir(input);
+            assert_eq!(verify(&function, &symbol_table), Vec::<String>::new());
+            PassManager::with_passes(passes).run_ir_passes(&mut function, &symbol_table);
+            assert_eq!(
+                write_ir(&function, &symbol_table).trim(),
+                expected.trim(),
+                "{}",
+                path.display()
+            );
+        }
+    }
+
+    fn names(passes: Vec<Pass>) -> Vec<&'static str> {
+        passes.iter().map(Pass::name).collect()
+    }
+
+    #[test]
+    fn disabled_passes_are_skipped() {
+        let manager = PassManager::new(2, &["fold".to_string()], &[], false);
+        assert_eq!(
+            names(manager.enabled_passes(false)),
+            ["ssa", "loops", "dce", "out-of-ssa"]
+        );
+        // Without SSA construction there is nothing to convert back out of
+        let manager = PassManager::new(2, &["ssa".to_string(), "peephole".to_string()], &[], false);
+        assert_eq!(
+            names(manager.enabled_passes(false)),
+            ["fold", "loops", "fold", "dce"]
+        );
+        assert!(manager.enabled_passes(true).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "The out-of-ssa pass can't be disabled")]
+    fn out_of_ssa_cant_be_disabled() {
+        PassManager::new(1, &["out-of-ssa".to_string()], &[], false);
+    }
+
+    #[test]
+    fn keeps_the_program_after_passes_asked_for() {
+        let (mut function, symbol_table) = parse_ir(
+            "mut x: int
+
+            block_0:
+                store x, 2
+                t0 = load x
+                t1 = mul t0, 3
+                exit t1",
+        );
+        let print_after = ["fold".to_string(), "peephole".to_string()];
+        let mut manager = PassManager::new(1, &[], &print_after, false);
+        manager.run_ir_passes(&mut function, &symbol_table);
+        assert_eq!(
+            manager.reports,
+            ["; IR after fold\nblock_0:\n    exit 6\n\n"]
+        );
+
+        let mut instructions = vec![
+            asm::Instruction::Mov(
+                asm::Operand::Register(asm::Register::Rdi),
+                asm::Operand::Register(asm::Register::Rdi),
+            ),
+            asm::Instruction::Syscall,
+        ];
+        manager.run_assembly_passes(&mut instructions);
+        assert_eq!(manager.reports[1], "; Assembly after peephole\nsyscall\n");
+        assert_eq!(manager.timings.len(), 5);
+    }
+}
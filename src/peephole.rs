@@ -0,0 +1,523 @@
+use std::fmt;
+
+use crate::asm::{Instruction, Operand, Register};
+
+// The backend keeps these registers free for shuffling values within a single IR instruction,
+// so a value left in one is never read by a later instruction or across a jump
+const SCRATCH_REGISTERS: [Register; 3] = [Register::Rax, Register::Rcx, Register::Rdx];
+
+// How many times each rewrite was made
+#[derive(Debug, Default)]
+pub struct PeepholeStats {
+    pub redundant_moves: usize,
+    pub dead_moves: usize,
+    pub folded_round_trips: usize,
+    pub fused_push_pops: usize,
+    pub zeroed_with_xor: usize,
+}
+
+// A rewrite looks at the instructions starting at an index and returns how many of them to
+// replace along with what to replace them with
+type Rule = fn(&[Instruction], usize) -> Option<(usize, Vec<Instruction>)>;
+
+impl fmt::Display for PeepholeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "peephole: removed {} redundant moves",
+            self.redundant_moves
+        )?;
+        writeln!(
+            f,
+            "peephole: removed {} moves into unused scratch registers",
+            self.dead_moves
+        )?;
+        writeln!(
+            f,
+            "peephole: folded {} scratch register round trips into one instruction",
+            self.folded_round_trips
+        )?;
+        writeln!(
+            f,
+            "peephole: fused {} push/pop pairs into moves",
+            self.fused_push_pops
+        )?;
+        writeln!(
+            f,
+            "peephole: replaced {} zeroing moves with xor",
+            self.zeroed_with_xor
+        )
+    }
+}
+
+// Clean up the assembly the backend produced by rewriting short runs of instructions into
+// cheaper equivalents. Keeps going until no rule matches anywhere
+pub fn optimise_peephole(instructions: &mut Vec<Instruction>) -> PeepholeStats {
+    let mut stats = PeepholeStats::default();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let mut index = 0;
+        while index < instructions.len() {
+            if rewrite_at(instructions, index, &mut stats) {
+                changed = true;
+                // The rewrite may complete a pattern that starts just before it
+                index = index.saturating_sub(2);
+            } else {
+                index += 1;
+            }
+        }
+    }
+    stats
+}
+
+// Try each rule in turn at one position, applying the first that matches
+fn rewrite_at(
+    instructions: &mut Vec<Instruction>,
+    index: usize,
+    stats: &mut PeepholeStats,
+) -> bool {
+    let rules: [(Rule, &mut usize); 5] = [
+        (remove_redundant_move, &mut stats.redundant_moves),
+        (fold_scratch_round_trip, &mut stats.folded_round_trips),
+        (remove_dead_move, &mut stats.dead_moves),
+        (fuse_push_pop, &mut stats.fused_push_pops),
+        (zero_with_xor, &mut stats.zeroed_with_xor),
+    ];
+    for (rule, count) in rules {
+        if let Some((length, replacement)) = rule(instructions, index) {
+            instructions.splice(index..index + length, replacement);
+            *count += 1;
+            return true;
+        }
+    }
+    false
+}
+
+// mov a, a does nothing, and neither does mov b, a straight after mov a, b. The prologue's lea
+// does nothing when there are no stack slots
+fn remove_redundant_move(
+    instructions: &[Instruction],
+    index: usize,
+) -> Option<(usize, Vec<Instruction>)> {
+    match &instructions[index..] {
+        [Instruction::Mov(dest, src), ..] if dest == src => Some((1, vec![])),
+        [Instruction::Lea(dest, memory), ..]
+            if memory.base == *dest && memory.index.is_none() && memory.displacement == 0 =>
+        {
+            Some((1, vec![]))
+        }
+        [first @ Instruction::Mov(dest, src), Instruction::Mov(back_dest, back_src), ..]
+            if back_dest == src && back_src == dest && !overwrites_base(*dest, *src) =>
+        {
+            Some((2, vec![first.clone()]))
+        }
+        _ => None,
+    }
+}
+
+// Whether writing to dest changes the address that src refers to
+fn overwrites_base(dest: Operand, src: Operand) -> bool {
+    match dest {
+        Operand::Register(register) => mentions(src, register),
+        _ => false,
+    }
+}
+
+// mov rax, a; op rax, b; mov a, rax is just op a, b when rax isn't read afterwards
+fn fold_scratch_round_trip(
+    instructions: &[Instruction],
+    index: usize,
+) -> Option<(usize, Vec<Instruction>)> {
+    let [Instruction::Mov(Operand::Register(scratch), target), operation, Instruction::Mov(back_dest, Operand::Register(back_src)), ..] =
+        &instructions[index..]
+    else {
+        return None;
+    };
+    if back_src != scratch
+        || back_dest != target
+        || !is_dead_after(instructions, index + 3, *scratch)
+    {
+        return None;
+    }
+    let scratch_operand = Operand::Register(*scratch);
+    // The other operand has to be usable alongside the target in a single instruction
+    let fits = |src: &Operand| {
+        let both_memory = target.is_memory() && src.is_memory();
+        !mentions(*src, *scratch) && !both_memory && !src.is_wide_immediate()
+    };
+    let folded = match operation {
+        Instruction::Add(dest, src) if *dest == scratch_operand && fits(src) => {
+            Instruction::Add(*target, *src)
+        }
+        Instruction::Sub(dest, src) if *dest == scratch_operand && fits(src) => {
+            Instruction::Sub(*target, *src)
+        }
+        Instruction::Xor(dest, src) if *dest == scratch_operand && fits(src) => {
+            Instruction::Xor(*target, *src)
+        }
+        Instruction::And(dest, src) if *dest == scratch_operand && fits(src) => {
+            Instruction::And(*target, *src)
+        }
+        Instruction::Shl(dest, count) if *dest == scratch_operand => {
+            Instruction::Shl(*target, *count)
+        }
+        Instruction::Sar(dest, count) if *dest == scratch_operand => {
+            Instruction::Sar(*target, *count)
+        }
+        Instruction::Shr(dest, count) if *dest == scratch_operand => {
+            Instruction::Shr(*target, *count)
+        }
+        Instruction::Neg(dest) if *dest == scratch_operand => Instruction::Neg(*target),
+        // imul can only write to a register
+        Instruction::Imul(dest, src) if dest == scratch && fits(src) => match target {
+            Operand::Register(target) => Instruction::Imul(*target, *src),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some((3, vec![folded]))
+}
+
+// A move into a scratch register that nothing reads before it is overwritten
+fn remove_dead_move(
+    instructions: &[Instruction],
+    index: usize,
+) -> Option<(usize, Vec<Instruction>)> {
+    match &instructions[index] {
+        Instruction::Mov(Operand::Register(register), _) | Instruction::Lea(register, _)
+            if is_dead_after(instructions, index + 1, *register) =>
+        {
+            Some((1, vec![]))
+        }
+        _ => None,
+    }
+}
+
+// Pushing a value and popping it straight back off is a move
+fn fuse_push_pop(instructions: &[Instruction], index: usize) -> Option<(usize, Vec<Instruction>)> {
+    match &instructions[index..] {
+        [Instruction::Push(src), Instruction::Pop(dest), ..] => {
+            if src == dest {
+                Some((2, vec![]))
+            } else if dest.is_memory() && (src.is_memory() || src.is_wide_immediate()) {
+                None
+            } else {
+                Some((2, vec![Instruction::Mov(*dest, *src)]))
+            }
+        }
+        _ => None,
+    }
+}
+
+// xor reg, reg is shorter than mov reg, 0 but sets the flags, so it can only be used when
+// nothing reads the flags before they are set again
+fn zero_with_xor(instructions: &[Instruction], index: usize) -> Option<(usize, Vec<Instruction>)> {
+    match &instructions[index] {
+        Instruction::Mov(dest @ Operand::Register(_), Operand::Immediate(0))
+            if are_flags_dead_after(instructions, index + 1) =>
+        {
+            Some((1, vec![Instruction::Xor(*dest, *dest)]))
+        }
+        _ => None,
+    }
+}
+
+fn mentions(operand: Operand, register: Register) -> bool {
+    match operand {
+        Operand::Register(used) => used == register,
+        Operand::Memory(memory) => {
+            memory.base == register || memory.index.is_some_and(|(index, _)| index == register)
+        }
+        Operand::Immediate(_) => false,
+    }
+}
+
+enum RegisterUse {
+    Read,
+    Write,
+    Untouched,
+}
+
+// How an instruction uses a register. Anything that reads the register at all, including
+// partial writes and memory addresses based on it, counts as a read
+fn get_register_use(instruction: &Instruction, register: Register) -> RegisterUse {
+    let read_if = |reads: bool| {
+        if reads {
+            RegisterUse::Read
+        } else {
+            RegisterUse::Untouched
+        }
+    };
+    match instruction {
+        Instruction::Mov(dest, src) => {
+            if mentions(*src, register) || (dest.is_memory() && mentions(*dest, register)) {
+                RegisterUse::Read
+            } else if *dest == Operand::Register(register) {
+                RegisterUse::Write
+            } else {
+                RegisterUse::Untouched
+            }
+        }
+        Instruction::Lea(dest, memory) => {
+            if mentions(Operand::Memory(*memory), register) {
+                RegisterUse::Read
+            } else if *dest == register {
+                RegisterUse::Write
+            } else {
+                RegisterUse::Untouched
+            }
+        }
+        Instruction::Movzx(dest) => {
+            if register == Register::Rax {
+                RegisterUse::Read
+            } else if *dest == register {
+                RegisterUse::Write
+            } else {
+                RegisterUse::Untouched
+            }
+        }
+        // xor of a register with itself doesn't depend on its old value
+        Instruction::Xor(Operand::Register(dest), Operand::Register(src))
+            if dest == src && *dest == register =>
+        {
+            RegisterUse::Write
+        }
+        Instruction::Add(dest, src)
+        | Instruction::Sub(dest, src)
+        | Instruction::Xor(dest, src)
+        | Instruction::And(dest, src)
+        | Instruction::Cmp(dest, src) => {
+            read_if(mentions(*dest, register) || mentions(*src, register))
+        }
+        Instruction::Imul(dest, src) => read_if(*dest == register || mentions(*src, register)),
+        Instruction::WideImul(src) => {
+            if register == Register::Rax || mentions(*src, register) {
+                RegisterUse::Read
+            } else if register == Register::Rdx {
+                RegisterUse::Write
+            } else {
+                RegisterUse::Untouched
+            }
+        }
+        Instruction::Shl(dest, _) | Instruction::Sar(dest, _) | Instruction::Shr(dest, _) => {
+            read_if(mentions(*dest, register))
+        }
+        Instruction::Cqo => match register {
+            Register::Rax => RegisterUse::Read,
+            Register::Rdx => RegisterUse::Write,
+            _ => RegisterUse::Untouched,
+        },
+        Instruction::Idiv(divisor) => read_if(
+            register == Register::Rax || register == Register::Rdx || mentions(*divisor, register),
+        ),
+        Instruction::Neg(operand) => read_if(mentions(*operand, register)),
+        Instruction::Set(_) => read_if(register == Register::Rax),
+        Instruction::Push(operand) => {
+            read_if(register == Register::Rsp || mentions(*operand, register))
+        }
+        Instruction::Pop(operand) => {
+            if register == Register::Rsp || (operand.is_memory() && mentions(*operand, register)) {
+                RegisterUse::Read
+            } else if *operand == Operand::Register(register) {
+                RegisterUse::Write
+            } else {
+                RegisterUse::Untouched
+            }
+        }
+        // The system call reads its arguments out of registers, and whatever is returned to can
+        // read any of them
+        Instruction::Syscall | Instruction::Ret => RegisterUse::Read,
+        Instruction::Label(_) | Instruction::Jmp(_) | Instruction::Jcc(_, _) => {
+            RegisterUse::Untouched
+        }
+    }
+}
+
+// Whether the value in a register is never read from this index onwards. Only scratch
+// registers are tracked, as they are the only ones the backend never keeps across a jump
+fn is_dead_after(instructions: &[Instruction], index: usize, register: Register) -> bool {
+    if !SCRATCH_REGISTERS.contains(&register) {
+        return false;
+    }
+    for instruction in &instructions[index..] {
+        match instruction {
+            Instruction::Label(_) | Instruction::Jmp(_) | Instruction::Jcc(_, _) => return true,
+            _ => (),
+        }
+        match get_register_use(instruction, register) {
+            RegisterUse::Read => return false,
+            RegisterUse::Write => return true,
+            RegisterUse::Untouched => (),
+        }
+    }
+    true
+}
+
+// Whether the flags are set again before anything reads them. The backend always sets the flags
+// in the same block that reads them, so they are dead at every label and jump
+fn are_flags_dead_after(instructions: &[Instruction], index: usize) -> bool {
+    for instruction in &instructions[index..] {
+        match instruction {
+            Instruction::Jcc(_, _) | Instruction::Set(_) => return false,
+            // idiv leaves the flags undefined, so nothing can rely on them afterwards
+            Instruction::Add(_, _)
+            | Instruction::Sub(_, _)
+            | Instruction::Xor(_, _)
+            | Instruction::And(_, _)
+            | Instruction::Imul(_, _)
+            | Instruction::WideImul(_)
+            | Instruction::Shl(_, _)
+            | Instruction::Sar(_, _)
+            | Instruction::Shr(_, _)
+            | Instruction::Idiv(_)
+            | Instruction::Neg(_)
+            | Instruction::Cmp(_, _)
+            | Instruction::Label(_)
+            | Instruction::Jmp(_)
+            | Instruction::Syscall
+            | Instruction::Ret => return true,
+            Instruction::Mov(_, _)
+            | Instruction::Movzx(_)
+            | Instruction::Lea(_, _)
+            | Instruction::Cqo
+            | Instruction::Push(_)
+            | Instruction::Pop(_) => (),
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{Condition, Memory};
+    use Register::{Rax, Rbp, Rbx, Rsi};
+
+    fn register(register: Register) -> Operand {
+        Operand::Register(register)
+    }
+
+    fn slot(displacement: i32) -> Operand {
+        Operand::Memory(Memory {
+            base: Rbp,
+            index: None,
+            displacement,
+        })
+    }
+
+    fn optimise(mut instructions: Vec<Instruction>) -> (Vec<Instruction>, PeepholeStats) {
+        let stats = optimise_peephole(&mut instructions);
+        (instructions, stats)
+    }
+
+    #[test]
+    fn removes_redundant_moves() {
+        let (instructions, stats) = optimise(vec![
+            Instruction::Mov(register(Rbx), register(Rbx)),
+            Instruction::Mov(register(Rbx), register(Rsi)),
+            Instruction::Mov(register(Rsi), register(Rbx)),
+            Instruction::Syscall,
+        ]);
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Mov(register(Rbx), register(Rsi)),
+                Instruction::Syscall
+            ]
+        );
+        assert_eq!(stats.redundant_moves, 2);
+    }
+
+    #[test]
+    fn removes_moves_into_scratch_registers_that_are_overwritten() {
+        let (instructions, stats) = optimise(vec![
+            Instruction::Mov(register(Rax), Operand::Immediate(5)),
+            Instruction::Mov(register(Rax), register(Rbx)),
+            Instruction::Syscall,
+        ]);
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Mov(register(Rax), register(Rbx)),
+                Instruction::Syscall
+            ]
+        );
+        assert_eq!(stats.dead_moves, 1);
+    }
+
+    #[test]
+    fn folds_scratch_register_round_trips() {
+        let (instructions, stats) = optimise(vec![
+            Instruction::Mov(register(Rax), slot(-8)),
+            Instruction::Add(register(Rax), Operand::Immediate(3)),
+            Instruction::Mov(slot(-8), register(Rax)),
+            Instruction::Jmp("loop".to_string()),
+        ]);
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Add(slot(-8), Operand::Immediate(3)),
+                Instruction::Jmp("loop".to_string())
+            ]
+        );
+        assert_eq!(stats.folded_round_trips, 1);
+
+        // add can't take two memory operands
+        let unfoldable = vec![
+            Instruction::Mov(register(Rax), slot(-8)),
+            Instruction::Add(register(Rax), slot(-16)),
+            Instruction::Mov(slot(-8), register(Rax)),
+            Instruction::Jmp("loop".to_string()),
+        ];
+        assert_eq!(optimise(unfoldable.clone()).0, unfoldable);
+    }
+
+    #[test]
+    fn fuses_push_pop_pairs() {
+        let (instructions, stats) = optimise(vec![
+            Instruction::Push(register(Rbx)),
+            Instruction::Pop(register(Rsi)),
+            Instruction::Syscall,
+        ]);
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Mov(register(Rsi), register(Rbx)),
+                Instruction::Syscall
+            ]
+        );
+        assert_eq!(stats.fused_push_pops, 1);
+
+        // There is no move from memory to memory
+        let unfusable = vec![
+            Instruction::Push(slot(-8)),
+            Instruction::Pop(slot(-16)),
+            Instruction::Syscall,
+        ];
+        assert_eq!(optimise(unfusable.clone()).0, unfusable);
+    }
+
+    #[test]
+    fn zeroes_with_xor_only_when_the_flags_are_dead() {
+        let (instructions, stats) = optimise(vec![
+            Instruction::Mov(register(Rbx), Operand::Immediate(0)),
+            Instruction::Syscall,
+        ]);
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Xor(register(Rbx), register(Rbx)),
+                Instruction::Syscall
+            ]
+        );
+        assert_eq!(stats.zeroed_with_xor, 1);
+
+        let flags_read = vec![
+            Instruction::Cmp(register(Rsi), Operand::Immediate(1)),
+            Instruction::Mov(register(Rbx), Operand::Immediate(0)),
+            Instruction::Jcc(Condition::E, "done".to_string()),
+        ];
+        assert_eq!(optimise(flags_read.clone()).0, flags_read);
+    }
+}
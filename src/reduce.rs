@@ -0,0 +1,429 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ast_printer::write_source;
+use crate::representations::{Block, Statement, Symbol, Token};
+use crate::{lex, parse, Program};
+
+// Shrinks a program that makes the compiler go wrong down to a small one that still does, by
+// delta debugging. Whole statements are removed first and compound statements replaced by their
+// bodies, working from the syntax tree, and then single tokens are removed from what is left.
+// The two alternate until neither can remove anything more. Whether a smaller program still goes
+// wrong is decided by a predicate, so the same reducer serves crashes, miscompiles and anything
+// else that can be checked
+
+// Commands that run for longer than this are killed and the candidate counts as not failing, as
+// removing code can leave a loop that never finishes
+const PREDICATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Reduce a program that the predicate says fails, returning the smallest version of it found
+// that still fails
+pub fn reduce(source: &str, still_fails: impl Fn(&str) -> bool) -> String {
+    let mut current = source.to_string();
+    loop {
+        let before = current.clone();
+        current = reduce_statements(&current, &still_fails);
+        current = reduce_tokens(&current, &still_fails);
+        if current == before {
+            return current;
+        }
+    }
+}
+
+// Remove as many items as possible while the predicate still holds, keeping at least min_length
+// of them. Chunks of items are tried first, halving the chunk size each time nothing more can be
+// removed, down to single items
+fn remove_chunks<T: Clone>(
+    mut items: Vec<T>,
+    min_length: usize,
+    still_fails: impl Fn(&[T]) -> bool,
+) -> Vec<T> {
+    let mut chunk_size = (items.len() / 2).max(1);
+    loop {
+        let mut start = 0;
+        let mut removed_any = false;
+        while start < items.len() {
+            let end = (start + chunk_size).min(items.len());
+            if items.len() - (end - start) >= min_length {
+                let candidate = [&items[..start], &items[end..]].concat();
+                if still_fails(&candidate) {
+                    items = candidate;
+                    removed_any = true;
+                    continue;
+                }
+            }
+            start = end;
+        }
+        if !removed_any {
+            if chunk_size == 1 {
+                return items;
+            }
+            chunk_size /= 2;
+        }
+    }
+}
+
+// A program that doesn't parse, which token removal can leave behind, is left as it is
+fn reduce_statements(source: &str, still_fails: &impl Fn(&str) -> bool) -> String {
+    let Ok(program) = lex(source).and_then(parse) else {
+        return source.to_string();
+    };
+    let Program {
+        statements,
+        symbol_table,
+    } = program;
+    // The last program that still failed, which is always the one the reducer ends up with
+    let smallest = RefCell::new(None);
+    let test = |program: &[Statement]| test_program(program, &symbol_table, still_fails, &smallest);
+    reduce_list(Vec::from(statements), 0, &|list| list, &test);
+    smallest.into_inner().unwrap_or_else(|| source.to_string())
+}
+
+fn test_program(
+    program: &[Statement],
+    symbol_table: &HashMap<String, Symbol>,
+    still_fails: &impl Fn(&str) -> bool,
+    smallest: &RefCell<Option<String>>,
+) -> bool {
+    let source = write_source(program, symbol_table);
+    if still_fails(&source) {
+        *smallest.borrow_mut() = Some(source);
+        true
+    } else {
+        false
+    }
+}
+
+// Reduce a list of statements somewhere in the program. rebuild puts a version of the list back
+// where it came from, giving the whole program to test
+fn reduce_list(
+    list: Vec<Statement>,
+    min_length: usize,
+    rebuild: &dyn Fn(Vec<Statement>) -> Vec<Statement>,
+    test: &dyn Fn(&[Statement]) -> bool,
+) -> Vec<Statement> {
+    let mut list = remove_chunks(list, min_length, |candidate| {
+        test(&rebuild(candidate.to_vec()))
+    });
+
+    // Replace compound statements by what they run, trying the same place again after each
+    // replacement in case what replaced it is compound too
+    let mut index = 0;
+    while index < list.len() {
+        let replaced = inner_statements(&list[index])
+            .into_iter()
+            .find_map(|inner| {
+                let candidate = [&list[..index], &inner[..], &list[index + 1..]].concat();
+                test(&rebuild(candidate.clone())).then_some(candidate)
+            });
+        match replaced {
+            Some(candidate) => list = candidate,
+            None => index += 1,
+        }
+    }
+
+    for index in 0..list.len() {
+        let put_back = |statement: Statement| {
+            let mut candidate = list.clone();
+            candidate[index] = statement;
+            rebuild(candidate)
+        };
+        let reduced = reduce_statement(list[index].clone(), &put_back, test);
+        list[index] = reduced;
+    }
+    list
+}
+
+// Reduce the statements inside a compound statement
+fn reduce_statement(
+    statement: Statement,
+    put_back: &dyn Fn(Statement) -> Vec<Statement>,
+    test: &dyn Fn(&[Statement]) -> bool,
+) -> Statement {
+    match statement {
+        Statement::If(expr, body) => {
+            let body = reduce_body(
+                *body,
+                &|body| put_back(Statement::If(expr.clone(), Box::new(body))),
+                test,
+            );
+            Statement::If(expr, Box::new(body))
+        }
+        Statement::IfElse(expr, if_body, else_body) => {
+            let if_body = reduce_body(
+                *if_body,
+                &|body| {
+                    put_back(Statement::IfElse(
+                        expr.clone(),
+                        Box::new(body),
+                        else_body.clone(),
+                    ))
+                },
+                test,
+            );
+            let else_body = reduce_body(
+                *else_body,
+                &|body| {
+                    put_back(Statement::IfElse(
+                        expr.clone(),
+                        Box::new(if_body.clone()),
+                        Box::new(body),
+                    ))
+                },
+                test,
+            );
+            Statement::IfElse(expr, Box::new(if_body), Box::new(else_body))
+        }
+        Statement::While(expr, body) => {
+            let body = reduce_body(
+                *body,
+                &|body| put_back(Statement::While(expr.clone(), Box::new(body))),
+                test,
+            );
+            Statement::While(expr, Box::new(body))
+        }
+        Statement::Block(block) => {
+            let list = reduce_list(
+                block_statements(&block),
+                1,
+                &|list| put_back(Statement::Block(Box::new(list_block(list)))),
+                test,
+            );
+            Statement::Block(Box::new(list_block(list)))
+        }
+        Statement::Assignment(_, _) | Statement::Break => statement,
+    }
+}
+
+// The body of an if or while, which stays a block if it was one
+fn reduce_body(
+    body: Statement,
+    put_back: &dyn Fn(Statement) -> Vec<Statement>,
+    test: &dyn Fn(&[Statement]) -> bool,
+) -> Statement {
+    match body {
+        Statement::Block(_) => reduce_statement(body, put_back, test),
+        _ => {
+            let list = reduce_list(vec![body], 1, &|list| put_back(list_body(list)), test);
+            list_body(list)
+        }
+    }
+}
+
+// What a compound statement could be replaced by: the statements it runs
+fn inner_statements(statement: &Statement) -> Vec<Vec<Statement>> {
+    let body_statements = |body: &Statement| match body {
+        Statement::Block(block) => block_statements(block),
+        _ => vec![body.clone()],
+    };
+    match statement {
+        Statement::If(_, body) | Statement::While(_, body) => vec![body_statements(body)],
+        Statement::IfElse(_, if_body, else_body) => {
+            vec![body_statements(if_body), body_statements(else_body)]
+        }
+        Statement::Block(block) => vec![block_statements(block)],
+        Statement::Assignment(_, _) | Statement::Break => Vec::new(),
+    }
+}
+
+fn block_statements(block: &Block) -> Vec<Statement> {
+    let mut statements = Vec::new();
+    let mut block = block;
+    loop {
+        match block {
+            Block::Statement(statement) => {
+                statements.push(statement.clone());
+                return statements;
+            }
+            Block::Block(statement, next) => {
+                statements.push(statement.clone());
+                block = next;
+            }
+        }
+    }
+}
+
+// Blocks can't be empty, so the list always has a statement in it
+fn list_block(list: Vec<Statement>) -> Block {
+    let mut statements = list.into_iter().rev();
+    let last = statements
+        .next()
+        .expect("A block needs at least one statement");
+    statements.fold(Block::Statement(last), |block, statement| {
+        Block::Block(statement, Box::new(block))
+    })
+}
+
+// A body that was a single statement stays one unless a replacement put several there
+fn list_body(mut list: Vec<Statement>) -> Statement {
+    if list.len() == 1 {
+        list.pop().expect("The list has a statement in it")
+    } else {
+        Statement::Block(Box::new(list_block(list)))
+    }
+}
+
+fn reduce_tokens(source: &str, still_fails: &impl Fn(&str) -> bool) -> String {
+    let Ok(tokens) = lex(source) else {
+        return source.to_string();
+    };
+    let smallest = RefCell::new(None);
+    remove_chunks(Vec::from(tokens), 0, |candidate| {
+        let source = token_source(candidate);
+        if still_fails(&source) {
+            *smallest.borrow_mut() = Some(source);
+            true
+        } else {
+            false
+        }
+    });
+    smallest.into_inner().unwrap_or_else(|| source.to_string())
+}
+
+// Tokens stay on the lines they came from, as which variable the program exits with depends on
+// line numbers
+fn token_source(tokens: &[Token]) -> String {
+    let mut source = String::new();
+    let mut line_number = 1;
+    for token in tokens {
+        if *token.line_number() > line_number {
+            source.push_str(&"\n".repeat(token.line_number() - line_number));
+            line_number = *token.line_number();
+        } else if !source.is_empty() {
+            source.push(' ');
+        }
+        source.push_str(token.lexeme());
+    }
+    source
+}
+
+// Decides whether a candidate still fails by running a shell command on it. The candidate is
+// written to a file whose path replaces {} in the command, or is added to the end of the command
+// if there is no {}, and the candidate still fails if the command exits with status 0. Failing
+// to set up or run the command is returned for the caller to report
+pub struct CommandPredicate {
+    command: String,
+    directory: PathBuf,
+    candidate: PathBuf,
+}
+
+impl CommandPredicate {
+    pub fn new(command: &str) -> io::Result<Self> {
+        let directory =
+            std::env::temp_dir().join(format!("testcomp-reduce-{}", std::process::id()));
+        std::fs::create_dir_all(&directory)?;
+        let candidate = directory.join("candidate.ttc");
+        let quoted = format!("'{}'", candidate.display());
+        let command = if command.contains("{}") {
+            command.replace("{}", &quoted)
+        } else {
+            format!("{} {}", command, quoted)
+        };
+        Ok(CommandPredicate {
+            command,
+            directory,
+            candidate,
+        })
+    }
+
+    pub fn still_fails(&self, source: &str) -> io::Result<bool> {
+        std::fs::write(&self.candidate, source)?;
+        // The command gets its own process group so that everything it starts can be killed
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn()?;
+        let start = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status.success());
+            }
+            if start.elapsed() > PREDICATE_TIMEOUT {
+                // SAFETY: kill only sends a signal, to the process group the command leads
+                unsafe {
+                    libc::kill(-(child.id() as i32), libc::SIGKILL);
+                }
+                let _ = child.wait();
+                return Ok(false);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl Drop for CommandPredicate {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::{interpret_with_limit, Trap};
+
+    fn traps(source: &str, expected: Trap) -> bool {
+        lex(source).and_then(parse).is_ok_and(|program| {
+            interpret_with_limit(&program.statements, &program.symbol_table, 10_000)
+                == Err(expected)
+        })
+    }
+
+    #[test]
+    fn remove_chunks_keeps_only_what_matters() {
+        let items = vec!['a', 'b', 'c', 'd', 'e', 'f', 'g'];
+        let kept = remove_chunks(items, 0, |candidate| {
+            candidate.contains(&'c') && candidate.contains(&'f')
+        });
+        assert_eq!(kept, vec!['c', 'f']);
+    }
+
+    #[test]
+    fn reduces_to_the_statements_that_fail() {
+        let source = "mut int a = 1;
+mut int b = 0;
+while a < 10 {
+    a = a + 1;
+    if a == 5 {
+        b = b + 3;
+    } else {
+        const int c = a * 2;
+    }
+}
+const int zero = a - a;
+const int d = b / zero;
+const int exit = d + a;";
+        let reduced = reduce(source, |candidate| traps(candidate, Trap::DivisionByZero));
+        assert_eq!(
+            reduced,
+            "mut int a = 1;\nmut int b = 0;\nconst int zero = a - a;\nconst int d = b / zero;\n"
+        );
+    }
+
+    #[test]
+    fn runs_commands_as_the_predicate() {
+        let source = "mut int i = 0;
+while i < 3 {
+    if i == 2 {
+        break;
+    }
+    i = i + 1;
+}
+const int exit = i;";
+        let predicate = CommandPredicate::new("grep -q break").unwrap();
+        let reduced = reduce(source, |candidate| {
+            predicate.still_fails(candidate).unwrap()
+        });
+        assert_eq!(reduced.trim(), "break");
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, Write};
+
+use crate::ast_printer::{expression_tree, statement_tree, type_name};
+use crate::interp::{evaluate_after, run_with_limit};
+use crate::parser::parse_expression;
+use crate::representations::{Expression, Token, TokenType, Type};
+use crate::{catch_panic, compile, lex, parse, Diagnostic, Options, Program};
+
+// An interactive session over the interpreter. Statements entered are added to a program that
+// grows one input at a time, and an expression entered is evaluated over everything the program
+// has done so far and printed with its type. Rather than keep the interpreter's state between
+// inputs the whole program is parsed and run again for each one, which gives the same result as
+// the language has no side effects, and keeps the line numbers the backend relies on right for
+// :asm
+
+// How many steps the program can take before it's given up on, so that a loop that never ends
+// can't hang the session
+const MAX_STEPS: u64 = 1_000_000;
+
+const HELP: &str = "\
+Enter a statement to add it to the program, or an expression to see its value and type.
+    :ast [input]  print the tree of every statement so far, or of the input
+    :asm          print the assembly the program so far compiles to
+    :symbols      print every variable declared so far
+    :reset        forget everything entered so far
+    :help         print this message
+    :quit         leave the session";
+
+pub struct Repl {
+    // Every statement accepted so far, one input per line
+    source: String,
+    // The source, parsed
+    program: Program,
+}
+
+// Read inputs from stdin until it ends or :quit is entered. An input carries on over lines
+// until its braces are balanced, so blocks can be written the way they would be in a file
+pub fn run_repl() {
+    let mut repl = Repl::new();
+    let mut input = String::new();
+    println!("testcomp repl, :help for help");
+    loop {
+        print!("{}", if input.is_empty() { ">> " } else { ".. " });
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        input.push_str(&line);
+        if input.matches('{').count() > input.matches('}').count() {
+            continue;
+        }
+        let entered = std::mem::take(&mut input);
+        if entered.trim() == ":quit" {
+            break;
+        }
+        match repl.eval(&entered) {
+            Ok(output) if output.is_empty() => (),
+            Ok(output) => println!("{}", output),
+            Err(message) => eprintln!("{}", message),
+        }
+    }
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Repl::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl {
+            source: String::new(),
+            program: Program {
+                statements: VecDeque::new(),
+                symbol_table: HashMap::new(),
+            },
+        }
+    }
+
+    // Handle one input, returning what to print, or why the input was rejected. A rejected
+    // statement leaves the session as it was
+    pub fn eval(&mut self, input: &str) -> Result<String, String> {
+        let input = input.trim();
+        if let Some(command) = input.strip_prefix(':') {
+            return self.command(command);
+        }
+        if input.is_empty() {
+            return Ok(String::new());
+        }
+        let tokens = lex(input).map_err(describe)?;
+        if is_statement(&tokens) {
+            self.add_statement(input)
+        } else {
+            self.evaluate(tokens)
+        }
+    }
+
+    fn command(&mut self, command: &str) -> Result<String, String> {
+        let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+        match (name, argument.trim()) {
+            ("ast", "") => Ok(self
+                .program
+                .statements
+                .iter()
+                .map(statement_tree)
+                .collect::<Vec<String>>()
+                .join("\n")),
+            ("ast", input) => self.tree(input),
+            ("asm", "") => {
+                if self.program.statements.is_empty() {
+                    return Err("There is no program to compile yet".to_string());
+                }
+                let artifact = compile(&self.source, &Options::default()).map_err(describe)?;
+                Ok(artifact.assembly().trim_end().to_string())
+            }
+            ("symbols", "") => {
+                let mut symbols: Vec<String> = self
+                    .program
+                    .symbol_table
+                    .iter()
+                    .map(|(id, symbol)| {
+                        let keyword = if symbol.mutable { "mut" } else { "const" };
+                        format!("{} {} {}", keyword, describe_type(&symbol._type), id)
+                    })
+                    .collect();
+                symbols.sort();
+                Ok(symbols.join("\n"))
+            }
+            ("reset", "") => {
+                *self = Repl::new();
+                Ok(String::new())
+            }
+            ("help", "") => Ok(HELP.to_string()),
+            _ => Err(format!(
+                "Unrecognised command :{}, :help lists them",
+                command
+            )),
+        }
+    }
+
+    // Parse the program with a statement added and run it, keeping the statement only if it
+    // does both
+    fn add_statement(&mut self, input: &str) -> Result<String, String> {
+        let source = format!("{}{}\n", self.source, input);
+        let program = lex(&source).and_then(parse).map_err(describe)?;
+        run_with_limit(&program.statements, &program.symbol_table, MAX_STEPS)
+            .map_err(|trap| format!("The program trapped: {}", trap))?;
+        self.source = source;
+        self.program = program;
+        Ok(String::new())
+    }
+
+    fn evaluate(&self, tokens: VecDeque<Token>) -> Result<String, String> {
+        let (expr, _type) = self.parse_expression(tokens)?;
+        let value = evaluate_after(
+            &self.program.statements,
+            &self.program.symbol_table,
+            &expr,
+            MAX_STEPS,
+        )
+        .map_err(|trap| format!("The program trapped: {}", trap))?;
+        let value = match _type {
+            Type::Int => value.to_string(),
+            Type::Bool => (value != 0).to_string(),
+            _ => format!("{:#x}", value),
+        };
+        Ok(format!("{}: {}", value, describe_type(&_type)))
+    }
+
+    fn parse_expression(&self, mut tokens: VecDeque<Token>) -> Result<(Expression, Type), String> {
+        let symbol_table = &self.program.symbol_table;
+        catch_panic(|| {
+            let parsed = parse_expression(&mut tokens, symbol_table);
+            if let Some(token) = tokens.front() {
+                panic!("Unexpected {} after the expression", token.lexeme());
+            }
+            parsed
+        })
+        .map_err(|message| format!("error while parsing: {}", message))
+    }
+
+    // The tree of a statement or expression, without running it or keeping it
+    fn tree(&self, input: &str) -> Result<String, String> {
+        let tokens = lex(input).map_err(describe)?;
+        if !is_statement(&tokens) {
+            let (expr, _) = self.parse_expression(tokens)?;
+            return Ok(expression_tree(&expr));
+        }
+        let source = format!("{}{}\n", self.source, input);
+        let program = lex(&source).and_then(parse).map_err(describe)?;
+        Ok(program
+            .statements
+            .iter()
+            .skip(self.program.statements.len())
+            .map(statement_tree)
+            .collect::<Vec<String>>()
+            .join("\n"))
+    }
+}
+
+// Statements start with a keyword or a block, or assign to a variable. Anything else is taken
+// to be an expression
+fn is_statement(tokens: &VecDeque<Token>) -> bool {
+    match tokens.front().map(|token| token.lexeme()) {
+        Some("const" | "mut" | "if" | "while" | "break" | "{") => true,
+        _ => tokens
+            .get(1)
+            .is_some_and(|token| *token.token_type() == TokenType::Assignment),
+    }
+}
+
+// Types as they are written in the language, where there is a way to write them
+fn describe_type(_type: &Type) -> String {
+    match _type {
+        Type::Array(_, _) | Type::None => format!("{:?}", _type),
+        _ => type_name(_type),
+    }
+}
+
+fn describe(diagnostics: Vec<Diagnostic>) -> String {
+    diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_variables_between_inputs() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.eval("mut int x = 4;"), Ok(String::new()));
+        assert_eq!(
+            repl.eval("while x < 10 {\n    x = x + 3;\n}"),
+            Ok(String::new())
+        );
+        assert_eq!(repl.eval("x * 2"), Ok("20: int".to_string()));
+        assert_eq!(repl.eval("x == 10"), Ok("true: bool".to_string()));
+        assert_eq!(repl.eval(":ast x + 1"), Ok("(x + 1)".to_string()));
+        assert!(repl.eval(":asm").unwrap().contains("syscall"));
+    }
+
+    #[test]
+    fn rejected_statements_leave_the_session_alone() {
+        let mut repl = Repl::new();
+        repl.eval("const int a = 1;").unwrap();
+        let error = repl.eval("const int b = a / 0;").unwrap_err();
+        assert!(error.contains("division by zero"), "{}", error);
+        assert!(repl
+            .eval("y")
+            .unwrap_err()
+            .starts_with("error while parsing"));
+        assert_eq!(repl.eval(":symbols"), Ok("const int a".to_string()));
+
+        repl.eval(":reset").unwrap();
+        assert_eq!(repl.eval(":symbols"), Ok(String::new()));
+        assert!(repl.eval("a").is_err());
+    }
+}
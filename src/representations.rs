@@ -123,6 +123,15 @@ pub struct Symbol {
     pub mutable: bool,
     pub init_line: usize,
     pub last_ref: usize,
+    pub live_range: Option<LiveRange>,
+}
+
+// The first and last program points where a symbol's value is needed. Symbols whose ranges
+// don't overlap can share a stack slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveRange {
+    pub start: usize,
+    pub end: usize,
 }
 
 #[derive(Debug)]
@@ -0,0 +1,178 @@
+use std::fmt;
+use std::io::{self, Read};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Running a compiled program without trusting it to behave. It gets a wall-clock timeout and
+// limits on CPU time, memory and stack, so a program that loops forever or runs away with
+// memory is stopped instead of taking the compiler down with it. What it prints is captured
+// rather than passed through
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub timeout: Duration,
+    pub cpu_seconds: u64,
+    pub memory_bytes: u64,
+    pub stack_bytes: u64,
+}
+
+// How a program's run ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunResult {
+    // None if the program was killed by a signal
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    // Whether the program was killed for going over the timeout
+    pub timed_out: bool,
+    pub duration: Duration,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            timeout: Duration::from_secs(10),
+            cpu_seconds: 10,
+            memory_bytes: 256 << 20,
+            stack_bytes: 8 << 20,
+        }
+    }
+}
+
+// Run a program with the limits applied, in its own process group so that anything it starts is
+// killed along with it
+pub fn run_sandboxed(command: &mut Command, limits: &Limits) -> io::Result<RunResult> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+    // The hard limit on CPU time is a second past the soft one, so that a program that goes over
+    // is sent SIGXCPU and it's clear why it stopped
+    let rlimits = [
+        (libc::RLIMIT_CPU, limits.cpu_seconds, limits.cpu_seconds + 1),
+        (libc::RLIMIT_AS, limits.memory_bytes, limits.memory_bytes),
+        (libc::RLIMIT_STACK, limits.stack_bytes, limits.stack_bytes),
+    ];
+    // SAFETY: setrlimit is async-signal-safe, and nothing is allocated between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, soft, hard) in rlimits {
+                let rlimit = libc::rlimit {
+                    rlim_cur: soft,
+                    rlim_max: hard,
+                };
+                if libc::setrlimit(resource, &rlimit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    let start = Instant::now();
+    let mut child = command.spawn()?;
+    // The pipes are drained as the program runs so that it never blocks on a full one
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+    let (status, timed_out) = wait_with_timeout(&mut child, limits.timeout)?;
+    let duration = start.elapsed();
+    Ok(RunResult {
+        exit_code: status.code(),
+        signal: status.signal(),
+        timed_out,
+        duration,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn drain(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        output
+    })
+}
+
+fn wait_with_timeout(
+    child: &mut Child,
+    timeout: Duration,
+) -> io::Result<(std::process::ExitStatus, bool)> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, false));
+        }
+        if start.elapsed() > timeout {
+            // SAFETY: kill only sends a signal, to the process group the program leads
+            unsafe {
+                libc::kill(-(child.id() as i32), libc::SIGKILL);
+            }
+            return Ok((child.wait()?, true));
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn signal_name(signal: i32) -> &'static str {
+    match signal {
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGILL => "SIGILL",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGXCPU => "SIGXCPU",
+        _ => "an unknown signal",
+    }
+}
+
+impl fmt::Display for RunResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.timed_out {
+            write!(f, "timed out")?;
+        } else if let Some(signal) = self.signal {
+            write!(f, "killed by signal {} ({})", signal, signal_name(signal))?;
+        } else if let Some(code) = self.exit_code {
+            write!(f, "exited with status {}", code)?;
+        }
+        write!(f, " after {:.2?}", self.duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_shell(script: &str, limits: &Limits) -> RunResult {
+        run_sandboxed(Command::new("sh").arg("-c").arg(script), limits)
+            .expect("The shell should start")
+    }
+
+    #[test]
+    fn captures_output_and_exit_code() {
+        let result = run_shell("echo out; echo err >&2; exit 7", &Limits::default());
+        assert_eq!(result.exit_code, Some(7));
+        assert_eq!(result.signal, None);
+        assert_eq!(result.stdout, b"out\n");
+        assert_eq!(result.stderr, b"err\n");
+    }
+
+    #[test]
+    fn kills_programs_that_run_too_long() {
+        let limits = Limits {
+            timeout: Duration::from_millis(100),
+            ..Limits::default()
+        };
+        let result = run_shell("while true; do :; done", &limits);
+        assert!(result.timed_out);
+        assert_eq!(result.signal, Some(libc::SIGKILL));
+        assert!(result.to_string().starts_with("timed out"));
+    }
+}
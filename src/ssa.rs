@@ -0,0 +1,478 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::cfg::{remove_unreachable_blocks, ControlFlowGraph, DominatorTree};
+use crate::ir::{BasicBlock, BlockId, Function, Instruction, Operand, Temp, Terminator};
+
+// Convert the function into SSA form. Every variable that never has its address taken is
+// promoted out of memory: its loads and stores are removed, and a phi node is placed wherever
+// different values of the variable meet. Variables read before they are written on some path
+// get the value 0 along that path. Temps defined more than once, as they are in IR that has
+// been converted out of SSA form, are promoted the same way
+pub fn construct_ssa(function: &mut Function) {
+    // Dominance is only defined for blocks the entry can reach
+    remove_unreachable_blocks(function);
+    demote_redefined_temps(function);
+    let cfg = ControlFlowGraph::new(function);
+    let dominator_tree = DominatorTree::new(&cfg);
+    let frontiers = dominator_tree.dominance_frontiers(&cfg);
+
+    // Work out which variables can be promoted and which blocks write to each of them
+    let mut escaped = HashSet::new();
+    let mut def_blocks = HashMap::<String, HashSet<BlockId>>::new();
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            match instruction {
+                Instruction::Store(id, _) => {
+                    def_blocks.entry(id.clone()).or_default().insert(block.id);
+                }
+                Instruction::Load(_, id) => {
+                    def_blocks.entry(id.clone()).or_default();
+                }
+                Instruction::AddressOf(_, id) => {
+                    escaped.insert(id.clone());
+                }
+                _ => (),
+            }
+        }
+    }
+    def_blocks.retain(|id, _| !escaped.contains(id));
+
+    // Place phi nodes on the iterated dominance frontier of every block that writes a variable.
+    // Blocks are visited in order so that temps are numbered the same way on every run
+    let mut phi_variables = HashMap::<Temp, String>::new();
+    let mut variables: Vec<&String> = def_blocks.keys().collect();
+    variables.sort();
+    for id in variables {
+        let mut has_phi = HashSet::new();
+        let mut worklist: Vec<BlockId> = def_blocks[id].iter().copied().collect();
+        worklist.sort_by(|a, b| b.cmp(a));
+        while let Some(block) = worklist.pop() {
+            let mut block_frontiers: Vec<&BlockId> = frontiers[&block].iter().collect();
+            block_frontiers.sort();
+            for frontier in block_frontiers {
+                if has_phi.insert(*frontier) {
+                    let dest = function.new_temp();
+                    function
+                        .block_mut(*frontier)
+                        .instructions
+                        .insert(0, Instruction::Phi(dest, Vec::new()));
+                    phi_variables.insert(dest, id.clone());
+                    if !def_blocks[id].contains(frontier) {
+                        worklist.push(*frontier);
+                    }
+                }
+            }
+        }
+    }
+
+    // Walk the dominator tree, replacing each load with the value of the variable at that point.
+    // A block starts with the values its immediate dominator ended with
+    let mut replacements = HashMap::<Temp, Operand>::new();
+    let mut block_values = HashMap::<BlockId, HashMap<String, Operand>>::new();
+    for block_id in dominator_tree.preorder(cfg.entry) {
+        let dominator = dominator_tree.immediate_dominators[&block_id];
+        let mut values = if dominator == block_id {
+            HashMap::new()
+        } else {
+            block_values[&dominator].clone()
+        };
+
+        let block = function.block_mut(block_id);
+        let instructions = std::mem::take(&mut block.instructions);
+        for instruction in instructions {
+            match instruction {
+                Instruction::Phi(dest, _) if phi_variables.contains_key(&dest) => {
+                    values.insert(phi_variables[&dest].clone(), Operand::Temp(dest));
+                    block.instructions.push(instruction);
+                }
+                Instruction::Load(dest, id) if def_blocks.contains_key(&id) => {
+                    let value = values.get(&id).copied().unwrap_or(Operand::Const(0));
+                    replacements.insert(dest, value);
+                }
+                Instruction::Store(id, value) if def_blocks.contains_key(&id) => {
+                    values.insert(id, resolve(&replacements, value));
+                }
+                _ => block.instructions.push(instruction),
+            }
+        }
+
+        // Fill in this block's entry in the phis of its successors
+        for successor in &cfg.successors[&block_id] {
+            for instruction in &mut function.block_mut(*successor).instructions {
+                if let Instruction::Phi(dest, incoming) = instruction {
+                    if let Some(id) = phi_variables.get(dest) {
+                        let value = values.get(id).copied().unwrap_or(Operand::Const(0));
+                        incoming.push((block_id, value));
+                    }
+                }
+            }
+        }
+        block_values.insert(block_id, values);
+    }
+
+    // Point every use of a removed load at the value it was replaced with
+    for block in &mut function.blocks {
+        for instruction in &mut block.instructions {
+            for operand in instruction.operands_mut() {
+                *operand = resolve(&replacements, *operand);
+            }
+        }
+        for operand in block.terminator.operands_mut() {
+            *operand = resolve(&replacements, *operand);
+        }
+    }
+
+    remove_dead_phis(function);
+}
+
+// Turn every temp that is defined more than once into a variable, so that promoting variables
+// gives each of its definitions a temp of its own. Each definition gets a fresh temp that is
+// stored to the variable, and each use loads it back first. The variables are named so that
+// they can't clash with the program's own, and never escape, so none of them outlive promotion
+fn demote_redefined_temps(function: &mut Function) {
+    let mut definition_counts = HashMap::<Temp, usize>::new();
+    for block in &function.blocks {
+        for dest in block.instructions.iter().filter_map(Instruction::dest) {
+            *definition_counts.entry(dest).or_default() += 1;
+        }
+    }
+    let redefined: HashSet<Temp> = definition_counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(temp, _)| temp)
+        .collect();
+    if redefined.is_empty() {
+        return;
+    }
+    for index in 0..function.blocks.len() {
+        let instructions = std::mem::take(&mut function.blocks[index].instructions);
+        let mut demoted = Vec::with_capacity(instructions.len());
+        for mut instruction in instructions {
+            load_redefined(
+                function,
+                &redefined,
+                instruction.operands_mut(),
+                &mut demoted,
+            );
+            let dest = instruction.dest().filter(|dest| redefined.contains(dest));
+            let Some(dest) = dest else {
+                demoted.push(instruction);
+                continue;
+            };
+            let fresh = function.new_temp();
+            *instruction.dest_mut().expect("The instruction has a dest") = fresh;
+            demoted.push(instruction);
+            demoted.push(Instruction::Store(
+                demoted_variable(dest),
+                Operand::Temp(fresh),
+            ));
+        }
+        let mut terminator = function.blocks[index].terminator.clone();
+        load_redefined(
+            function,
+            &redefined,
+            terminator.operands_mut(),
+            &mut demoted,
+        );
+        let block = &mut function.blocks[index];
+        block.instructions = demoted;
+        block.terminator = terminator;
+    }
+}
+
+// Replace each use of a demoted temp with a load of its variable, added to the end of
+// instructions
+fn load_redefined(
+    function: &mut Function,
+    redefined: &HashSet<Temp>,
+    operands: Vec<&mut Operand>,
+    instructions: &mut Vec<Instruction>,
+) {
+    for operand in operands {
+        if let Operand::Temp(temp) = operand {
+            if redefined.contains(temp) {
+                let loaded = function.new_temp();
+                instructions.push(Instruction::Load(loaded, demoted_variable(*temp)));
+                *temp = loaded;
+            }
+        }
+    }
+}
+
+fn demoted_variable(temp: Temp) -> String {
+    format!("%{}", temp)
+}
+
+// Follow a chain of replaced loads to the value that ends up being used
+fn resolve(replacements: &HashMap<Temp, Operand>, operand: Operand) -> Operand {
+    let mut operand = operand;
+    while let Operand::Temp(temp) = operand {
+        match replacements.get(&temp) {
+            Some(replacement) => operand = *replacement,
+            None => break,
+        }
+    }
+    operand
+}
+
+// Phis are placed for every variable on the frontier, even where the variable is never read
+// again. Remove any phi whose value isn't used by anything other than other dead phis
+fn remove_dead_phis(function: &mut Function) {
+    let mut live = HashSet::<Temp>::new();
+    let mut worklist = Vec::<Temp>::new();
+    let mut phi_operands = HashMap::<Temp, Vec<Temp>>::new();
+    for block in &function.blocks {
+        for instruction in &block.instructions {
+            let used = instruction.operands();
+            match instruction {
+                Instruction::Phi(dest, _) => {
+                    phi_operands.insert(*dest, used.iter().filter_map(|o| o.as_temp()).collect());
+                }
+                _ => worklist.extend(used.iter().filter_map(|o| o.as_temp())),
+            }
+        }
+        worklist.extend(
+            block
+                .terminator
+                .operands()
+                .iter()
+                .filter_map(|o| o.as_temp()),
+        );
+    }
+    while let Some(temp) = worklist.pop() {
+        if live.insert(temp) {
+            if let Some(operands) = phi_operands.get(&temp) {
+                worklist.extend(operands);
+            }
+        }
+    }
+    for block in &mut function.blocks {
+        block.instructions.retain(|instruction| match instruction {
+            Instruction::Phi(dest, _) => live.contains(dest),
+            _ => true,
+        });
+    }
+}
+
+// Convert the function back out of SSA form by replacing each phi with copies. Every phi gets a
+// fresh temp which each predecessor copies its value into just before jumping, and the phi
+// becomes a copy from that temp. Reading all the incoming values before any phi is written
+// keeps phis that swap values correct. Edges from a block with several successors into a block
+// with phis get a block of their own so the copies only run along that edge
+pub fn destruct_ssa(function: &mut Function) {
+    let cfg = ControlFlowGraph::new(function);
+    let phi_blocks: Vec<BlockId> = function
+        .blocks
+        .iter()
+        .filter(|block| block.instructions.first().is_some_and(Instruction::is_phi))
+        .map(|block| block.id)
+        .collect();
+
+    for block_id in phi_blocks {
+        // Split critical edges into this block
+        let mut predecessors = HashMap::<BlockId, BlockId>::new();
+        for pred in &cfg.predecessors[&block_id] {
+            if cfg.successors[pred].len() > 1 {
+                let split_id = function.new_block_id();
+                function
+                    .block_mut(*pred)
+                    .terminator
+                    .replace_successor(block_id, split_id);
+                let position = function
+                    .blocks
+                    .iter()
+                    .position(|block| block.id == block_id)
+                    .expect("Block is in the function");
+                function.blocks.insert(
+                    position,
+                    BasicBlock {
+                        id: split_id,
+                        instructions: Vec::new(),
+                        terminator: Terminator::Jump(block_id),
+                    },
+                );
+                predecessors.insert(*pred, split_id);
+            } else {
+                predecessors.insert(*pred, *pred);
+            }
+        }
+
+        let phi_count = function
+            .block(block_id)
+            .instructions
+            .iter()
+            .take_while(|instruction| instruction.is_phi())
+            .count();
+        let phis: Vec<Instruction> = function
+            .block_mut(block_id)
+            .instructions
+            .drain(..phi_count)
+            .collect();
+        let mut copies = Vec::new();
+        for phi in phis {
+            let Instruction::Phi(dest, incoming) = phi else {
+                panic!("Only phis were taken from the start of the block")
+            };
+            let transfer = function.new_temp();
+            for (pred, value) in incoming {
+                function
+                    .block_mut(predecessors[&pred])
+                    .instructions
+                    .push(Instruction::Copy(transfer, value));
+            }
+            copies.push(Instruction::Copy(dest, Operand::Temp(transfer)));
+        }
+        function
+            .block_mut(block_id)
+            .instructions
+            .splice(0..0, copies);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::BinaryOp;
+    use crate::ir_text::parse_ir;
+
+    fn phis(function: &Function, block: u32) -> Vec<&Instruction> {
+        function
+            .block(BlockId(block))
+            .instructions
+            .iter()
+            .filter(|instruction| instruction.is_phi())
+            .collect()
+    }
+
+    #[test]
+    fn places_phis_where_different_values_meet() {
+        let (mut function, _) = parse_ir(
+            "mut x: int
+            mut y: int
+
+            block_0:
+                store x, 1
+                store y, 5
+                branch 1, block_1, block_2
+            block_1:
+                store x, 2
+                jump block_3
+            block_2:
+                jump block_3
+            block_3:
+                t0 = load x
+                t1 = load y
+                t2 = add t0, t1
+                exit t2",
+        );
+        construct_ssa(&mut function);
+        // x has a different value on each side, while y is the same on both, so only x needs a
+        // phi and every load is gone
+        let joined = phis(&function, 3);
+        let [Instruction::Phi(dest, incoming)] = joined.as_slice() else {
+            panic!("Expected one phi, got {:?}", joined)
+        };
+        let mut incoming = incoming.clone();
+        incoming.sort_by_key(|(block, _)| *block);
+        assert_eq!(
+            incoming,
+            vec![
+                (BlockId(1), Operand::Const(2)),
+                (BlockId(2), Operand::Const(1))
+            ]
+        );
+        assert!(function
+            .blocks
+            .iter()
+            .all(
+                |block| block.instructions.iter().all(|instruction| !matches!(
+                    instruction,
+                    Instruction::Load(..) | Instruction::Store(..)
+                ))
+            ));
+        assert_eq!(
+            function.block(BlockId(3)).instructions.last(),
+            Some(&Instruction::Binary(
+                Temp(2),
+                BinaryOp::Add,
+                Operand::Temp(*dest),
+                Operand::Const(5)
+            ))
+        );
+    }
+
+    #[test]
+    fn splits_critical_edges_when_removing_phis() {
+        // block_0 branches straight to block_2 as well as through block_1, so the copy for the
+        // edge from block_0 needs a block of its own
+        let (mut function, _) = parse_ir(
+            "block_0:
+                branch 1, block_1, block_2
+            block_1:
+                jump block_2
+            block_2:
+                t0 = phi [block_0: 1], [block_1: 2]
+                exit t0",
+        );
+        destruct_ssa(&mut function);
+        let Terminator::Branch(_, _, split) = function.block(BlockId(0)).terminator else {
+            panic!("block_0 should still branch")
+        };
+        assert_eq!(split, BlockId(3));
+        let split_block = function.block(split);
+        assert_eq!(split_block.terminator, Terminator::Jump(BlockId(2)));
+        assert_eq!(
+            split_block.instructions,
+            vec![Instruction::Copy(Temp(1), Operand::Const(1))]
+        );
+        // block_1 only has one successor, so its copy goes in it directly
+        assert_eq!(
+            function.block(BlockId(1)).instructions,
+            vec![Instruction::Copy(Temp(1), Operand::Const(2))]
+        );
+        assert_eq!(
+            function.block(BlockId(2)).instructions,
+            vec![Instruction::Copy(Temp(0), Operand::Temp(Temp(1)))]
+        );
+        assert!(phis(&function, 2).is_empty());
+    }
+
+    #[test]
+    fn gives_temps_defined_more_than_once_a_phi() {
+        // IR that has been converted out of SSA form, where t3 is set before the loop and at the
+        // end of each iteration
+        let (mut function, symbol_table) = parse_ir(
+            "block_0:
+                t3 = copy 0
+                jump block_1
+            block_1:
+                t0 = copy t3
+                t1 = lt t0, 5
+                branch t1, block_2, block_3
+            block_2:
+                t2 = add t0, 1
+                t3 = copy t2
+                jump block_1
+            block_3:
+                exit t0",
+        );
+        construct_ssa(&mut function);
+        assert_eq!(
+            crate::verifier::verify(&function, &symbol_table),
+            Vec::<String>::new()
+        );
+        let header_phis = phis(&function, 1);
+        let [Instruction::Phi(_, incoming)] = header_phis.as_slice() else {
+            panic!("Expected one phi, got {:?}", header_phis)
+        };
+        let mut incoming = incoming.clone();
+        incoming.sort_by_key(|(block, _)| *block);
+        // Each definition gets a temp of its own
+        assert_eq!(incoming[0], (BlockId(0), Operand::Temp(Temp(4))));
+        assert_eq!(
+            function.block(BlockId(0)).instructions,
+            vec![Instruction::Copy(Temp(4), Operand::Const(0))]
+        );
+        assert_eq!(incoming[1].0, BlockId(2));
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+// How long each phase of compiling and running a program took, and how much it produced, for
+// --time-passes. It can be printed as a table to read or as JSON to keep track of in CI
+
+// One phase, with how many things it produced if that means anything for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhaseTime {
+    pub phase: &'static str,
+    pub duration: Duration,
+    pub items: Option<(usize, &'static str)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TimeReport {
+    pub phases: Vec<PhaseTime>,
+}
+
+impl TimeReport {
+    pub fn new() -> TimeReport {
+        TimeReport::default()
+    }
+
+    // Record a phase that started at start and has just finished
+    pub fn record(
+        &mut self,
+        phase: &'static str,
+        start: Instant,
+        items: Option<(usize, &'static str)>,
+    ) {
+        self.phases.push(PhaseTime {
+            phase,
+            duration: start.elapsed(),
+            items,
+        });
+    }
+
+    pub fn append(&mut self, other: TimeReport) {
+        self.phases.extend(other.phases);
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|phase| phase.duration).sum()
+    }
+
+    pub fn table(&self) -> String {
+        let mut table = format!("{:<18}{:>12}  {}\n", "phase", "time", "items");
+        for phase in &self.phases {
+            let items = match phase.items {
+                Some((count, unit)) => format!("{} {}", count, unit),
+                None => String::new(),
+            };
+            let duration = format!("{:.2?}", phase.duration);
+            let line = format!("{:<18}{:>12}  {}", phase.phase, duration, items);
+            let _ = writeln!(table, "{}", line.trim_end());
+        }
+        let total = format!("{:.2?}", self.total());
+        let _ = writeln!(table, "{:<18}{:>12}", "total", total);
+        table
+    }
+
+    // Times are in nanoseconds so that they stay whole numbers
+    pub fn json(&self) -> String {
+        let phases: Vec<String> = self
+            .phases
+            .iter()
+            .map(|phase| {
+                let mut object = format!(
+                    "{{\"phase\": \"{}\", \"nanoseconds\": {}",
+                    phase.phase,
+                    phase.duration.as_nanos()
+                );
+                if let Some((count, unit)) = phase.items {
+                    let _ = write!(object, ", \"items\": {}, \"unit\": \"{}\"", count, unit);
+                }
+                object.push('}');
+                object
+            })
+            .collect();
+        format!(
+            "{{\"phases\": [{}], \"total_nanoseconds\": {}}}\n",
+            phases.join(", "),
+            self.total().as_nanos()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> TimeReport {
+        TimeReport {
+            phases: vec![
+                PhaseTime {
+                    phase: "lexing",
+                    duration: Duration::from_micros(3),
+                    items: Some((14, "tokens")),
+                },
+                PhaseTime {
+                    phase: "running",
+                    duration: Duration::from_millis(2),
+                    items: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn prints_a_table_and_json() {
+        let report = report();
+        let table = report.table();
+        assert!(table.contains("lexing"), "{}", table);
+        assert!(table.contains("14 tokens"), "{}", table);
+        assert!(
+            table.lines().last().unwrap().starts_with("total"),
+            "{}",
+            table
+        );
+        assert_eq!(
+            report.json(),
+            "{\"phases\": [{\"phase\": \"lexing\", \"nanoseconds\": 3000, \"items\": 14, \
+             \"unit\": \"tokens\"}, {\"phase\": \"running\", \"nanoseconds\": 2000000}], \
+             \"total_nanoseconds\": 2003000}\n"
+        );
+    }
+}
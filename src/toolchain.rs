@@ -0,0 +1,157 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{Diagnostic, Phase};
+
+// Turning a program's assembly into an executable with an external assembler and linker, nasm
+// and ld unless told otherwise. The assembly and object files are kept in a temporary directory,
+// so the only thing left behind is the executable
+
+const ASSEMBLER_FLAGS: [&str; 5] = ["-ggdb", "-F", "dwarf", "-f", "elf64"];
+const LINKER_FLAGS: [&str; 2] = ["-m", "elf_x86_64"];
+
+// How many temporary directories this process has made, to keep their names apart
+static TEMP_DIR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub struct ExternalToolchain {
+    pub assembler: PathBuf,
+    // Passed after the flags asking for a 64 bit ELF object with debug information
+    pub assembler_flags: Vec<String>,
+    pub linker: PathBuf,
+    // Passed after the flags asking for an x86-64 executable
+    pub linker_flags: Vec<String>,
+}
+
+// A directory that is removed along with everything in it once it's dropped
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl Default for ExternalToolchain {
+    fn default() -> Self {
+        ExternalToolchain {
+            assembler: PathBuf::from("nasm"),
+            assembler_flags: Vec::new(),
+            linker: PathBuf::from("ld"),
+            linker_flags: Vec::new(),
+        }
+    }
+}
+
+impl ExternalToolchain {
+    // Assemble a program in directory, returning the path of the object file
+    pub fn assemble(&self, assembly: &str, directory: &Path) -> Result<PathBuf, Vec<Diagnostic>> {
+        let source = directory.join("program.asm");
+        let object = directory.join("program.o");
+        std::fs::write(&source, assembly).map_err(|error| {
+            diagnostic(
+                Phase::Assembling,
+                format!("couldn't write {}: {}", source.display(), error),
+            )
+        })?;
+        let mut command = Command::new(&self.assembler);
+        command
+            .args(ASSEMBLER_FLAGS)
+            .args(&self.assembler_flags)
+            .arg("-o")
+            .arg(&object)
+            .arg(&source);
+        run_tool(Phase::Assembling, &mut command)?;
+        Ok(object)
+    }
+
+    pub fn link(&self, object: &Path, output: &Path) -> Result<(), Vec<Diagnostic>> {
+        let mut command = Command::new(&self.linker);
+        command
+            .args(LINKER_FLAGS)
+            .args(&self.linker_flags)
+            .arg("-o")
+            .arg(output)
+            .arg(object);
+        run_tool(Phase::Linking, &mut command)
+    }
+}
+
+impl TempDir {
+    // Create a directory under the system's temporary directory, unique to this call so that
+    // threads in the same process don't share one
+    pub fn new(name: &str) -> TempDir {
+        let count = TEMP_DIR_COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!(
+            "testcomp-{}-{}-{}",
+            name,
+            std::process::id(),
+            count
+        ));
+        std::fs::create_dir_all(&path).expect("Should be able to create a temporary directory");
+        TempDir { path }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+// Run a tool, reporting what it printed to stderr if it fails
+fn run_tool(phase: Phase, command: &mut Command) -> Result<(), Vec<Diagnostic>> {
+    let tool = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output()
+        .map_err(|error| diagnostic(phase, format!("couldn't run {}: {}", tool, error)))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(diagnostic(
+        phase,
+        format!(
+            "{} failed with {}\n{}",
+            tool,
+            output.status,
+            stderr.trim_end()
+        ),
+    ))
+}
+
+fn diagnostic(phase: Phase, message: String) -> Vec<Diagnostic> {
+    vec![Diagnostic { phase, message }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn reports_tool_failures_with_their_stderr() {
+        let directory = TempDir::new("toolchain-test");
+        let assembler = directory.path.join("assembler");
+        std::fs::write(&assembler, "#!/bin/sh\necho \"bad operand\" >&2\nexit 3\n").unwrap();
+        std::fs::set_permissions(&assembler, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let toolchain = ExternalToolchain {
+            assembler,
+            linker: PathBuf::from("testcomp-no-such-linker"),
+            ..ExternalToolchain::default()
+        };
+
+        let diagnostics = toolchain
+            .assemble("mov rax, 1", &directory.path)
+            .expect_err("The assembler fails");
+        assert_eq!(diagnostics[0].phase, Phase::Assembling);
+        assert!(diagnostics[0].message.ends_with("bad operand"));
+
+        let diagnostics = toolchain
+            .link(
+                &directory.path.join("program.o"),
+                &directory.path.join("program"),
+            )
+            .expect_err("There is no such linker");
+        assert_eq!(diagnostics[0].phase, Phase::Linking);
+        assert!(diagnostics[0]
+            .message
+            .starts_with("couldn't run testcomp-no-such-linker"));
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// Debugging output from inside the compiler, grouped by the subsystem it comes from. Every
+// subsystem is silent until it is enabled, and traces go to stderr so they never get mixed up
+// with what the compiler normally prints
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    // Every token produced
+    Lexer,
+    // Lookaheads and the types the parser works out for expressions
+    Parser,
+    // Each statement's tree once the program is parsed
+    Ast,
+    // Where every variable ended up
+    Backend,
+}
+
+pub const SUBSYSTEM_NAMES: [&str; 4] = ["lexer", "parser", "ast", "backend"];
+
+// One bit for each subsystem
+static ENABLED: AtomicU8 = AtomicU8::new(0);
+
+impl Subsystem {
+    pub fn name(&self) -> &'static str {
+        SUBSYSTEM_NAMES[*self as usize]
+    }
+
+    pub fn from_name(name: &str) -> Subsystem {
+        match name {
+            "lexer" => Subsystem::Lexer,
+            "parser" => Subsystem::Parser,
+            "ast" => Subsystem::Ast,
+            "backend" => Subsystem::Backend,
+            _ => panic!("Unrecognised trace subsystem {}", name),
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        1 << *self as u8
+    }
+}
+
+pub fn enable(subsystem: Subsystem) {
+    ENABLED.fetch_or(subsystem.bit(), Ordering::Relaxed);
+}
+
+pub fn enabled(subsystem: Subsystem) -> bool {
+    ENABLED.load(Ordering::Relaxed) & subsystem.bit() != 0
+}
+
+// Print a line for a subsystem if it is being traced, like eprintln! with the subsystem's name
+// in front. The arguments aren't evaluated otherwise
+#[macro_export]
+macro_rules! trace {
+    ($subsystem:ident, $($arg:tt)*) => {
+        if $crate::trace::enabled($crate::trace::Subsystem::$subsystem) {
+            eprintln!(
+                "[{}] {}",
+                $crate::trace::Subsystem::$subsystem.name(),
+                format_args!($($arg)*)
+            );
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subsystems_are_silent_until_enabled() {
+        assert!(!enabled(Subsystem::Lexer));
+        enable(Subsystem::from_name("lexer"));
+        assert!(enabled(Subsystem::Lexer));
+        assert!(!enabled(Subsystem::Backend));
+        for name in SUBSYSTEM_NAMES {
+            assert_eq!(Subsystem::from_name(name).name(), name);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::cfg::{ControlFlowGraph, DominatorTree};
+use crate::ir::{BlockId, Function, Instruction, Operand, Temp};
+use crate::representations::Symbol;
+
+// Where a temp is defined or used, as (block, position in the block). The terminator comes after
+// every instruction in its block
+type Location = (BlockId, usize);
+
+// Check that a function is well formed, returning a description of every problem found. Every
+// block must have a unique id, jump to blocks that exist and only have phis at its start, and
+// every variable must be in the symbol table. Every temp that is used must be defined, and
+// temps with a single definition must be defined somewhere that runs before every use. Temps
+// may only be defined more than once outside of SSA form, when there are no phis
+pub fn verify(function: &Function, symbol_table: &HashMap<String, Symbol>) -> Vec<String> {
+    let mut problems = Vec::new();
+    if function.blocks.is_empty() {
+        problems.push("The function has no blocks".to_string());
+        return problems;
+    }
+
+    let mut block_ids = HashSet::new();
+    for block in &function.blocks {
+        if !block_ids.insert(block.id) {
+            problems.push(format!("{} is defined more than once", block.id));
+        }
+        if block.id.0 >= function.block_count {
+            problems.push(format!("{} is past the function's block count", block.id));
+        }
+    }
+    for block in &function.blocks {
+        for successor in block.terminator.successors() {
+            if !block_ids.contains(&successor) {
+                problems.push(format!(
+                    "{} jumps to {}, which doesn't exist",
+                    block.id, successor
+                ));
+            }
+        }
+    }
+    // The CFG can't be built with jumps to missing blocks
+    if !problems.is_empty() {
+        return problems;
+    }
+
+    let mut definitions = HashMap::<Temp, Vec<Location>>::new();
+    let mut uses = Vec::<(Temp, Location)>::new();
+    let mut phi_uses = Vec::<(Temp, BlockId, BlockId)>::new();
+    let mut has_phis = false;
+    for block in &function.blocks {
+        let mut past_phis = false;
+        for (position, instruction) in block.instructions.iter().enumerate() {
+            match instruction {
+                Instruction::Phi(_, incoming) => {
+                    has_phis = true;
+                    if past_phis {
+                        problems.push(format!(
+                            "{} has a phi after other instructions: {}",
+                            block.id, instruction
+                        ));
+                    }
+                    for (pred, operand) in incoming {
+                        if let Operand::Temp(temp) = operand {
+                            phi_uses.push((*temp, block.id, *pred));
+                        }
+                    }
+                }
+                _ => {
+                    past_phis = true;
+                    for temp in instruction.operands().iter().filter_map(Operand::as_temp) {
+                        uses.push((temp, (block.id, position)));
+                    }
+                }
+            }
+            match instruction {
+                Instruction::Load(_, id)
+                | Instruction::Store(id, _)
+                | Instruction::AddressOf(_, id)
+                    if !symbol_table.contains_key(id) =>
+                {
+                    problems.push(format!("{} uses undeclared variable {}", block.id, id));
+                }
+                _ => (),
+            }
+            if let Some(dest) = instruction.dest() {
+                definitions
+                    .entry(dest)
+                    .or_default()
+                    .push((block.id, position));
+            }
+        }
+        for temp in block
+            .terminator
+            .operands()
+            .iter()
+            .filter_map(Operand::as_temp)
+        {
+            uses.push((temp, (block.id, block.instructions.len())));
+        }
+    }
+
+    for (temp, locations) in &definitions {
+        if temp.0 >= function.temp_count {
+            problems.push(format!("{} is past the function's temp count", temp));
+        }
+        if has_phis && locations.len() > 1 {
+            problems.push(format!("{} is defined more than once in SSA form", temp));
+        }
+    }
+
+    let cfg = ControlFlowGraph::new(function);
+    let dominator_tree = DominatorTree::new(&cfg);
+    // Code that can't be reached is never run, so only reachable uses have to be dominated
+    let runs_before = |definition: Location, location: Location| {
+        if definition.0 == location.0 {
+            definition.1 < location.1
+        } else {
+            dominator_tree.dominates(definition.0, location.0)
+        }
+    };
+    for (temp, location) in uses {
+        match definitions.get(&temp).map(Vec::as_slice) {
+            None => problems.push(format!(
+                "{} is used in {} but never defined",
+                temp, location.0
+            )),
+            Some([definition]) if cfg.is_reachable(location.0) => {
+                if !runs_before(*definition, location) {
+                    problems.push(format!(
+                        "{} is used in {} before it is defined",
+                        temp, location.0
+                    ));
+                }
+            }
+            Some(_) => (),
+        }
+    }
+    // A value coming into a phi has to be available at the end of the block it comes from
+    for (temp, block, pred) in phi_uses {
+        match definitions.get(&temp).map(Vec::as_slice) {
+            None => problems.push(format!("{} is used in {} but never defined", temp, block)),
+            Some([definition]) if cfg.is_reachable(pred) => {
+                if definition.0 != pred && !dominator_tree.dominates(definition.0, pred) {
+                    problems.push(format!(
+                        "{} comes into a phi in {} from {}, where it isn't defined",
+                        temp, block, pred
+                    ));
+                }
+            }
+            Some(_) => (),
+        }
+    }
+
+    // Every phi needs exactly one value for each block that jumps to it
+    for block in &function.blocks {
+        let Some(predecessors) = cfg.predecessors.get(&block.id) else {
+            continue;
+        };
+        let predecessors: HashSet<&BlockId> = predecessors.iter().collect();
+        for instruction in &block.instructions {
+            let Instruction::Phi(dest, incoming) = instruction else {
+                continue;
+            };
+            let incoming_blocks: Vec<&BlockId> = incoming.iter().map(|(pred, _)| pred).collect();
+            let incoming_set: HashSet<&BlockId> = incoming_blocks.iter().copied().collect();
+            if incoming_blocks.len() != incoming_set.len() || incoming_set != predecessors {
+                problems.push(format!(
+                    "The phi for {} in {} doesn't have one value for each predecessor",
+                    dest, block.id
+                ));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir_text::parse_ir;
+
+    fn verify_text(text: &str) -> Vec<String> {
+        let (function, symbol_table) = parse_ir(text);
+        verify(&function, &symbol_table)
+    }
+
+    #[test]
+    fn accepts_well_formed_ssa() {
+        let problems = verify_text(
+            "block_0:
+                jump block_1
+            block_1:
+                t0 = phi [block_0: 0], [block_2: t1]
+                t2 = lt t0, 10
+                branch t2, block_2, block_3
+            block_2:
+                t1 = add t0, 1
+                jump block_1
+            block_3:
+                exit t0",
+        );
+        assert_eq!(problems, Vec::<String>::new());
+    }
+
+    #[test]
+    fn rejects_missing_blocks_and_variables() {
+        let problems = verify_text(
+            "block_0:
+                t0 = load x
+                jump block_4",
+        );
+        assert_eq!(
+            problems,
+            vec!["block_0 jumps to block_4, which doesn't exist".to_string()]
+        );
+        let problems = verify_text(
+            "block_0:
+                t0 = load x
+                exit t0",
+        );
+        assert_eq!(
+            problems,
+            vec!["block_0 uses undeclared variable x".to_string()]
+        );
+    }
+
+    #[test]
+    fn rejects_uses_that_are_not_dominated() {
+        let problems = verify_text(
+            "block_0:
+                branch 1, block_1, block_2
+            block_1:
+                t0 = copy 5
+                jump block_2
+            block_2:
+                t1 = add t0, 1
+                exit t1",
+        );
+        assert_eq!(
+            problems,
+            vec!["t0 is used in block_2 before it is defined".to_string()]
+        );
+    }
+
+    #[test]
+    fn rejects_phis_missing_a_predecessor() {
+        let problems = verify_text(
+            "block_0:
+                branch 1, block_1, block_2
+            block_1:
+                jump block_2
+            block_2:
+                t0 = phi [block_0: 1]
+                exit t0",
+        );
+        assert_eq!(
+            problems,
+            vec![
+                "The phi for t0 in block_2 doesn't have one value for each predecessor".to_string()
+            ]
+        );
+    }
+}
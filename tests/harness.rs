@@ -0,0 +1,326 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use testcomp::sandbox::{run_sandboxed, Limits};
+
+// Runs every .ttc program under tests/ through the compiler and checks it against the
+// annotations in the comments at the top of the file:
+//
+//     // exit: 42            the program exits with this status
+//     // error: some text    compiling fails with a message containing the text
+//     // asm: imul           the emitted assembly contains the text
+//     // asm -O1 -O2: imul   the same, only at the given optimisation levels
+//     // opt: 0 2            the optimisation levels to check at, all of them by default
+//
+// Any other comment is left alone. Programs are compiled in a temporary directory and the
+// executable the built-in toolchain writes is run, with a timeout and resource limits, to check
+// the exit status and that nothing was printed to stderr. When nasm and ld are both installed
+// the emitted assembly is also assembled and linked with them and that executable checked as
+// well, and when they aren't that step is skipped and said so. Programs with an expected exit
+// status are also written out as optimised IR, and the IR compiled again at the same level and
+// run, to check that IR the compiler writes means the same thing when it's read back in
+
+const COMPILER: &str = env!("CARGO_BIN_EXE_testcomp");
+const OPT_LEVELS: [u8; 3] = [0, 1, 2];
+
+#[derive(Default)]
+struct Expectations {
+    exit: Option<i32>,
+    error: Option<String>,
+    // Each snippet with the levels it is checked at, or None for every level
+    asm: Vec<(String, Option<Vec<u8>>)>,
+    opt_levels: Option<Vec<u8>>,
+}
+
+// What the machine running the tests can do
+struct Environment {
+    // Only x86-64 Linux can run what the compiler produces
+    native: bool,
+    external_toolchain: bool,
+}
+
+fn main() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let mut programs = Vec::new();
+    collect_programs(&root, &mut programs);
+
+    let environment = Environment {
+        native: cfg!(all(target_os = "linux", target_arch = "x86_64")),
+        external_toolchain: is_installed("nasm") && is_installed("ld"),
+    };
+    if !environment.native {
+        println!("note: this isn't x86-64 Linux, so programs are compiled but never run");
+    } else if !environment.external_toolchain {
+        println!(
+            "note: nasm or ld isn't installed, so programs are only run as linked by the \
+             built-in toolchain and the steps that assemble and link them with nasm and ld are \
+             skipped"
+        );
+    }
+
+    let work_dir = std::env::temp_dir().join(format!("testcomp-harness-{}", std::process::id()));
+    std::fs::create_dir_all(&work_dir).expect("Should be able to create a temporary directory");
+    let mut failures = Vec::new();
+    for program in &programs {
+        let source = std::fs::read_to_string(program)
+            .unwrap_or_else(|error| panic!("Couldn't read {}: {}", program.display(), error));
+        let expectations = parse_header(&source, program);
+        let name = program.strip_prefix(&root).unwrap_or(program).display();
+        for &opt_level in expectations.opt_levels.as_deref().unwrap_or(&OPT_LEVELS) {
+            let problems = check(program, &expectations, opt_level, &work_dir, &environment);
+            let result = if problems.is_empty() { "ok" } else { "FAILED" };
+            println!("{} -O{} ... {}", name, opt_level, result);
+            for problem in problems {
+                failures.push(format!("{} -O{}: {}", name, opt_level, problem));
+            }
+        }
+    }
+    let _ = std::fs::remove_dir_all(&work_dir);
+
+    println!(
+        "\nharness: {} programs, {} failures",
+        programs.len(),
+        failures.len()
+    );
+    if !failures.is_empty() {
+        for failure in &failures {
+            println!("    {}", failure);
+        }
+        std::process::exit(1);
+    }
+}
+
+fn collect_programs(path: &Path, programs: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(path)
+        .unwrap_or_else(|error| panic!("Couldn't read {}: {}", path.display(), error))
+        .map(|entry| entry.expect("Directory entries should be readable").path())
+        .collect();
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            collect_programs(&entry, programs);
+        } else if entry
+            .extension()
+            .is_some_and(|extension| extension == "ttc")
+        {
+            programs.push(entry);
+        }
+    }
+}
+
+fn parse_header(source: &str, path: &Path) -> Expectations {
+    let mut expectations = Expectations::default();
+    for line in source.lines() {
+        let Some(comment) = line.trim().strip_prefix("//") else {
+            break;
+        };
+        let Some((key, value)) = comment.split_once(':') else {
+            continue;
+        };
+        let words: Vec<&str> = key.split_whitespace().collect();
+        let value = value.trim().to_string();
+        let levels = (words.len() > 1).then(|| {
+            words[1..]
+                .iter()
+                .map(|level| parse_level(level.strip_prefix("-O").unwrap_or(level), path))
+                .collect()
+        });
+        match words.first().copied() {
+            Some("exit") => {
+                let exit = value.parse().unwrap_or_else(|_| {
+                    panic!("{}: {} isn't an exit status", path.display(), value)
+                });
+                expectations.exit = Some(exit);
+            }
+            Some("error") => expectations.error = Some(value),
+            Some("asm") => expectations.asm.push((value, levels)),
+            Some("opt") => {
+                let levels = value
+                    .split_whitespace()
+                    .map(|level| parse_level(level, path))
+                    .collect();
+                expectations.opt_levels = Some(levels);
+            }
+            _ => (),
+        }
+    }
+    expectations
+}
+
+fn parse_level(level: &str, path: &Path) -> u8 {
+    match level.parse() {
+        Ok(level) if OPT_LEVELS.contains(&level) => level,
+        _ => panic!("{}: {} isn't an optimisation level", path.display(), level),
+    }
+}
+
+fn is_installed(tool: &str) -> bool {
+    std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths).any(|directory| directory.join(tool).is_file())
+    })
+}
+
+// Compile one program at one optimisation level, returning everything that wasn't as expected
+fn check(
+    program: &Path,
+    expectations: &Expectations,
+    opt_level: u8,
+    work_dir: &Path,
+    environment: &Environment,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let compiled = run(Command::new(COMPILER)
+        .arg(format!("-O{}", opt_level))
+        .args(["--emit", "asm"])
+        .arg(program)
+        .current_dir(work_dir));
+    let stderr = String::from_utf8_lossy(&compiled.stderr);
+
+    if let Some(error) = &expectations.error {
+        if compiled.status.success() {
+            problems.push(format!("expected the error \"{}\" but it compiled", error));
+        } else if !stderr.contains(error.as_str()) {
+            problems.push(format!(
+                "expected the error \"{}\" but got: {}",
+                error,
+                panic_message(&stderr)
+            ));
+        }
+        return problems;
+    }
+    if !compiled.status.success() {
+        problems.push(format!("didn't compile: {}", panic_message(&stderr)));
+        return problems;
+    }
+
+    let assembly = std::fs::read_to_string(work_dir.join("test.asm"))
+        .expect("The compiler should have written test.asm");
+    for (snippet, levels) in &expectations.asm {
+        let applies = levels
+            .as_ref()
+            .is_none_or(|levels| levels.contains(&opt_level));
+        if applies && !assembly.contains(snippet.as_str()) {
+            problems.push(format!("the assembly doesn't contain \"{}\"", snippet));
+        }
+    }
+
+    if !environment.native {
+        return problems;
+    }
+    let mut executables = vec![("built-in toolchain", work_dir.join("test"))];
+    if environment.external_toolchain {
+        match assemble_and_link(work_dir) {
+            Ok(executable) => executables.push(("nasm and ld", executable)),
+            Err(problem) => problems.push(problem),
+        }
+    }
+    if let Some(expected) = expectations.exit {
+        problems.extend(check_reloaded_ir(program, expected, opt_level, work_dir));
+    }
+    for (toolchain, executable) in executables {
+        let result = run_sandboxed(
+            Command::new(&executable).current_dir(work_dir),
+            &Limits::default(),
+        )
+        .unwrap_or_else(|error| panic!("Couldn't run {}: {}", executable.display(), error));
+        match (expectations.exit, result.exit_code) {
+            (Some(expected), Some(actual)) if expected != actual => problems.push(format!(
+                "linked by the {}, exited with {} instead of {}",
+                toolchain, actual, expected
+            )),
+            (_, None) => problems.push(format!(
+                "linked by the {}, didn't exit normally: {}",
+                toolchain, result
+            )),
+            _ => (),
+        }
+        if !result.stderr.is_empty() {
+            problems.push(format!(
+                "linked by the {}, printed to stderr: {}",
+                toolchain,
+                String::from_utf8_lossy(&result.stderr).trim()
+            ));
+        }
+    }
+    problems
+}
+
+// Write the program as IR at an optimisation level, then compile that IR at the same level and
+// run it
+fn check_reloaded_ir(
+    program: &Path,
+    expected: i32,
+    opt_level: u8,
+    work_dir: &Path,
+) -> Option<String> {
+    let level = format!("-O{}", opt_level);
+    let emitted = run(Command::new(COMPILER)
+        .args([level.as_str(), "--emit", "ir", "-o", "reloaded"])
+        .arg(program)
+        .current_dir(work_dir));
+    if !emitted.status.success() {
+        let stderr = String::from_utf8_lossy(&emitted.stderr);
+        return Some(format!("didn't write IR: {}", panic_message(&stderr)));
+    }
+    let compiled = run(Command::new(COMPILER)
+        .args([level.as_str(), "-o", "reloaded", "reloaded.ir"])
+        .current_dir(work_dir));
+    if !compiled.status.success() {
+        let stderr = String::from_utf8_lossy(&compiled.stderr);
+        return Some(format!(
+            "the IR it wrote didn't compile: {}",
+            panic_message(&stderr)
+        ));
+    }
+    let result = run_sandboxed(
+        Command::new(work_dir.join("reloaded")).current_dir(work_dir),
+        &Limits::default(),
+    )
+    .unwrap_or_else(|error| panic!("Couldn't run the reloaded program: {}", error));
+    match result.exit_code {
+        Some(actual) if actual == expected => None,
+        Some(actual) => Some(format!(
+            "compiled from the IR it wrote, exited with {} instead of {}",
+            actual, expected
+        )),
+        None => Some(format!(
+            "compiled from the IR it wrote, didn't exit normally: {}",
+            result
+        )),
+    }
+}
+
+fn assemble_and_link(work_dir: &Path) -> Result<PathBuf, String> {
+    let steps: [&[&str]; 2] = [
+        &["nasm", "-f", "elf64", "-o", "external.o", "test.asm"],
+        &["ld", "-m", "elf_x86_64", "-o", "external", "external.o"],
+    ];
+    for step in steps {
+        let output = run(Command::new(step[0]).args(&step[1..]).current_dir(work_dir));
+        if !output.status.success() {
+            return Err(format!(
+                "{} failed: {}",
+                step[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+    }
+    Ok(work_dir.join("external"))
+}
+
+fn run(command: &mut Command) -> Output {
+    command
+        .output()
+        .unwrap_or_else(|error| panic!("Couldn't run {:?}: {}", command, error))
+}
+
+// The line of the compiler's output saying why it panicked
+fn panic_message(stderr: &str) -> &str {
+    let mut lines = stderr.lines();
+    while let Some(line) = lines.next() {
+        if line.contains("panicked at") {
+            return lines.next().unwrap_or(line);
+        }
+    }
+    stderr.trim()
+}